@@ -0,0 +1,66 @@
+//! Generates the `Tag` object and the node case classes of IoRs.scala from src/nodes.rs, into
+//! `$OUT_DIR/nodes.scala`. The `scala_nodes_are_generated` test checks that IoRs.scala has them, so
+//! that the tags and fields the native side expects can't drift apart from the Scala ones.
+
+use std::{env, fmt::Write, fs, path::Path};
+
+struct Kind {
+    name: &'static str,
+    tag: i32,
+    params: &'static str,
+    supertype: &'static str,
+    fields: &'static [(&'static str, &'static str)],
+}
+
+macro_rules! node_kinds {
+    ($($kind:ident $name:ident = $tag:literal : $params:literal => $supertype:literal {
+        $($field:ident : $scala_type:literal = $sig:literal),*
+    })*) => {
+        const KINDS: &[Kind] = &[$(
+            Kind {
+                name: stringify!($kind),
+                tag: $tag,
+                params: $params,
+                supertype: $supertype,
+                fields: &[$((stringify!($field), $scala_type)),*],
+            },
+        )*];
+    };
+}
+
+include!("src/nodes.rs");
+
+fn generate() -> String {
+    let mut out = String::new();
+    out.push_str("  private[iors] case class Tag(underlying: Int) extends AnyVal\n\n");
+    out.push_str("  private[iors] object Tag {\n");
+    for kind in KINDS {
+        writeln!(out, "    val {}: Tag = Tag({})", kind.name, kind.tag).unwrap();
+    }
+    out.push_str("  }\n");
+
+    for kind in KINDS {
+        let fields = kind
+            .fields
+            .iter()
+            .map(|(name, scala_type)| format!("{}: {}", name, scala_type))
+            .collect::<Vec<_>>()
+            .join(", ");
+        write!(
+            out,
+            "\n  private[iors] final case class {}{}({}) extends {}(Tag.{})\n",
+            kind.name, kind.params, fields, kind.supertype, kind.name
+        )
+        .unwrap();
+    }
+    out
+}
+
+fn main() {
+    println!("cargo:rerun-if-changed=build.rs");
+    println!("cargo:rerun-if-changed=src/nodes.rs");
+
+    let out = env::var("OUT_DIR").expect("cargo sets OUT_DIR");
+    fs::write(Path::new(&out).join("nodes.scala"), generate())
+        .expect("could not write nodes.scala");
+}
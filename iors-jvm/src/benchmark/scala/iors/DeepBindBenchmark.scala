@@ -22,6 +22,7 @@ import org.openjdk.jmh.annotations._
 
 import scala.concurrent.ExecutionContext
 import scala.concurrent.ExecutionContext.Implicits
+import scala.concurrent.duration._
 
 /** To do comparative benchmarks between versions:
  *
@@ -46,6 +47,22 @@ class DeepBindBenchmark {
   @Param(Array("10000"))
   var size: Int = _
 
+  @Param(Array("references", "shared"))
+  var bindStack: String = _
+
+  var runtime: IoRsRuntime = _
+
+  @Setup
+  def setup(): Unit = {
+    val strategy = if (bindStack == "shared") BindStackStrategy.Shared else BindStackStrategy.References
+    runtime = IoRsRuntime(IoRsRuntimeConfig(bindStack = strategy))
+  }
+
+  @TearDown
+  def tearDown(): Unit = {
+    val _ = runtime.shutdown(1.second)
+  }
+
   @Benchmark
   def pure(): Int = {
     def loop(i: Int): IoRs[Int] =
@@ -56,7 +73,7 @@ class DeepBindBenchmark {
           loop(j + 1)
       }
 
-    loop(0).unsafeRunSync()
+    loop(0).unsafeRunSync(runtime)
   }
 
   @Benchmark
@@ -69,7 +86,7 @@ class DeepBindBenchmark {
           loop(j + 1)
       }
 
-    loop(0).unsafeRunSync()
+    loop(0).unsafeRunSync(runtime)
   }
 
   @Benchmark
@@ -84,6 +101,6 @@ class DeepBindBenchmark {
         }
       }
 
-    loop(0).unsafeRunSync()
+    loop(0).unsafeRunSync(runtime)
   }
 }
@@ -19,6 +19,8 @@ package iors
 import java.util.concurrent.TimeUnit
 import org.openjdk.jmh.annotations._
 
+import scala.concurrent.duration._
+
 /** To do comparative benchmarks between versions:
  *
  *     benchmarks/run-benchmark MapCallsBenchmark
@@ -39,20 +41,40 @@ import org.openjdk.jmh.annotations._
 class MapCallsBenchmark {
   import MapCallsBenchmark.test
 
+  @Param(Array("tagField", "nodeClass"))
+  var dispatch: String = _
+
+  // starts from `IoRs.pureInt`, so that the runtime calls the functions unboxed
+  @Param(Array("false", "true"))
+  var specialized: Boolean = _
+
+  var runtime: IoRsRuntime = _
+
+  @Setup
+  def setup(): Unit = {
+    val nodeDispatch = if (dispatch == "nodeClass") NodeDispatch.NodeClass else NodeDispatch.TagField
+    runtime = IoRsRuntime(IoRsRuntimeConfig(nodeDispatch = nodeDispatch))
+  }
+
+  @TearDown
+  def tearDown(): Unit = {
+    val _ = runtime.shutdown(1.second)
+  }
+
   @Benchmark
-  def one(): Long = test(12000, 1)
+  def one(): Long = test(runtime, specialized, 12000, 1)
 
   @Benchmark
-  def batch30(): Long = test(12000 / 30, 30)
+  def batch30(): Long = test(runtime, specialized, 12000 / 30, 30)
 
   @Benchmark
-  def batch120(): Long = test(12000 / 120, 120)
+  def batch120(): Long = test(runtime, specialized, 12000 / 120, 120)
 }
 
 object MapCallsBenchmark {
-  def test(iterations: Int, batch: Int): Long = {
+  def test(runtime: IoRsRuntime, specialized: Boolean, iterations: Int, batch: Int): Long = {
     val f = (x: Int) => x + 1
-    var io = IoRs.pure(0)
+    var io = if (specialized) IoRs.pureInt(0) else IoRs.pure(0)
 
     var j = 0
     while (j < batch) { io = io.map(f); j += 1 }
@@ -60,7 +82,7 @@ object MapCallsBenchmark {
     var sum = 0L
     var i = 0
     while (i < iterations) {
-      sum += io.unsafeRunSync()
+      sum += io.unsafeRunSync(runtime)
       i += 1
     }
     sum
@@ -0,0 +1,54 @@
+package iors
+
+import java.util.concurrent.TimeUnit
+
+import org.openjdk.jmh.annotations._
+
+import scala.concurrent.duration._
+
+/** The overhead of a single call into the native library, reading a counter of a runtime, through each of the ways to
+ * make one. It's the only call that has all three, the hot ones take objects and can only go through JNI:
+ *
+ *  - `object`: JNI, an instance method of the `IoRs` object, like most of the natives
+ *  - `static`: JNI, a static method of `NativeCalls`
+ *  - `foreign`: the Foreign Function & Memory API, which needs JDK 22+ both to build the jar and to run it
+ *
+ * To run the benchmark from within sbt:
+ *
+ *     jmh:run -i 10 -wi 10 -f 2 -t 1 iors.NativeCallsBenchmark
+ *
+ * Which means "10 iterations", "10 warm-up iterations", "2 forks", "1 thread".
+ */
+@State(Scope.Thread)
+@BenchmarkMode(Array(Mode.Throughput))
+@OutputTimeUnit(TimeUnit.SECONDS)
+class NativeCallsBenchmark {
+
+  @Param(Array("object", "static", "foreign"))
+  var binding: String = _
+
+  var runtime: IoRsRuntime = _
+
+  var calls: NativeCalls.Primitives = _
+
+  @Setup
+  def setup(): Unit = {
+    runtime = IoRsRuntime()
+    calls = binding match {
+      case "object" => (handle, counter) => IoRs.runtimeStat0(handle, counter)
+      case "static" => NativeCalls.JNI
+      case _ =>
+        Option(NativeCalls.foreign()).getOrElse {
+          throw new UnsupportedOperationException("the foreign binding needs the jar built and run on JDK 22+")
+        }
+    }
+  }
+
+  @TearDown
+  def tearDown(): Unit = {
+    val _ = runtime.shutdown(1.second)
+  }
+
+  @Benchmark
+  def runtimeStat(): Long = calls.runtimeStat(runtime.nativeHandle, 0)
+}
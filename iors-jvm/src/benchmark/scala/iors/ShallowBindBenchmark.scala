@@ -22,6 +22,7 @@ import org.openjdk.jmh.annotations._
 
 import scala.concurrent.ExecutionContext
 import scala.concurrent.ExecutionContext.Implicits
+import scala.concurrent.duration._
 
 /** To do comparative benchmarks between versions:
  *
@@ -46,6 +47,26 @@ class ShallowBindBenchmark {
   @Param(Array("10000"))
   var size: Int = _
 
+  @Param(Array("tagField", "nodeClass"))
+  var dispatch: String = _
+
+  @Param(Array("native", "hybrid"))
+  var execution: String = _
+
+  var runtime: IoRsRuntime = _
+
+  @Setup
+  def setup(): Unit = {
+    val nodeDispatch = if (dispatch == "nodeClass") NodeDispatch.NodeClass else NodeDispatch.TagField
+    val executionMode = if (execution == "hybrid") ExecutionMode.Hybrid else ExecutionMode.Native
+    runtime = IoRsRuntime(IoRsRuntimeConfig(nodeDispatch = nodeDispatch, execution = executionMode))
+  }
+
+  @TearDown
+  def tearDown(): Unit = {
+    val _ = runtime.shutdown(1.second)
+  }
+
   @Benchmark
   def pure(): Int = {
     def loop(i: Int): IoRs[Int] =
@@ -54,7 +75,7 @@ class ShallowBindBenchmark {
 
     IoRs.pure(0)
       .flatMap(loop)
-      .unsafeRunSync()
+      .unsafeRunSync(runtime)
   }
 
   @Benchmark
@@ -63,7 +84,7 @@ class ShallowBindBenchmark {
       if (i < size) IoRs(i + 1).flatMap(loop)
       else IoRs(i)
 
-    IoRs(0).flatMap(loop).unsafeRunSync()
+    IoRs(0).flatMap(loop).unsafeRunSync(runtime)
   }
 
   @Benchmark
@@ -72,6 +93,6 @@ class ShallowBindBenchmark {
       if (i < size) IoRs.shift.flatMap(_ => IoRs.pure(i + 1)).flatMap(loop)
       else IoRs.shift.flatMap(_ => IoRs.pure(i))
 
-    IoRs(0).flatMap(loop).unsafeRunSync()
+    IoRs(0).flatMap(loop).unsafeRunSync(runtime)
   }
 }
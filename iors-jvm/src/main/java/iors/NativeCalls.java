@@ -0,0 +1,45 @@
+package iors;
+
+/** The static entry points of the native library, the ones that get a runtime or closure handle and no receiver.
+ * Scala objects can't have static methods, hence Java. They are registered by the library when {@code IoRs} loads it,
+ * which it has always done by the time there's a handle to pass.
+ */
+final class NativeCalls {
+  private NativeCalls() {}
+
+  static native void configure0(long runtime, String[] keys, String[] values, scala.Function1<?, ?> classifier);
+
+  static native RuntimeStats runtimeStats0(long runtime);
+
+  /** The counter at index {@code counter} of {@link RuntimeStats}, -1 if there's none. */
+  static native long runtimeStat0(long runtime, int counter);
+
+  static native String fiberDump0(long runtime);
+
+  static native String[] shutdown0(long runtime, long timeoutMillis);
+
+  /** Lets go of the native runtime behind the handle, which can't be used afterwards. */
+  static native void releaseRuntime0(long runtime);
+
+  static native void dropClosure0(long closure);
+
+  /** The calls that take and return nothing but primitives, so they can be made without JNI as well. Running programs
+   * and applying closures pass objects, so they can't.
+   */
+  interface Primitives {
+    long runtimeStat(long runtime, int counter);
+  }
+
+  static final Primitives JNI = NativeCalls::runtimeStat0;
+
+  /** The Foreign Function &amp; Memory binding of the {@link Primitives}, null if the jar was built on a JDK before 22
+   * or runs on one. Its calls are "critical": they don't leave the Java thread state, so they are cheaper than JNI ones.
+   */
+  static Primitives foreign() {
+    try {
+      return (Primitives) Class.forName("iors.ForeignCalls").getDeclaredConstructor().newInstance();
+    } catch (ReflectiveOperationException | LinkageError e) {
+      return null;
+    }
+  }
+}
@@ -0,0 +1,30 @@
+package iors;
+
+import java.lang.foreign.FunctionDescriptor;
+import java.lang.foreign.Linker;
+import java.lang.foreign.SymbolLookup;
+import java.lang.foreign.ValueLayout;
+import java.lang.invoke.MethodHandle;
+
+/** {@link NativeCalls.Primitives} through the Foreign Function &amp; Memory API, see {@link NativeCalls#foreign}. Only
+ * compiled on JDK 22+. Looks the symbols up in the libraries loaded by the class loader of this class, so {@code IoRs}
+ * has to be loaded first. The JVM warns about the native access unless it runs with
+ * {@code --enable-native-access=ALL-UNNAMED}.
+ */
+final class ForeignCalls implements NativeCalls.Primitives {
+  private static final MethodHandle RUNTIME_STAT = Linker.nativeLinker().downcallHandle(
+      SymbolLookup.loaderLookup().find("iors_runtime_stat").orElseThrow(),
+      FunctionDescriptor.of(ValueLayout.JAVA_LONG, ValueLayout.JAVA_LONG, ValueLayout.JAVA_INT),
+      Linker.Option.critical(false));
+
+  @Override
+  public long runtimeStat(long runtime, int counter) {
+    try {
+      return (long) RUNTIME_STAT.invokeExact(runtime, counter);
+    } catch (RuntimeException | Error e) {
+      throw e;
+    } catch (Throwable e) {
+      throw new IllegalStateException(e);
+    }
+  }
+}
@@ -0,0 +1,20 @@
+package iors
+
+/** How a running program keeps the `map`, `flatMap` and `attempt` frames it hasn't got to yet. */
+sealed trait BindStackStrategy
+
+object BindStackStrategy {
+
+  /** A JNI local reference per frame while the program runs synchronously on one thread, turned into global references
+   * when it suspends on an `async` node. This is the default.
+   */
+  case object References extends BindStackStrategy
+
+  /** The functions in an `Object[]` per program, and its depth and the kinds of the frames in a direct `ByteBuffer`
+   * next to it, which the native runtime reads as plain memory. The loop of `ExecutionMode.Hybrid` runs the frames in
+   * place instead of getting them copied over, and a suspended program doesn't take a JNI global reference per frame.
+   * The native run loop still takes a JNI call to push a function and one to pop it.
+   */
+  case object Shared extends BindStackStrategy
+
+}
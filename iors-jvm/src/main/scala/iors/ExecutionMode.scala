@@ -0,0 +1,23 @@
+package iors
+
+/** Where the runtime runs the synchronous parts of programs, between the async boundaries. */
+sealed trait ExecutionMode
+
+object ExecutionMode {
+
+  /** Everything in the native run loop, a JNI call per function. This is the default. */
+  case object Native extends ExecutionMode
+
+  /** The native runtime keeps the fibers, the scheduling, the timers and the async boundaries, and hands everything in
+   * between to a loop on the JVM side, which the JIT can see through. The frames of the programs are kept the
+   * `BindStackStrategy.Shared` way whatever the runtime is configured with, so that both sides run them in place.
+   * Execution tracing, async stack traces and the watchdog only see what the native loop runs.
+   */
+  case object Hybrid extends ExecutionMode
+
+  /** Decided by the runtime every time a program starts or resumes: `Hybrid`, unless the program is traced (see
+   * `IoRs.setExecutionTracing` and `IoRs.enableAsyncStackTraces`) or the watchdog is on.
+   */
+  case object Auto extends ExecutionMode
+
+}
@@ -0,0 +1,21 @@
+package iors
+
+/** Whether programs keep a ring buffer of the nodes they evaluated most recently, see `IoRs.trace`.
+ *
+ * The setting is picked up when a program is started with `unsafeRunAsync`/`unsafeRunSync`.
+ */
+sealed trait ExecutionTracing
+
+object ExecutionTracing {
+
+  case object Off extends ExecutionTracing
+
+  /** Trace every program, keeping its last `bufferSize` steps. With `dumpOnFailure` the trace of a program that
+   * fails with an unhandled error is reported like the other warnings, see `IoRs.setWarningHandler`.
+   */
+  final case class On(bufferSize: Int = 64, dumpOnFailure: Boolean = false) extends ExecutionTracing
+
+  /** Like [[On]], but only for every `every`-th program. */
+  final case class Sampled(every: Int, bufferSize: Int = 64, dumpOnFailure: Boolean = false) extends ExecutionTracing
+
+}
@@ -0,0 +1,20 @@
+package iors
+
+/** Decides which exceptions thrown by user code the runtime refuses to recover from.
+ *
+ * Fatal exceptions are not turned into a failed `IoRs`: they skip every `attempt`/`handleErrorWith` on the stack
+ * and are rethrown from whatever call is currently driving the program (`unsafeRunAsync` or an async callback).
+ */
+sealed trait FatalErrorPolicy
+
+object FatalErrorPolicy {
+
+  /** The same classification as `scala.util.control.NonFatal`. This is the default. */
+  case object NonFatal extends FatalErrorPolicy
+
+  /** Every exception is recoverable. */
+  case object Never extends FatalErrorPolicy
+
+  final case class Custom(isFatal: Throwable => Boolean) extends FatalErrorPolicy
+
+}
@@ -1,23 +1,48 @@
 package iors
 
-import java.util.concurrent.ArrayBlockingQueue
-
 import iors.IoRs.Tag
 
 import scala.annotation.unused
+import scala.runtime.java8.{JFunction1$mcDD$sp, JFunction1$mcII$sp, JFunction1$mcJJ$sp}
 import scala.concurrent.ExecutionContext
+import scala.concurrent.duration._
 
 abstract sealed class IoRs[+A] private(private val tag: Tag) {
-  @native def unsafeRunAsync(/* actually used, but the lint fires here */ @unused cb: Either[Throwable, A] => ()): Unit
-
-  def unsafeRunSync(): A = {
-    val queue = new ArrayBlockingQueue[Either[Throwable, A]](1)
-    unsafeRunAsync(queue.put)
-    queue.take().toTry.get
+  def unsafeRunAsync(cb: Either[Throwable, A] => ()): Unit = unsafeRunAsync(IoRsRuntime.global, cb)
+
+  /** Fatal errors (see `IoRs.setFatalErrorPolicy`) skip the error handlers of the program and go to `cb` like any
+   * other. They are also rethrown from whatever call is running the program when it dies, this one if it hasn't
+   * suspended yet.
+   */
+  def unsafeRunAsync(runtime: IoRsRuntime, cb: Either[Throwable, A] => ()): Unit =
+    runtime.prepare(this).unsafeRunAsync0(runtime.nativeHandle, cb)
+
+  // actually used, but the lint fires here
+  @native private[iors] def unsafeRunAsync0(@unused runtime: Long, @unused cb: Either[Throwable, A] => ()): Unit
+
+  def unsafeRunSync(): A = unsafeRunSync(IoRsRuntime.global)
+
+  /** Returns the value straight from the native run loop, or throws the error. The calling thread only parks, natively,
+   * once the program goes async.
+   */
+  def unsafeRunSync(runtime: IoRsRuntime): A =
+    runtime.prepare(this).unsafeRunSync0(runtime.nativeHandle).asInstanceOf[A]
+
+  @native private[iors] def unsafeRunSync0(@unused runtime: Long): Any
+
+  /** Consecutive `map`s get fused into one, so that the runtime calls their functions in one go, up to
+   * `-Diors.fusionMaxStackDepth` (128 by default) functions deep. The specialized ones of `pureInt` and friends don't.
+   */
+  def map[B](f: A => B): IoRs[B] = this match {
+    case IoRs.Map(source, g) if IoRs.Fused.depth(g) < IoRs.FusionMaxStackDepth =>
+      IoRs.Map(source, new IoRs.Fused(g, f, IoRs.Fused.depth(g) + 1))
+    case _ =>
+      IoRs.specializedMap(this, f) match {
+        case Some(specialized) => specialized.asInstanceOf[IoRs[B]]
+        case None => IoRs.Map(this, f)
+      }
   }
 
-  def map[B](f: A => B): IoRs[B] = IoRs.Map(this, f)
-
   def flatMap[B](f: A => IoRs[B]): IoRs[B] = IoRs.FlatMap(this, f)
 
   def attempt: IoRs[Either[Throwable, A]] = IoRs.Attempt(this)
@@ -32,10 +57,165 @@ object IoRs {
 
   System.loadLibrary("iors")
 
+  /** How many functions `map` composes into one at most, 1 turns the fusion off. JVM-wide, unlike the settings of
+   * [[IoRsRuntimeConfig]], as maps get fused while they're built, before any runtime runs them. Loading the library just
+   * above rejects a value that isn't a positive integer, like it does the other `iors.*` properties.
+   */
+  private[iors] val FusionMaxStackDepth: Int =
+    Option(System.getProperty("iors.fusionMaxStackDepth")).fold(128)(_.trim.toInt)
+
   @native def printVersion(): Unit
 
+  /** See [[IoRsRuntime.stats]], for the global runtime. */
+  def runtimeStats(): RuntimeStats = IoRsRuntime.global.stats
+
+  /** Prints every program started on the global runtime that hasn't completed yet, with the frames left on the stack
+   * of the ones suspended in `IoRs.async`.
+   */
+  def dumpFibers(): Unit = println(fiberDump())
+
+  def fiberDump(): String = IoRsRuntime.global.fiberDump()
+
+  /** See [[IoRsRuntime.configure]], for the global runtime. The setters below change it as well. */
+  def configure(config: IoRsRuntimeConfig): Unit = IoRsRuntime.global.configure(config)
+
+  private def updateConfig(settings: Seq[(String, String)], classifier: Throwable => Boolean = null): Unit =
+    IoRsRuntime.global.updateConfig(settings, classifier)
+
+  /** The runtime warns when the callback handed to an `IoRs.async` register function gets garbage collected
+   * without being called. With `fail = true` it also fails the program with an `IllegalStateException`, instead of
+   * leaving whoever waits for it (like `unsafeRunSync`) blocked forever.
+   */
+  def setFailOnLostAsyncCallback(fail: Boolean): Unit =
+    updateConfig(Seq("iors.failOnLostAsyncCallback" -> fail.toString))
+
+  /** Starts a native thread that every `interval` warns about programs that have been suspended in
+   * `IoRs.async`, or inside of a single `IoRs.delay` thunk, for longer than `threshold`. Restarts it if it was already
+   * running.
+   */
+  def startWatchdog(threshold: FiniteDuration, interval: FiniteDuration = 1.second): Unit =
+    updateConfig(IoRsRuntimeConfig.watchdogSettings(Some(threshold), interval))
+
+  def stopWatchdog(): Unit = updateConfig(Seq("iors.watchdogThresholdMillis" -> "0"))
+
+  @volatile private var warningHandler: String => Unit = System.err.println(_)
+
+  /** Where the warnings of every runtime go, stderr by default: lost async callbacks, programs the watchdog finds stuck
+   * and the execution traces of failing programs. Called from the runtime's own threads, and from the finalizer.
+   */
+  def setWarningHandler(handler: String => Unit): Unit = warningHandler = handler
+
+  def setFatalErrorPolicy(policy: FatalErrorPolicy): Unit =
+    updateConfig(
+      Seq(IoRsRuntimeConfig.fatalErrorPolicySetting(policy)),
+      IoRsRuntimeConfig.fatalErrorClassifier(policy),
+    )
+
+  /** Makes every program started from now on remember its last `depth` `map`/`flatMap` frames and attach them as an
+   * [[IoRsTrace]] to the errors thrown by user code. Costs a class name lookup per frame, so it's off by default.
+   */
+  def enableAsyncStackTraces(depth: Int = 32): Unit = updateConfig(Seq("iors.asyncStackTraceDepth" -> depth.toString))
+
+  def disableAsyncStackTraces(): Unit = updateConfig(Seq("iors.asyncStackTraceDepth" -> "0"))
+
+  def setExecutionTracing(tracing: ExecutionTracing): Unit =
+    updateConfig(IoRsRuntimeConfig.executionTracingSettings(tracing))
+
+  /** The last steps of the traced program running on this thread (when called from inside of it), or of the last
+   * traced program that finished on this thread, oldest first. Empty if there is none.
+   */
+  @native def trace(): Array[String]
+
+  @native private[iors] def globalRuntime0(): Long
+
+  @native private[iors] def createRuntime0(
+    @unused keys: Array[String],
+    @unused values: Array[String],
+    @unused classifier: Throwable => Boolean,
+  ): Long
+
+  /** `NativeCalls.runtimeStat0` as an instance method of this object, for comparing the two in the benchmarks. */
+  @native private[iors] def runtimeStat0(@unused runtime: Long, @unused counter: Int): Long
+
+  @native private[iors] def registerNodeKind0(
+    @unused tag: Int,
+    @unused name: String,
+    @unused fieldNames: Array[String],
+    @unused handler: Array[AnyRef] => IoRs[Any],
+  ): Unit
+
+  @native private[iors] def nodeKind0(@unused tag: Int): Array[String]
+
+  @native private[iors] def nodeKindRegistrar0(): Long
+
+  @native private[iors] def sleep0(@unused nanos: Long, @unused wakeup: () => Unit): Unit
+
+  /** The frames of `io` that are known before it runs, pushed outermost first onto the frames of one node, which the
+   * runtime takes as they are.
+   */
+  private[iors] def compile[A](io: IoRs[A]): IoRs[A] = {
+    var frames: SharedFrames = null
+    def push(tag: Tag, f: AnyRef): Unit = {
+      if (frames == null) frames = new SharedFrames(SharedFrames.InitialCapacity)
+      frames.push(tag, f)
+    }
+    var current: IoRs[Any] = io
+    var walking = true
+    while (walking) {
+      current match {
+        case Map(source, f) =>
+          push(Tag.Map, f)
+          current = source
+        case FlatMap(source, f) =>
+          push(Tag.FlatMap, f)
+          current = source
+        case Attempt(source) =>
+          push(Tag.Attempt, null)
+          current = source
+        case _ =>
+          walking = false
+      }
+    }
+
+    if (frames == null) io else Compiled(frames, current)
+  }
+
+  private[iors] def addTrace(throwable: Throwable, ops: Array[String], classes: Array[String]): Unit =
+    throwable.addSuppressed(new IoRsTrace(ops, classes))
+
+  private[iors] def warn(message: String): Unit = warningHandler(message)
+
+  /** The functions `f` applies one after the other, for the async stack traces to name. */
+  private[iors] def unfused(f: AnyRef): Array[AnyRef] = f match {
+    case fused: Fused[_, _, _] => unfused(fused.first) ++ unfused(fused.second)
+    case _ => Array(f)
+  }
+
   def pure[A](value: A): IoRs[A] = IoRs.Pure(value)
 
+  /** Like `pure`, but the runtime keeps the value unboxed for as long as it goes through `map`s with `Int => Int`
+   * functions. The same goes for `pureLong` and `pureDouble`.
+   */
+  def pureInt(value: Int): IoRs[Int] = IoRs.PureInt(value)
+
+  def pureLong(value: Long): IoRs[Long] = IoRs.PureLong(value)
+
+  def pureDouble(value: Double): IoRs[Double] = IoRs.PureDouble(value)
+
+  /** `source.map(f)` as a node whose function the runtime calls unboxed, if `source` is one of the specialized nodes and
+   * `f` a lambda of the matching primitive type. Specialized maps don't get fused, `Fused` isn't specialized and would
+   * box the values in between.
+   */
+  private[iors] def specializedMap(source: IoRs[Any], f: AnyRef): Option[IoRs[Any]] = (source, f) match {
+    case (_: PureInt | _: MapIntInt, f: JFunction1$mcII$sp) =>
+      Some(MapIntInt(source.asInstanceOf[IoRs[Int]], f.asInstanceOf[Int => Int]))
+    case (_: PureLong | _: MapLongLong, f: JFunction1$mcJJ$sp) =>
+      Some(MapLongLong(source.asInstanceOf[IoRs[Long]], f.asInstanceOf[Long => Long]))
+    case (_: PureDouble | _: MapDoubleDouble, f: JFunction1$mcDD$sp) =>
+      Some(MapDoubleDouble(source.asInstanceOf[IoRs[Double]], f.asInstanceOf[Double => Double]))
+    case _ => None
+  }
+
   def delay[A](body: => A): IoRs[A] = IoRs.Delay(() => body)
 
   def apply[A](body: => A): IoRs[A] = delay(body)
@@ -48,6 +228,31 @@ object IoRs {
     ec.execute { () => cb(Right(())) }
   }
 
+  /** Completes after `duration`, continuing on the compute pool of the runtime the program runs on. */
+  def sleep(duration: FiniteDuration): IoRs[Unit] = async { cb =>
+    sleep0(duration.toNanos, () => cb(Right(())))
+  }
+
+  /** Adds a kind of node to the run loop, under a `tag` no other kind uses. The run loop hands the fields of every node
+   * of that kind to `handler`, and continues with the program it returns: `IoRs.pure` produces a value, `flatMap`
+   * pushes a frame and `IoRs.async` suspends. Exceptions it throws fail the program.
+   */
+  def registerNodeKind(tag: Int, name: String, fieldNames: Seq[String])(handler: Array[AnyRef] => IoRs[Any]): NodeKind = {
+    registerNodeKind0(tag, name, fieldNames.toArray, handler)
+    new NodeKind(tag, name, fieldNames)
+  }
+
+  /** The kind registered under `tag`, from scala or by a native library, see [[nodeKindRegistrar]]. */
+  def nodeKind(tag: Int): NodeKind = nodeKind0(tag) match {
+    case null => throw new NoSuchElementException(s"no node kind registered for tag $tag")
+    case described => new NodeKind(tag, described.head, described.tail.toSeq)
+  }
+
+  /** The address of `iors_register_node_kind` in the copy of the native library this class loader loaded, for handing
+   * to native libraries that register kinds of nodes. They'd get a registry of their own by linking against iors.
+   */
+  def nodeKindRegistrar: Long = nodeKindRegistrar0()
+
   def fromEither[A](either: Either[Throwable, A]): IoRs[A] = {
     either match {
       case Left(throwable) => RaiseError(throwable)
@@ -55,6 +260,7 @@ object IoRs {
     }
   }
 
+  // generated by build.rs from src/nodes.rs, edit that instead
   private[iors] case class Tag(underlying: Int) extends AnyVal
 
   private[iors] object Tag {
@@ -65,6 +271,14 @@ object IoRs {
     val Map: Tag = Tag(4)
     val FlatMap: Tag = Tag(5)
     val Attempt: Tag = Tag(6)
+    val Extension: Tag = Tag(7)
+    val Compiled: Tag = Tag(8)
+    val PureInt: Tag = Tag(9)
+    val PureLong: Tag = Tag(10)
+    val PureDouble: Tag = Tag(11)
+    val MapIntInt: Tag = Tag(12)
+    val MapLongLong: Tag = Tag(13)
+    val MapDoubleDouble: Tag = Tag(14)
   }
 
   private[iors] final case class Pure[+A](value: A) extends IoRs[A](Tag.Pure)
@@ -81,12 +295,42 @@ object IoRs {
 
   private[iors] final case class Attempt[+A](source: IoRs[A]) extends IoRs[Either[Throwable, A]](Tag.Attempt)
 
-  private[iors] final class FfiClosure[-A](private var nativePointer: Long) extends (A => ()) {
-    @native def doApply(/* actually used, but the lint fires here */ @unused v: A): Unit
+  private[iors] final case class Extension[+A](kind: NodeKind, fields: Array[AnyRef]) extends IoRs[A](Tag.Extension)
+
+  private[iors] final case class Compiled[+A](frames: SharedFrames, leaf: IoRs[Any]) extends IoRs[A](Tag.Compiled)
+
+  private[iors] final case class PureInt(value: Int) extends IoRs[Int](Tag.PureInt)
+
+  private[iors] final case class PureLong(value: Long) extends IoRs[Long](Tag.PureLong)
+
+  private[iors] final case class PureDouble(value: Double) extends IoRs[Double](Tag.PureDouble)
+
+  private[iors] final case class MapIntInt(source: IoRs[Int], f: Int => Int) extends IoRs[Int](Tag.MapIntInt)
+
+  private[iors] final case class MapLongLong(source: IoRs[Long], f: Long => Long) extends IoRs[Long](Tag.MapLongLong)
+
+  private[iors] final case class MapDoubleDouble(source: IoRs[Double], f: Double => Double) extends IoRs[Double](Tag.MapDoubleDouble)
+  // end of generated code
+
+  /** `first` and then `second`, the functions of `depth` fused `map`s. */
+  private[iors] final class Fused[-A, B, +C](val first: A => B, val second: B => C, val depth: Int) extends (A => C) {
+    override def apply(a: A): C = second(first(a))
+  }
+
+  private[iors] object Fused {
+    def depth(f: AnyRef): Int = f match {
+      case fused: Fused[_, _, _] => fused.depth
+      case _ => 1
+    }
+  }
+
+  private[iors] final class FfiClosure[-A](nativePointer: Long) extends (A => ()) {
+    // an instance method, so that the closure can't get finalized while it's being applied
+    @native private[iors] def apply0(/* actually used, but the lint fires here */ @unused pointer: Long, @unused v: A): Unit
 
-    override def apply(v: A): Unit = doApply(v)
+    override def apply(v: A): Unit = apply0(nativePointer, v)
 
-    @native override def finalize(): Unit
+    override def finalize(): Unit = NativeCalls.dropClosure0(nativePointer)
   }
 
 }
@@ -0,0 +1,93 @@
+package iors
+
+import scala.concurrent.duration.{Duration, FiniteDuration}
+
+/** An instance of the native runtime, with its own configuration, compute pool, timer, metrics and watchdog. Programs
+ * run on the one they are started with (see `IoRs.unsafeRunAsync`), or on [[IoRsRuntime.global]] if none is given.
+ *
+ * Its threads are stopped by [[shutdown]], or once this object and every program running on it are garbage collected.
+ */
+final class IoRsRuntime private[iors](private[iors] val nativeHandle: Long) extends AutoCloseable {
+
+  /** Reconfigures this runtime. Programs that are already running may keep using some of the old settings. */
+  def configure(config: IoRsRuntimeConfig): Unit =
+    updateConfig(config.settings, IoRsRuntimeConfig.fatalErrorClassifier(config.fatalErrorPolicy))
+
+  private[iors] def updateConfig(settings: Seq[(String, String)], classifier: Throwable => Boolean = null): Unit = {
+    val (keys, values) = settings.unzip
+    NativeCalls.configure0(nativeHandle, keys.toArray, values.toArray, classifier)
+    settings.collectFirst { case ("iors.compilePrograms", value) => value.trim.toBoolean }.foreach(compilesPrograms = _)
+  }
+
+  /** Whether programs get compiled before they're handed to this runtime, see `IoRsRuntimeConfig.compilePrograms`. */
+  @volatile private[iors] var compilesPrograms: Boolean = false
+
+  /** `io` the way this runtime runs it. */
+  private[iors] def prepare[A](io: IoRs[A]): IoRs[A] = if (compilesPrograms) IoRs.compile(io) else io
+
+  /** Counters of the work this runtime has done since it was created. */
+  def stats: RuntimeStats = NativeCalls.runtimeStats0(nativeHandle)
+
+  /** Every program started on this runtime that hasn't completed yet, with the frames left on the stack of the ones
+   * suspended in `IoRs.async`.
+   */
+  def fiberDump(): String = NativeCalls.fiberDump0(nativeHandle)
+
+  /** Stops accepting new programs, starting one afterwards throws an `IllegalStateException`, and waits up to
+   * `timeout` for the running ones to complete. The ones that don't are cancelled: their callbacks get a
+   * `java.util.concurrent.CancellationException` without any of their error handlers running, right away if they are
+   * suspended in `IoRs.async`, or else after the step they are in. Then the runtime's threads are stopped and whatever
+   * it held on to is released, except for the classes it resolved, which go once this object is garbage collected.
+   *
+   * Returns the fiber dumps (see [[fiberDump]]) of the cancelled programs, empty if all of them completed in time.
+   * Shutting down a runtime that is already shut down does nothing.
+   */
+  def shutdown(timeout: FiniteDuration): Seq[String] =
+    NativeCalls.shutdown0(nativeHandle, timeout.toMillis).toSeq
+
+  /** [[shutdown]] without waiting for anything. */
+  override def close(): Unit = {
+    val _ = shutdown(Duration.Zero)
+  }
+
+  override protected def finalize(): Unit = NativeCalls.releaseRuntime0(nativeHandle)
+}
+
+object IoRsRuntime {
+
+  /** The runtime programs run on by default, configured from the `iors.*` system properties when the native library
+   * is loaded.
+   */
+  def global: IoRsRuntime = {
+    val runtime = current
+    if (runtime != null) runtime
+    else
+      synchronized {
+        if (current == null) {
+          val runtime = new IoRsRuntime(IoRs.globalRuntime0())
+          // validated by the native library along with the other properties
+          runtime.compilesPrograms = Option(System.getProperty("iors.compilePrograms")).exists(_.trim.toBoolean)
+          current = runtime
+        }
+        current
+      }
+  }
+
+  @volatile private var current: IoRsRuntime = _
+
+  /** Runs `reload`, which replaces the global runtime on the native side, and forgets the one that was handed out
+   * until then. For the tests.
+   */
+  private[iors] def reloadGlobal(reload: => Unit): Unit = synchronized {
+    reload
+    current = null
+  }
+
+  def apply(config: IoRsRuntimeConfig = IoRsRuntimeConfig()): IoRsRuntime = {
+    val (keys, values) = config.settings.unzip
+    val classifier = IoRsRuntimeConfig.fatalErrorClassifier(config.fatalErrorPolicy)
+    val runtime = new IoRsRuntime(IoRs.createRuntime0(keys.toArray, values.toArray, classifier))
+    runtime.compilesPrograms = config.compilePrograms
+    runtime
+  }
+}
@@ -0,0 +1,118 @@
+package iors
+
+import scala.concurrent.duration._
+
+/** Settings of a native runtime, used when creating an [[IoRsRuntime]] or applied with `IoRsRuntime.configure`.
+ *
+ * The global runtime reads them from system properties when the native library is loaded. The names
+ * are the ones in [[IoRsRuntimeConfig.settings]], e.g. `-Diors.computeThreads=4`, `-Diors.executionTracing=sampled:100`
+ * or `-Diors.fatalErrors=never`. The runtime validates the values and throws an `IllegalArgumentException` naming the
+ * first bad one, without applying any of them.
+ *
+ * `-Diors.fusionMaxStackDepth` (see `IoRs.map`) is validated along with them, but isn't a setting of a runtime: maps get
+ * fused as they're built, so it applies to every runtime in the JVM alike.
+ *
+ * @param computeThreads          size of the native thread pool programs continue on after auto-yielding
+ * @param autoYieldThreshold      after how many steps a program gives up its thread and continues on the compute pool,
+ *                                0 turns auto-yielding off
+ * @param localFrameCapacity      JNI local references reserved for evaluating a single step, on top of the ones the
+ *                                frames of a running program take
+ * @param asyncStackTraceDepth    see `IoRs.enableAsyncStackTraces`, 0 turns them off
+ * @param executionTracing        see `IoRs.setExecutionTracing`
+ * @param fatalErrorPolicy        see `IoRs.setFatalErrorPolicy`
+ * @param failOnLostAsyncCallback see `IoRs.setFailOnLostAsyncCallback`
+ * @param watchdogThreshold       see `IoRs.startWatchdog`, `None` turns the watchdog off
+ * @param watchdogInterval        how often the watchdog looks at the running programs
+ * @param compilePrograms         pushes the `map`, `flatMap` and `attempt` frames a program starts out with onto a
+ *                                `BindStackStrategy.Shared` stack before it's handed to the runtime, which takes them
+ *                                over without a JNI call per node. The programs of these runtimes keep their frames that
+ *                                way whatever `bindStack` says. The programs returned by `flatMap` functions are still
+ *                                read node by node
+ * @param bindStack               how programs keep the frames they haven't run yet
+ * @param nodeDispatch            how the runtime tells the kinds of nodes apart
+ * @param execution               where the synchronous parts of programs run
+ */
+final case class IoRsRuntimeConfig(
+  computeThreads: Int = Runtime.getRuntime.availableProcessors(),
+  autoYieldThreshold: Int = 0,
+  localFrameCapacity: Int = 16,
+  asyncStackTraceDepth: Int = 0,
+  executionTracing: ExecutionTracing = ExecutionTracing.Off,
+  fatalErrorPolicy: FatalErrorPolicy = FatalErrorPolicy.NonFatal,
+  failOnLostAsyncCallback: Boolean = false,
+  watchdogThreshold: Option[FiniteDuration] = None,
+  watchdogInterval: FiniteDuration = 1.second,
+  compilePrograms: Boolean = false,
+  bindStack: BindStackStrategy = BindStackStrategy.References,
+  nodeDispatch: NodeDispatch = NodeDispatch.TagField,
+  execution: ExecutionMode = ExecutionMode.Native,
+) {
+  import IoRsRuntimeConfig._
+
+  private[iors] def settings: Seq[(String, String)] =
+    Seq(
+      "iors.computeThreads" -> computeThreads.toString,
+      "iors.autoYieldThreshold" -> autoYieldThreshold.toString,
+      "iors.localFrameCapacity" -> localFrameCapacity.toString,
+      "iors.asyncStackTraceDepth" -> asyncStackTraceDepth.toString,
+      fatalErrorPolicySetting(fatalErrorPolicy),
+      "iors.failOnLostAsyncCallback" -> failOnLostAsyncCallback.toString,
+      "iors.compilePrograms" -> compilePrograms.toString,
+      bindStackSetting(bindStack),
+      nodeDispatchSetting(nodeDispatch),
+      executionSetting(execution),
+    ) ++ executionTracingSettings(executionTracing) ++ watchdogSettings(watchdogThreshold, watchdogInterval)
+}
+
+object IoRsRuntimeConfig {
+
+  private[iors] def executionTracingSettings(tracing: ExecutionTracing): Seq[(String, String)] = tracing match {
+    case ExecutionTracing.Off => Seq("iors.executionTracing" -> "off")
+    case ExecutionTracing.On(bufferSize, dumpOnFailure) =>
+      Seq(
+        "iors.executionTracing" -> "on",
+        "iors.executionTraceBufferSize" -> bufferSize.toString,
+        "iors.executionTraceDumpOnFailure" -> dumpOnFailure.toString,
+      )
+    case ExecutionTracing.Sampled(every, bufferSize, dumpOnFailure) =>
+      Seq(
+        "iors.executionTracing" -> s"sampled:$every",
+        "iors.executionTraceBufferSize" -> bufferSize.toString,
+        "iors.executionTraceDumpOnFailure" -> dumpOnFailure.toString,
+      )
+  }
+
+  private[iors] def fatalErrorPolicySetting(policy: FatalErrorPolicy): (String, String) = policy match {
+    case FatalErrorPolicy.NonFatal => "iors.fatalErrors" -> "nonfatal"
+    case FatalErrorPolicy.Never => "iors.fatalErrors" -> "never"
+    case FatalErrorPolicy.Custom(_) => "iors.fatalErrors" -> "custom"
+  }
+
+  private[iors] def bindStackSetting(strategy: BindStackStrategy): (String, String) = strategy match {
+    case BindStackStrategy.References => "iors.bindStack" -> "references"
+    case BindStackStrategy.Shared => "iors.bindStack" -> "shared"
+  }
+
+  private[iors] def nodeDispatchSetting(dispatch: NodeDispatch): (String, String) = dispatch match {
+    case NodeDispatch.TagField => "iors.nodeDispatch" -> "tagField"
+    case NodeDispatch.NodeClass => "iors.nodeDispatch" -> "nodeClass"
+  }
+
+  private[iors] def executionSetting(execution: ExecutionMode): (String, String) = execution match {
+    case ExecutionMode.Native => "iors.execution" -> "native"
+    case ExecutionMode.Hybrid => "iors.execution" -> "hybrid"
+    case ExecutionMode.Auto => "iors.execution" -> "auto"
+  }
+
+  private[iors] def fatalErrorClassifier(policy: FatalErrorPolicy): Throwable => Boolean = policy match {
+    case FatalErrorPolicy.Custom(isFatal) => isFatal
+    case _ => null
+  }
+
+  private[iors] def watchdogSettings(threshold: Option[FiniteDuration], interval: FiniteDuration): Seq[(String, String)] =
+    Seq(
+      "iors.watchdogThresholdMillis" -> threshold.fold(0L)(_.toMillis).toString,
+      "iors.watchdogIntervalMillis" -> interval.toMillis.toString,
+    )
+
+}
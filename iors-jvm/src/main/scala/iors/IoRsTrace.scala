@@ -0,0 +1,15 @@
+package iors
+
+/** Attached as a suppressed exception to errors thrown by functions passed to `map`/`flatMap`/`delay` when async
+ * stack traces are enabled with `IoRs.enableAsyncStackTraces`.
+ *
+ * Its stack trace lists the most recent bind frames of the failing program, newest first, as
+ * `<closure class>.<combinator>`.
+ */
+final class IoRsTrace private[iors](ops: Array[String], classes: Array[String])
+  extends Throwable("iors trace", null, false, true) {
+
+  setStackTrace(ops.zip(classes).map { case (op, cls) => new StackTraceElement(cls, op, null, -1) })
+
+  override def fillInStackTrace(): Throwable = this
+}
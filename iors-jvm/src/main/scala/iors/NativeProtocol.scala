@@ -0,0 +1,9 @@
+package iors
+
+/** What the native library expects of the classes in this jar: the node classes, their fields and the helpers it calls.
+ * Has to be bumped together with `PROTOCOL_VERSION` in lib.rs, loading the library fails when the two differ.
+ */
+private[iors] object NativeProtocol {
+  // read through the static forwarder while IoRs is still being initialized, so it's kept out of IoRs itself
+  final val Version = 14
+}
@@ -0,0 +1,14 @@
+package iors
+
+/** How the runtime tells which kind of node an `IoRs` is. */
+sealed trait NodeDispatch
+
+object NodeDispatch {
+
+  /** Reads the `tag` field every node has. This is the default. */
+  case object TagField extends NodeDispatch
+
+  /** Compares the class of the node against the classes of the node kinds, without reading any of its fields. */
+  case object NodeClass extends NodeDispatch
+
+}
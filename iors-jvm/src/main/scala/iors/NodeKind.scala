@@ -0,0 +1,13 @@
+package iors
+
+/** A kind of node added to the run loop from outside of iors, see [[IoRs.registerNodeKind]] and [[IoRs.nodeKind]].
+ * Applying it to the fields of a node makes a program that its handler evaluates.
+ */
+final class NodeKind private[iors] (val tag: Int, val name: String, val fieldNames: Seq[String]) {
+  def apply[A](fields: AnyRef*): IoRs[A] = {
+    require(fields.size == fieldNames.size, s"$name takes ${fieldNames.size} fields, got ${fields.size}")
+    IoRs.Extension(this, fields.toArray)
+  }
+
+  override def toString: String = s"NodeKind($tag, $name, ${fieldNames.mkString(", ")})"
+}
@@ -0,0 +1,18 @@
+package iors
+
+/** A snapshot of the counters of a runtime since it was created, see `IoRsRuntime.stats`.
+ *
+ * @param nodesEvaluated     `IoRs` nodes the run loop went through
+ * @param jniUpcalls         method calls from the native side back into JVM code (user functions, boxing, `Left` and
+ *                           `Right`, ...), not counting the objects it only constructs
+ * @param globalRefsCreated  JNI global references created, mostly one per `map`/`flatMap` frame a program suspends with
+ * @param ffiClosuresCreated callbacks handed to `IoRs.async` register functions
+ * @param asyncBoundaries    `IoRs.async` nodes evaluated
+ */
+final case class RuntimeStats(
+  nodesEvaluated: Long,
+  jniUpcalls: Long,
+  globalRefsCreated: Long,
+  ffiClosuresCreated: Long,
+  asyncBoundaries: Long,
+)
@@ -0,0 +1,73 @@
+package iors
+
+import java.nio.{ByteBuffer, ByteOrder}
+
+import iors.IoRs.Tag
+
+/** The frames of a program under `BindStackStrategy.Shared`, outermost first. The functions are in an array, the depth
+ * and the tag of every frame in a direct buffer, which the native runtime reads and writes as plain memory. `SyncLoop`
+ * runs and pushes frames on it directly, so it changes hands with the native runtime without anything being copied.
+ */
+private[iors] final class SharedFrames(capacity: Int) {
+  var functions: Array[AnyRef] = new Array[AnyRef](capacity)
+  var kinds: ByteBuffer = SharedFrames.kindsBuffer(capacity)
+
+  def depth: Int = kinds.getInt(0)
+
+  def depth_=(depth: Int): Unit = {
+    val _ = kinds.putInt(0, depth)
+  }
+
+  /** The tag of the node that pushed the frame at `i`. */
+  def tag(i: Int): Int = kinds.get(SharedFrames.Header + i).toInt
+
+  def push(tag: Tag, f: AnyRef): Unit = {
+    val depth = this.depth
+    if (depth == functions.length) grow()
+    functions(depth) = f
+    val _ = kinds.put(SharedFrames.Header + depth, tag.underlying.toByte)
+    this.depth = depth + 1
+  }
+
+  /** Takes the top frame off and returns its function, null for `attempt`. Its tag is `tag(depth)` afterwards. */
+  def pop(): AnyRef = {
+    depth -= 1
+    val f = functions(depth)
+    functions(depth) = null
+    f
+  }
+
+  /** Drops the frames up to and including the first `attempt`, returns whether there was one. */
+  def unwindToAttempt(): Boolean = {
+    var found = false
+    while (!found && depth > 0) {
+      val _ = pop()
+      found = tag(depth) == Tag.Attempt.underlying
+    }
+    found
+  }
+
+  /** Replaces the array and the buffer by ones twice as big. The native runtime calls it too when it pushes onto a full
+   * stack, and reads both fields again afterwards.
+   */
+  def grow(): Unit = {
+    val capacity = math.max(SharedFrames.InitialCapacity, functions.length * 2)
+    val grown = SharedFrames.kindsBuffer(capacity)
+    val used = kinds.duplicate()
+    val _ = used.limit(SharedFrames.Header + depth)
+    val _ = grown.put(used)
+    functions = java.util.Arrays.copyOf(functions, capacity)
+    kinds = grown
+  }
+}
+
+private[iors] object SharedFrames {
+
+  /** The bytes in front of the tags, the depth as a native-endian `Int`. */
+  final val Header = 8
+
+  final val InitialCapacity = 64
+
+  private def kindsBuffer(capacity: Int): ByteBuffer =
+    ByteBuffer.allocateDirect(Header + capacity).order(ByteOrder.nativeOrder())
+}
@@ -0,0 +1,142 @@
+package iors
+
+import iors.IoRs._
+
+import scala.util.control.NonFatal
+
+/** The synchronous part of the run loop on the JVM side, see `ExecutionMode.Hybrid`. The native runtime hands it a node
+ * along with the frames of the fiber, which it runs and pushes in place, and gets them back once the loop is done or
+ * can't go on. It runs
+ * everything but `async` and extension nodes, which end a segment. So does an exception thrown by user code, as the
+ * runtime's fatal error policy decides what happens to it.
+ */
+private[iors] object SyncLoop {
+
+  /** The program completed with `result`. */
+  final val Completed = 0
+
+  /** `raiseError` failed the program with `result` and none of the frames handled it. */
+  final val Failed = 1
+
+  /** User code, or the loop itself, threw `result`, the frames left still have to handle it. */
+  final val Thrown = 2
+
+  /** The loop stopped at the node `result`, one it doesn't run or the one after `maxSteps`. */
+  final val Stopped = 3
+
+  /** How a segment ended, with the frames left, null if there were never any. `steps` is the number of nodes the loop
+   * went through.
+   */
+  final class Segment(val outcome: Int, val result: AnyRef, val frames: SharedFrames, val steps: Int)
+
+  /** Runs `io` on top of `frames`, which is null if the program never had any, for up to `maxSteps` nodes. */
+  def run(io: IoRs[Any], frames: SharedFrames, maxSteps: Int): Segment = {
+    var shared = frames
+    def push(tag: Tag, f: AnyRef): Unit = {
+      if (shared == null) shared = new SharedFrames(SharedFrames.InitialCapacity)
+      shared.push(tag, f)
+    }
+    var current = io
+    // a value on its way down the frames, when `hasValue`
+    var value: Any = null
+    var hasValue = false
+    var steps = 0
+    var segment: Segment = null
+
+    try while (segment == null) {
+      if (hasValue) {
+        if (shared == null || shared.depth == 0) {
+          segment = new Segment(Completed, value.asInstanceOf[AnyRef], shared, steps)
+        } else {
+          val f = shared.pop()
+          val tag = shared.tag(shared.depth)
+          if (tag == Tag.Attempt.underlying) {
+            value = Right(value)
+          } else {
+            try {
+              if (tag == Tag.FlatMap.underlying) {
+                current = f.asInstanceOf[Any => IoRs[Any]](value)
+                hasValue = false
+              } else {
+                // specialized maps too, their functions box like any other
+                value = f.asInstanceOf[Any => Any](value)
+              }
+            } catch {
+              case throwable: Throwable => segment = new Segment(Thrown, throwable, shared, steps)
+            }
+          }
+        }
+      } else if (steps >= maxSteps) {
+        segment = new Segment(Stopped, current, shared, steps)
+      } else {
+        steps += 1
+        current match {
+          case Pure(v) =>
+            value = v
+            hasValue = true
+          case PureInt(v) =>
+            value = v
+            hasValue = true
+          case PureLong(v) =>
+            value = v
+            hasValue = true
+          case PureDouble(v) =>
+            value = v
+            hasValue = true
+          case Delay(thunk) =>
+            try {
+              value = thunk()
+              hasValue = true
+            } catch {
+              case throwable: Throwable => segment = new Segment(Thrown, throwable, shared, steps)
+            }
+          case RaiseError(throwable) =>
+            if (shared != null && shared.unwindToAttempt()) {
+              value = Left(throwable)
+              hasValue = true
+            } else {
+              segment = new Segment(Failed, throwable, shared, steps)
+            }
+          case Map(source, f) =>
+            push(Tag.Map, f)
+            current = source
+          case FlatMap(source, f) =>
+            push(Tag.FlatMap, f)
+            current = source
+          case MapIntInt(source, f) =>
+            push(Tag.MapIntInt, f)
+            current = source
+          case MapLongLong(source, f) =>
+            push(Tag.MapLongLong, f)
+            current = source
+          case MapDoubleDouble(source, f) =>
+            push(Tag.MapDoubleDouble, f)
+            current = source
+          case Attempt(source) =>
+            push(Tag.Attempt, null)
+            current = source
+          case Compiled(compiled, leaf) =>
+            if (shared == null || shared.depth == 0) shared = compiled
+            else {
+              var i = 0
+              while (i < compiled.depth) {
+                push(Tag(compiled.tag(i)), compiled.functions(i))
+                i += 1
+              }
+            }
+            // every frame counts as a node, like in the native loop
+            steps += compiled.depth
+            current = leaf
+          case _ =>
+            // async and extension nodes are left to the runtime
+            steps -= 1
+            segment = new Segment(Stopped, current, shared, steps)
+        }
+      }
+    } catch {
+      // the frames are left for the runtime either way, it handles this like it would an error of user code
+      case NonFatal(throwable) => segment = new Segment(Thrown, throwable, shared, steps)
+    }
+    segment
+  }
+}
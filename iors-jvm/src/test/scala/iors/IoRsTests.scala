@@ -1,11 +1,72 @@
 package iors
 
+import java.io.{PrintWriter, StringWriter}
+import java.lang.ref.WeakReference
+import java.lang.reflect.InvocationTargetException
+import java.util.concurrent.{ArrayBlockingQueue, CancellationException, ConcurrentLinkedQueue, TimeUnit}
+
 import iors.IoRs.printVersion
 
 import scala.concurrent.ExecutionContext.Implicits.global
 import scala.concurrent.Future
+import scala.concurrent.duration._
 
 object IoRsTests {
+
+  /** The tests below, run one after the other by [[runAll]], since some of them reconfigure the global runtime. */
+  private val tests: Seq[(String, () => Unit)] = Seq(
+    "fatalErrorsSkipHandlers" -> (() => fatalErrorsSkipHandlers()),
+    "asyncStackTracesNameTheFailingChain" -> (() => asyncStackTracesNameTheFailingChain()),
+    "executionTraceRecordsSteps" -> (() => executionTraceRecordsSteps()),
+    "failureDumpsGoToTheWarningHandler" -> (() => failureDumpsGoToTheWarningHandler()),
+    "fiberDumpShowsSuspendedFibers" -> (() => fiberDumpShowsSuspendedFibers()),
+    "throwingRegisterFailsTheProgram" -> (() => throwingRegisterFailsTheProgram()),
+    "lostCallbacksAreReported" -> (() => lostCallbacksAreReported()),
+    "watchdogWarnsOncePerStuckThunk" -> (() => watchdogWarnsOncePerStuckThunk()),
+    "badConfigIsRejectedWhole" -> (() => badConfigIsRejectedWhole()),
+    "runtimesAreIsolated" -> (() => runtimesAreIsolated()),
+    "shutdownCancelsStuckPrograms" -> (() => shutdownCancelsStuckPrograms()),
+    "collectedRuntimesStopTheirThreads" -> (() => collectedRuntimesStopTheirThreads()),
+    "globalsSurviveConcurrentReloads" -> (() => globalsSurviveConcurrentReloads()),
+    "replacedGlobalRuntimesStopTheirThreads" -> (() => replacedGlobalRuntimesStopTheirThreads()),
+    "otherClassLoadersGetTheirOwnGlobals" -> (() => otherClassLoadersGetTheirOwnGlobals()),
+    "mismatchedJarsFailLoading" -> (() => mismatchedJarsFailLoading()),
+    "badFusionDepthsFailLoading" -> (() => badFusionDepthsFailLoading()),
+    "unloadingShutsDownEveryRuntime" -> (() => unloadingShutsDownEveryRuntime()),
+    "extensionNodesRunTheirHandler" -> (() => extensionNodesRunTheirHandler()),
+    "compiledProgramsRunTheSame" -> (() => compiledProgramsRunTheSame()),
+    "sharedBindStackRunsTheSame" -> (() => sharedBindStackRunsTheSame()),
+    "sharedFramesChangeHandsInPlace" -> (() => sharedFramesChangeHandsInPlace()),
+    "bindFramesStayLocalUntilSuspended" -> (() => bindFramesStayLocalUntilSuspended()),
+    "classDispatchRunsTheSame" -> (() => classDispatchRunsTheSame()),
+    "mapChainsGetFused" -> (() => mapChainsGetFused()),
+    "valuesAndErrorsCarryOverSteps" -> (() => valuesAndErrorsCarryOverSteps()),
+    "specializedMapsRunUnboxed" -> (() => specializedMapsRunUnboxed()),
+    "specializedMapsArentFused" -> (() => specializedMapsArentFused()),
+    "nativeCallsAgree" -> (() => nativeCallsAgree()),
+    "statsCountWhatRan" -> (() => statsCountWhatRan()),
+    "hybridRunsTheSame" -> (() => hybridRunsTheSame()),
+    "hybridLoopErrorsCanBeAttempted" -> (() => hybridLoopErrorsCanBeAttempted()),
+    "unsafeRunSyncReturnsDirectly" -> (() => unsafeRunSyncReturnsDirectly()),
+  )
+
+  /** Runs every test, returns the name and stack trace of each one that failed. */
+  def runAll(): Array[String] =
+    tests.flatMap { case (name, test) =>
+      try {
+        test()
+        None
+      } catch {
+        case failure: Throwable =>
+          val trace = new StringWriter()
+          failure.printStackTrace(new PrintWriter(trace))
+          Some(s"$name failed: $trace")
+      }
+    }.toArray
+
+  private def assertEquals[A](what: String, expected: A, actual: A): Unit =
+    if (expected != actual) throw new AssertionError(s"$what: expected $expected, got $actual")
+
   def itWorks(): Int = {
     val io = for {
       x <- IoRs.pure(42)
@@ -32,6 +93,839 @@ object IoRsTests {
     res
   }
 
+  def fatalErrorsSkipHandlers(): Unit = {
+    def boom(): Int = throw new StackOverflowError("boom")
+
+    var handled = false
+    def guarded(io: IoRs[Int]): IoRs[Int] = io.handleErrorWith { _ =>
+      handled = true
+      IoRs.pure(0)
+    }
+    def rethrown(io: IoRs[Int]): Boolean =
+      try {
+        io.unsafeRunSync()
+        false
+      } catch {
+        case _: StackOverflowError => true
+      }
+
+    assert(rethrown(guarded(IoRs(boom()))), "the StackOverflowError wasn't rethrown")
+
+    // after a suspension the fiber dies on a runtime thread, with no java code to throw to
+    val suspended = guarded(IoRs.sleep(1.milli).flatMap(_ => IoRs(boom())))
+    val outcome = new ArrayBlockingQueue[Either[Throwable, Int]](1)
+    suspended.unsafeRunAsync(outcome.put)
+    outcome.poll(5, TimeUnit.SECONDS) match {
+      case Left(_: StackOverflowError) =>
+      case other => throw new AssertionError(s"expected the StackOverflowError in the callback, got $other")
+    }
+    assert(rethrown(suspended), "the StackOverflowError wasn't rethrown after a suspension")
+
+    assert(!handled, "the error handler ran")
+  }
+
+  def asyncStackTracesNameTheFailingChain(): Unit = {
+    def boom(x: Int): Int = throw new RuntimeException(s"boom $x")
+
+    def traced(io: IoRs[Int]): List[StackTraceElement] = io.attempt.unsafeRunSync() match {
+      case Left(exc) =>
+        exc.getSuppressed.toList match {
+          case (trace: IoRsTrace) :: Nil => trace.getStackTrace.toList
+          case suppressed => throw new AssertionError(s"expected a single IoRsTrace, got $suppressed")
+        }
+      case Right(value) => throw new AssertionError(s"expected a failure, got $value")
+    }
+
+    IoRs.enableAsyncStackTraces(depth = 8)
+    try {
+      // newest first, the map that threw on top
+      val chain = traced(IoRs.pure(1).map(_ + 1).flatMap(x => IoRs.pure(x).map(boom)))
+      assertEquals("the traced frames", List("map", "flatMap", "map"), chain.map(_.getMethodName))
+
+      val fused = traced(IoRs.pure(1).map(_ + 1).map(_ * 2).map(boom))
+      assertEquals("the traced frames of fused maps", List("map", "map", "map"), fused.map(_.getMethodName))
+      assert(!fused.exists(_.getClassName.contains("Fused")), s"the trace names the fused function: $fused")
+    } finally {
+      IoRs.disableAsyncStackTraces()
+    }
+  }
+
+  def executionTraceRecordsSteps(): Unit = {
+    IoRs.setExecutionTracing(ExecutionTracing.On(bufferSize = 3))
+    try {
+      val inside = IoRs.pure(1).flatMap(x => IoRs(x + 1)).flatMap(_ => IoRs(IoRs.trace().toList)).unsafeRunSync()
+      val after = IoRs.trace().toList
+
+      assertEquals("the steps", List("Pure", "Delay", "Delay"), inside.map(_.takeWhile(_ != ' ')))
+      assertEquals("the trace after the program finished", inside, after)
+    } finally {
+      IoRs.setExecutionTracing(ExecutionTracing.Off)
+    }
+  }
+
+  def failureDumpsGoToTheWarningHandler(): Unit = {
+    val warnings = new ConcurrentLinkedQueue[String]()
+    IoRs.setWarningHandler { warning => warnings.add(warning); () }
+    IoRs.setExecutionTracing(ExecutionTracing.On(bufferSize = 3, dumpOnFailure = true))
+    try {
+      IoRs.pure(1).flatMap(_ => IoRs.raiseError[Int](new RuntimeException("boom"))).unsafeRunAsync(_ => ())
+
+      warnings.toArray.toList match {
+        case List(warning: String) =>
+          assert(warning.contains("RuntimeException: boom"), s"the warning doesn't name the error: $warning")
+          assert(warning.contains("RaiseError"), s"the warning doesn't have the steps: $warning")
+        case other => throw new AssertionError(s"expected a single warning, got $other")
+      }
+    } finally {
+      IoRs.setExecutionTracing(ExecutionTracing.Off)
+      IoRs.setWarningHandler(System.err.println(_))
+    }
+  }
+
+  def fiberDumpShowsSuspendedFibers(): Unit = {
+    var resume: Either[Throwable, Int] => Unit = null
+    IoRs.async[Int](cb => resume = cb).map(_ + 1).attempt.unsafeRunAsync(_ => ())
+
+    val dump = IoRs.fiberDump()
+    resume(Right(1))
+
+    assert(dump.contains("suspended on async"), s"no suspended fiber in $dump")
+    assert(dump.linesIterator.exists(_.trim.startsWith("map ")), s"no map frame in $dump")
+  }
+
+  def throwingRegisterFailsTheProgram(): Unit = {
+    val runtime = IoRsRuntime()
+    val boom = new RuntimeException("register threw")
+
+    val handled = IoRs.async[Int](_ => throw boom).map(_ + 1).attempt.unsafeRunSync(runtime)
+    val unhandled = new ArrayBlockingQueue[Either[Throwable, Int]](1)
+    IoRs.async[Int](_ => throw boom).map(_ + 1).unsafeRunAsync(runtime, unhandled.put)
+    // the callback won, the error comes too late
+    val calledBack = IoRs.async[Int] { cb => cb(Right(1)); throw boom }.map(_ + 1).unsafeRunSync(runtime)
+    val dump = runtime.fiberDump()
+    runtime.shutdown(1.second)
+
+    assertEquals("the handled error", Left(boom), handled)
+    assertEquals("the unhandled error", Left(boom), unhandled.poll(5, TimeUnit.SECONDS))
+    assertEquals("the program that was called back", 2, calledBack)
+    assert(dump.startsWith("iors fiber dump (0 fibers)"), s"fibers left behind: $dump")
+  }
+
+  def lostCallbacksAreReported(): Unit = {
+    val warnings = new ConcurrentLinkedQueue[String]()
+    IoRs.setWarningHandler { warning => warnings.add(warning); () }
+    val reporting = IoRsRuntime()
+    val failing = IoRsRuntime(IoRsRuntimeConfig(failOnLostAsyncCallback = true))
+    val reported = new ArrayBlockingQueue[Either[Throwable, Int]](1)
+    val failed = new ArrayBlockingQueue[Either[Throwable, Either[(String, String), Int]]](1)
+
+    try {
+      // the register functions let go of the callbacks right away
+      IoRs.async[Int](_ => ()).map(_ + 1).unsafeRunAsync(reporting, reported.put)
+      IoRs
+        .async[Int](_ => ())
+        .attempt
+        .map(_.left.map(e => (e.getMessage, Thread.currentThread.getName)))
+        .unsafeRunAsync(failing, failed.put)
+
+      def lost(outcome: String): Int = warnings.toArray.count(_.toString.endsWith(outcome))
+      val deadline = System.nanoTime() + 10.seconds.toNanos
+      while ((lost("the fiber will never complete") == 0 || failed.isEmpty) && System.nanoTime() < deadline) {
+        System.gc()
+        System.runFinalization()
+        Thread.sleep(10)
+      }
+      val live = reporting.fiberDump()
+      reporting.shutdown(1.second)
+      failing.shutdown(1.second)
+
+      assertEquals("the warnings about the reported callback", 1, lost("the fiber will never complete"))
+      assertEquals("the warnings about the failed callback", 1, lost("failing the fiber"))
+      failed.poll() match {
+        case Right(Left((message, thread))) =>
+          assertEquals("the error", "async callback lost", message)
+          assert(thread.contains("-compute-"), s"the program continued on $thread")
+        case other => throw new AssertionError(s"expected the program to handle the lost callback, got $other")
+      }
+      assertEquals("the outcome of the program that was only reported", null, reported.poll())
+      assert(live.startsWith("iors fiber dump (0 fibers)"), s"the lost fiber is still live: $live")
+    } finally {
+      IoRs.setWarningHandler(System.err.println(_))
+    }
+  }
+
+  def watchdogWarnsOncePerStuckThunk(): Unit = {
+    val warnings = new ConcurrentLinkedQueue[String]()
+    IoRs.setWarningHandler { warning => warnings.add(warning); () }
+    val runtime = IoRsRuntime(IoRsRuntimeConfig(watchdogThreshold = Some(20.millis), watchdogInterval = 5.millis))
+    try {
+      // stuck for a lot of scans past the threshold
+      IoRs.delay(Thread.sleep(300)).unsafeRunSync(runtime)
+      runtime.shutdown(1.second)
+
+      val stuck = warnings.toArray.map(_.toString).filter(_.contains("running the delay thunk"))
+      assertEquals(s"the warnings about the stuck thunk in ${stuck.toList}", 1, stuck.length)
+      assert(stuck.head.startsWith("iors watchdog: "), s"not a watchdog warning: ${stuck.head}")
+    } finally {
+      IoRs.setWarningHandler(System.err.println(_))
+    }
+  }
+
+  def badConfigIsRejectedWhole(): Unit = {
+    val rejected =
+      try {
+        IoRs.configure(IoRsRuntimeConfig(asyncStackTraceDepth = 8, localFrameCapacity = 0))
+        false
+      } catch {
+        case exc: IllegalArgumentException => exc.getMessage.contains("iors.localFrameCapacity")
+      }
+
+    // the valid setting from the same call must not have been applied either
+    val io = IoRs.pure(1).map[Int](_ => throw new RuntimeException("boom")).attempt
+    val untraced = io.unsafeRunSync().left.exists(_.getSuppressed.isEmpty)
+
+    assert(rejected, "the bad setting wasn't rejected")
+    assert(untraced, "the valid setting was applied")
+  }
+
+  def runtimesAreIsolated(): Unit = {
+    val used = IoRsRuntime(IoRsRuntimeConfig(computeThreads = 1))
+    val unused = IoRsRuntime()
+
+    val thread = IoRs.pure(1).flatMap(_ => IoRs.sleep(10.millis)).map(_ => Thread.currentThread.getName).unsafeRunSync(used)
+
+    assert(thread.endsWith("-compute-0"), s"continued on $thread")
+    assert(used.stats.nodesEvaluated > 0, "the runtime used didn't count the nodes")
+    assertEquals("the nodes the unused runtime evaluated", 0L, unused.stats.nodesEvaluated)
+  }
+
+  def shutdownCancelsStuckPrograms(): Unit = {
+    val runtime = IoRsRuntime()
+    val stuck = new ArrayBlockingQueue[Either[Throwable, Int]](1)
+    // holding on to the callback, so that it doesn't get reported as lost instead
+    var callback: Either[Throwable, Int] => Unit = null
+    IoRs.async[Int](cb => callback = cb).map(_ + 1).attempt.map(_ => 1).unsafeRunAsync(runtime, stuck.put)
+    val completed = IoRs.sleep(10.millis).map(_ => 2).unsafeRunSync(runtime) == 2
+
+    val cancelled = runtime.shutdown(100.millis)
+    val rejected =
+      try {
+        IoRs.pure(3).unsafeRunSync(runtime)
+        false
+      } catch {
+        case _: IllegalStateException => true
+      }
+
+    val skippedHandlers = stuck.take() match {
+      case Left(_: CancellationException) => true
+      case _ => false
+    }
+
+    assert(completed, "the program that completed in time didn't")
+    assert(rejected, "a program was started after the shutdown")
+    assert(skippedHandlers, "the stuck program ran its handlers")
+    assert(callback != null, "the stuck program wasn't registered")
+    assertEquals("the cancelled fibers", 1, cancelled.size)
+    assert(cancelled.head.contains("suspended on async"), s"unexpected description ${cancelled.head}")
+  }
+
+  private def threads(prefix: String): List[String] =
+    Thread.getAllStackTraces.keySet.toArray.toList.map(_.asInstanceOf[Thread].getName).filter(_.startsWith(prefix))
+
+  /** Collects garbage until the threads named `prefix...` are gone, for at most 10 seconds. */
+  private def awaitThreadsGone(prefix: String): Unit = {
+    val deadline = System.nanoTime() + 10.seconds.toNanos
+    while (threads(prefix).nonEmpty && System.nanoTime() < deadline) {
+      System.gc()
+      System.runFinalization()
+      Thread.sleep(10)
+    }
+  }
+
+  def collectedRuntimesStopTheirThreads(): Unit = {
+    // a method of its own, so that nothing refers to the runtime once it returns
+    def use(): String = {
+      val runtime = IoRsRuntime(IoRsRuntimeConfig(computeThreads = 1, watchdogThreshold = Some(1.minute)))
+      // the compute pool, the timer and the watchdog are running after that
+      IoRs.sleep(1.milli).map(_ => Thread.currentThread.getName.stripSuffix("compute-0")).unsafeRunSync(runtime)
+    }
+
+    val prefix = use()
+    val started = threads(prefix)
+    awaitThreadsGone(prefix)
+
+    assertEquals(s"the threads of the runtime $prefix", 3, started.size)
+    assertEquals(s"the threads left after the runtime $prefix was collected", Nil, threads(prefix))
+  }
+
+  def globalsSurviveConcurrentReloads(): Unit = {
+    val shared = IoRsRuntime(IoRsRuntimeConfig(computeThreads = 2))
+    val globalBefore = IoRsRuntime.global
+    val failures = new ConcurrentLinkedQueue[Throwable]()
+
+    val threads = (0 until 8).map { i =>
+      new Thread(() =>
+        try {
+          for (j <- 0 until 50) {
+            if (i % 2 == 0) {
+              NativeTestHooks.reloadGlobals()
+            } else {
+              val own = j % 5 == 0
+              val runtime =
+                if (own) IoRsRuntime(IoRsRuntimeConfig(computeThreads = 1))
+                else if (j % 5 == 1) IoRsRuntime.global
+                else shared
+              val result = IoRs.pure(1).flatMap(x => IoRs.sleep(1.milli).map(_ => x + 1)).unsafeRunSync(runtime)
+              if (result != 2) failures.add(new AssertionError(s"got $result"))
+              if (own) runtime.shutdown(1.second)
+            }
+          }
+        } catch {
+          case t: Throwable => failures.add(t)
+        }
+      )
+    }
+    threads.foreach(_.start())
+    threads.foreach(_.join())
+    shared.shutdown(1.second)
+
+    NativeTestHooks.reloadGlobals()
+    failures.forEach(_.printStackTrace())
+    assertEquals("the failures", 0, failures.size)
+    assert(IoRsRuntime.global ne globalBefore, "the global runtime wasn't replaced")
+    assertEquals("a program after the reloads", 1, IoRs.pure(1).unsafeRunSync(IoRsRuntime()))
+    assertEquals("a program on the global runtime after the reloads", 1, IoRs.pure(1).unsafeRunSync())
+  }
+
+  def replacedGlobalRuntimesStopTheirThreads(): Unit = {
+    // the compute pool and the timer are running after that
+    val prefix = IoRs.sleep(1.milli).map(_ => Thread.currentThread.getName.replaceAll("compute-\\d+$", "")).unsafeRunSync()
+    val started = threads(prefix)
+    NativeTestHooks.reloadGlobals()
+    awaitThreadsGone(prefix)
+
+    assert(started.nonEmpty, s"the global runtime $prefix had no threads")
+    assertEquals(s"the threads left after the global runtime $prefix was replaced", Nil, threads(prefix))
+  }
+
+  def otherClassLoadersGetTheirOwnGlobals(): Unit = {
+    val nodesBefore = IoRs.runtimeStats().nodesEvaluated
+    // a method of its own, so that nothing refers to the loader once it returns
+    def runElsewhere(): (AnyRef, Boolean, WeakReference[ClassLoader]) = {
+      val loader = new OtherClassLoader
+      val nodes = loader.run("globalRuntimeNodes")
+      val otherClasses = loader.loadClass("iors.IoRs") ne classOf[IoRs[_]]
+      loader.close()
+      (nodes, otherClasses, new WeakReference(loader))
+    }
+    val (nodes, otherClasses, collected) = runElsewhere()
+
+    assert(otherClasses, "the other loader got the classes of this one")
+    assert(nodes.asInstanceOf[java.lang.Long] > 0, s"the other loader's global runtime evaluated $nodes nodes")
+    assertEquals("the nodes of this loader's global runtime", nodesBefore, IoRs.runtimeStats().nodesEvaluated)
+
+    // unloading the other copy of the library shuts down its runtimes only
+    val deadline = System.nanoTime() + 10.seconds.toNanos
+    while (collected.get != null && System.nanoTime() < deadline) {
+      System.gc()
+      Thread.sleep(10)
+    }
+    assertEquals("a program after the other loader is gone", 1, IoRs.pure(1).unsafeRunSync())
+  }
+
+  /** What loading the library in `loader` failed with. */
+  private def loadingFails(loader: OtherClassLoader): String =
+    try {
+      loader.run("globalRuntimeNodes")
+      "nothing, it loaded"
+    } catch {
+      case e: InvocationTargetException => e.getCause.toString
+    } finally loader.close()
+
+  def mismatchedJarsFailLoading(): Unit = {
+    val stale = loadingFails(new OtherClassLoader(Some("OldNativeProto")))
+    val missing = loadingFails(new OtherClassLoader(None))
+
+    assert(
+      stale.startsWith("java.lang.UnsatisfiedLinkError") && stale.contains("(protocol version 1) doesn't match"),
+      s"loading against an older jar failed with $stale",
+    )
+    assert(
+      missing.startsWith("java.lang.UnsatisfiedLinkError") && missing.contains("has no NativeProtocol.Version"),
+      s"loading against a jar without a version failed with $missing",
+    )
+  }
+
+  def badFusionDepthsFailLoading(): Unit = {
+    val depths = List("0", "-1", "deep")
+    val failures = for (depth <- depths) yield {
+      System.setProperty("iors.fusionMaxStackDepth", depth)
+      try loadingFails(new OtherClassLoader)
+      finally {
+        val _ = System.clearProperty("iors.fusionMaxStackDepth")
+      }
+    }
+
+    for ((failure, depth) <- failures.zip(depths)) {
+      assert(
+        failure.contains(s"iors.fusionMaxStackDepth must be a positive integer, got '$depth'"),
+        s"loading with iors.fusionMaxStackDepth=$depth failed with $failure",
+      )
+    }
+  }
+
+  def unloadingShutsDownEveryRuntime(): Unit = {
+    val runtime = IoRsRuntime()
+    val stuck = new ArrayBlockingQueue[Either[Throwable, Int]](1)
+    // holding on to the callback, so that it doesn't get reported as lost instead
+    var callback: Either[Throwable, Int] => Unit = null
+    IoRs.async[Int](cb => callback = cb).unsafeRunAsync(runtime, stuck.put)
+    val globalBefore = IoRsRuntime.global
+    val _ = IoRs.pure(1).unsafeRunSync(globalBefore)
+    // a method of its own, so that only the registry refers to the handler
+    def registerKind(): WeakReference[AnyRef] = {
+      val result = IoRs.pure(0)
+      val handler: Array[AnyRef] => IoRs[Any] = _ => result
+      val _ = IoRs.registerNodeKind(110, "forgotten", Seq())(handler)
+      new WeakReference[AnyRef](handler)
+    }
+    val handler = registerKind()
+
+    NativeTestHooks.unloadAndReload()
+
+    def rejects(runtime: IoRsRuntime): Boolean =
+      try {
+        IoRs.pure(1).unsafeRunSync(runtime)
+        false
+      } catch {
+        case _: IllegalStateException => true
+      }
+    val forgotten =
+      try {
+        val _ = IoRs.nodeKind(110)
+        false
+      } catch {
+        case _: NoSuchElementException => true
+      }
+    val deadline = System.nanoTime() + 10.seconds.toNanos
+    while (handler.get != null && System.nanoTime() < deadline) {
+      System.gc()
+      Thread.sleep(10)
+    }
+    val cancelled = stuck.poll(10, TimeUnit.SECONDS) match {
+      case Left(_: CancellationException) => true
+      case _ => false
+    }
+
+    assert(callback != null, "the stuck program wasn't registered")
+    assert(cancelled, "the stuck program wasn't cancelled")
+    assert(rejects(runtime), "a runtime took a program after the unload")
+    assert(rejects(globalBefore), "the old global runtime took a program after the unload")
+    assert(forgotten, "the node kind was kept after the unload")
+    assert(handler.get == null, "the handler of the node kind was kept after the unload")
+    assertEquals("a program on the reloaded global runtime", 2, IoRs.pure(2).unsafeRunSync())
+  }
+
+  def extensionNodesRunTheirHandler(): Unit = {
+    val double = IoRs.registerNodeKind(100, "double", Seq("n")) { fields =>
+      IoRs.pure(fields(0).asInstanceOf[Integer] * 2)
+    }
+    val later = IoRs.registerNodeKind(101, "later", Seq("n")) { fields =>
+      IoRs.sleep(1.milli).map(_ => fields(0))
+    }
+    def boom(): IoRs[Any] = throw new RuntimeException("boom")
+    val failing = IoRs.registerNodeKind(102, "failing", Seq())(_ => boom())
+
+    val program = for {
+      x <- double[Int](Int.box(20))
+      y <- later[Integer](Int.box(x + 1))
+      failed <- failing[Int]().attempt
+    } yield (y.intValue, failed.left.map(_.getMessage))
+
+    val rejected =
+      try {
+        IoRs.registerNodeKind(100, "again", Seq())(_ => IoRs.pure(0))
+        false
+      } catch {
+        case _: IllegalArgumentException => true
+      }
+
+    assertEquals("the program", (41, Left("boom")), program.unsafeRunSync())
+    assert(rejected, "the tag was registered twice")
+    assertEquals("the fields of the kind", Seq("n"), IoRs.nodeKind(101).fieldNames)
+  }
+
+  /** Runs nodes of the kinds `native_node_kinds_step` in the integration tests registers natively, one for every step
+   * and one that fails.
+   */
+  def nativeNodeKindsStep(): Unit = {
+    val value = IoRs.nodeKind(200)
+    val program = IoRs.nodeKind(201)
+    val bind = IoRs.nodeKind(202)
+    val suspend = IoRs.nodeKind(203)
+    val fail = IoRs.nodeKind(204)
+    val register: (Either[Throwable, Integer] => Unit) => Unit = cb => cb(Right(Int.box(4)))
+    val all = for {
+      a <- value[Integer](Int.box(1))
+      b <- program[Integer](IoRs.pure(Int.box(2)))
+      c <- bind[Integer](IoRs.pure(Int.box(3)), (n: Integer) => IoRs.pure(Int.box(n * 10)))
+      d <- suspend[Integer](register)
+      failed <- fail[Int](null: AnyRef).attempt
+      thrown <- fail[Int]("thrown").attempt
+    } yield (List(a, b, c, d).map(_.intValue), failed.left.map(_.getMessage), thrown.left.map(_.getClass))
+
+    assertEquals(
+      "the program",
+      (List(1, 2, 30, 4), Left("the node handler failed with 1"), Left(classOf[IllegalArgumentException])),
+      all.unsafeRunSync(),
+    )
+    assertEquals("the fields of the bind kind", Seq("source", "f"), bind.fieldNames)
+  }
+
+  def compiledProgramsRunTheSame(): Unit = {
+    val compiling = IoRsRuntime(IoRsRuntimeConfig(compilePrograms = true))
+    def program: IoRs[Either[String, Int]] =
+      IoRs.pure(1)
+        .map(_ + 1)
+        .flatMap(x => IoRs.sleep(1.milli).map(_ => x * 10))
+        .attempt
+        .flatMap {
+          case Right(x) => IoRs.raiseError[Int](new RuntimeException(s"got $x"))
+          case Left(e) => IoRs.raiseError[Int](e)
+        }
+        .attempt
+        .map(_.left.map(_.getMessage))
+
+    val notCompiling = IoRsRuntime()
+    val compiled = program.unsafeRunSync(compiling)
+    val plain = program.unsafeRunSync(notCompiling)
+    // compiled before it's handed over, the runtime doesn't call back for it
+    val upcalls = (compiling.stats.jniUpcalls, notCompiling.stats.jniUpcalls)
+    // turned on for a runtime created without it
+    notCompiling.configure(IoRsRuntimeConfig(compilePrograms = true))
+    val reconfigured = program.unsafeRunSync(notCompiling)
+
+    val broken = new SharedFrames(1)
+    broken.push(IoRs.Tag.Pure, null)
+    val failed = IoRs.Compiled[Int](broken, IoRs.pure(1)).attempt.map(_.left.map(_.getMessage)).unsafeRunSync()
+    compiling.shutdown(1.second)
+    notCompiling.shutdown(1.second)
+
+    assertEquals("the compiled program", Left("got 20"), compiled)
+    assertEquals("the plain program", compiled, plain)
+    assertEquals("the upcalls of the compiled and the plain program", upcalls._2, upcalls._1)
+    assertEquals("the program of the reconfigured runtime", compiled, reconfigured)
+    assert(notCompiling.compilesPrograms, "the reconfigured runtime doesn't compile")
+    assertEquals("the broken program", Left("Pure is not a frame a program can be compiled to"), failed)
+  }
+
+  def sharedBindStackRunsTheSame(): Unit = {
+    val shared = IoRsRuntime(IoRsRuntimeConfig(bindStack = BindStackStrategy.Shared))
+
+    // a few hundred frames deep, more than the stack starts out with
+    def nested(depth: Int): IoRs[Int] =
+      if (depth == 0) IoRs.sleep(1.milli).map(_ => 0)
+      else nested(depth - 1).map(_ + 1).attempt.flatMap(r => IoRs.fromEither(r))
+    val failing = (1 to 200)
+      .foldLeft(IoRs.raiseError[Int](new RuntimeException("boom")))((io, _) => io.map(_ + 1))
+      .attempt
+      .map(_.left.map(_.getMessage))
+
+    val result = nested(200).unsafeRunSync(shared)
+    val recovered = failing.unsafeRunSync(shared)
+
+    val suspended = new ArrayBlockingQueue[Either[Throwable, Int]](1)
+    var callback: Either[Throwable, Int] => Unit = null
+    IoRs.async[Int](cb => callback = cb).map(_ + 1).attempt.map(_ => 1).unsafeRunAsync(shared, suspended.put)
+    val dump = shared.fiberDump()
+    callback(Right(1))
+    val resumed = suspended.take() == Right(1)
+    shared.shutdown(1.second)
+
+    assertEquals("the nested program", 200, result)
+    assertEquals("the failing program", Left("boom"), recovered)
+    assert(resumed, "the suspended program didn't resume")
+    assert(dump.contains("    map ") && dump.contains("    attempt"), s"frames missing from $dump")
+  }
+
+  def sharedFramesChangeHandsInPlace(): Unit = {
+    val frames = new SharedFrames(1)
+    (1 to 100).foreach(i => frames.push(if (i % 10 == 0) IoRs.Tag.Attempt else IoRs.Tag.Map, Int.box(i)))
+    val top = frames.pop()
+    val unwound = frames.unwindToAttempt()
+
+    assertEquals("the popped function", Int.box(100), top)
+    assert(unwound, "no attempt to unwind to")
+    assertEquals("the frames left", 89, frames.depth)
+    assertEquals("the tag of the top frame", IoRs.Tag.Map.underlying, frames.tag(88))
+    assertEquals("the function of the top frame", Int.box(89), frames.functions(88))
+
+    // the frames pushed on either side get run by the other one
+    val hybrid = IoRsRuntime(IoRsRuntimeConfig(bindStack = BindStackStrategy.Shared, execution = ExecutionMode.Hybrid))
+    def nested(depth: Int): IoRs[Int] =
+      if (depth == 0) IoRs.async[Int](cb => cb(Right(0)))
+      else nested(depth - 1).map(_ + 1).attempt.flatMap(r => IoRs.fromEither(r))
+    val failing = IoRs
+      .async[Int](cb => cb(Left(new RuntimeException("boom"))))
+      .map(_ + 1)
+      .flatMap(x => (1 to 200).foldLeft(IoRs.pure(x))((io, _) => io.map(_ + 1)))
+      .attempt
+      .map(_.left.map(_.getMessage))
+
+    val result = nested(200).unsafeRunSync(hybrid)
+    val recovered = failing.unsafeRunSync(hybrid)
+    hybrid.shutdown(1.second)
+
+    assertEquals("the nested program", 200, result)
+    assertEquals("the failing program", Left("boom"), recovered)
+  }
+
+  def bindFramesStayLocalUntilSuspended(): Unit = {
+    val runtime = IoRsRuntime(IoRsRuntimeConfig())
+    val frames = 1000
+    // flatMaps, maps would get fused into a few frames
+    def chain(leaf: IoRs[Int]): IoRs[Int] =
+      (1 to frames).foldLeft(leaf)((io, _) => io.flatMap(x => IoRs.pure(x + 1)))
+
+    val synchronous = chain(IoRs.pure(0)).unsafeRunSync(runtime)
+    val synchronousRefs = runtime.stats.globalRefsCreated
+    // every frame below the sleep has to outlive the thread it was pushed on
+    val suspending = chain(IoRs.sleep(1.milli).map(_ => 0)).unsafeRunSync(runtime)
+    val promotedRefs = runtime.stats.globalRefsCreated - synchronousRefs
+    runtime.shutdown(1.second)
+
+    assertEquals("the synchronous program", frames, synchronous)
+    assertEquals("the suspending program", frames, suspending)
+    assert(synchronousRefs < frames / 10, s"$synchronousRefs global references for the synchronous program")
+    assert(promotedRefs >= frames, s"only $promotedRefs global references for the suspending program")
+  }
+
+  def classDispatchRunsTheSame(): Unit = {
+    val byClass = IoRsRuntime(IoRsRuntimeConfig(nodeDispatch = NodeDispatch.NodeClass))
+    def program: IoRs[Either[String, Int]] =
+      IoRs(1)
+        .map(_ + 1)
+        .flatMap(x => IoRs.sleep(1.milli).map(_ => x * 10))
+        .flatMap(x => IoRs.raiseError[Int](new RuntimeException(s"got $x")))
+        .attempt
+        .map(_.left.map(_.getMessage))
+
+    val dispatched = program.unsafeRunSync(byClass)
+    val plain = program.unsafeRunSync()
+    byClass.shutdown(1.second)
+    assertEquals("the program dispatched by class", Left("got 20"), dispatched)
+    assertEquals("the program dispatched by tag", dispatched, plain)
+  }
+
+  def mapChainsGetFused(): Unit = {
+    val runtime = IoRsRuntime(IoRsRuntimeConfig())
+    val chain = (1 to 1000).foldLeft(IoRs.pure(0))((io, _) => io.map(_ + 1))
+    val bounded = chain match {
+      case IoRs.Map(_, f) => IoRs.Fused.depth(f) <= IoRs.FusionMaxStackDepth
+      case _ => false
+    }
+
+    val result = chain.unsafeRunSync(runtime)
+    // a handful of fused map nodes and the pure one, instead of a thousand maps and pures
+    val nodes = runtime.stats.nodesEvaluated
+    val failing = chain
+      .map(x => if (x > 0) throw new RuntimeException("boom") else x)
+      .map(_ + 1)
+      .attempt
+      .map(_.left.map(_.getMessage))
+      .unsafeRunSync(runtime)
+    runtime.shutdown(1.second)
+
+    assertEquals("the chain", 1000, result)
+    assert(bounded, "a fused function is deeper than the maximum")
+    assert(nodes < 100, s"$nodes nodes evaluated")
+    assertEquals("the failing chain", Left("boom"), failing)
+  }
+
+  def valuesAndErrorsCarryOverSteps(): Unit = {
+    // yields on every step, so the values and errors the loop holds have to become nodes in between
+    val yielding = IoRsRuntime(IoRsRuntimeConfig(autoYieldThreshold = 1))
+    def program: IoRs[(Boolean, Either[String, Int])] =
+      for {
+        isNull <- IoRs(1).map(_ => null: String).flatMap(s => IoRs.pure(s == null))
+        failed <- IoRs(1).map(x => if (x > 0) throw new RuntimeException("boom") else x).map(_ + 1).attempt
+        recovered <- IoRs.raiseError[Int](new RuntimeException("again")).attempt.map(_.isLeft)
+      } yield (isNull && recovered, failed.left.map(_.getMessage))
+
+    val results = Seq(program.unsafeRunSync(), program.unsafeRunSync(yielding))
+    yielding.shutdown(1.second)
+    assertEquals("the results without and with yielding", Seq((true, Left("boom")), (true, Left("boom"))), results)
+  }
+
+  def specializedMapsRunUnboxed(): Unit = {
+    val ints = IoRs.pureInt(1).map(_ + 1).map(_ * 3)
+    val longs = IoRs.pureLong(1L).map(_ + 1L)
+    val doubles = IoRs.pureDouble(1.5).map(_ * 2)
+    val specialized =
+      ints.isInstanceOf[IoRs.MapIntInt] && longs.isInstanceOf[IoRs.MapLongLong] &&
+        doubles.isInstanceOf[IoRs.MapDoubleDouble]
+
+    // the values get boxed for the frames that aren't specialized
+    val program = for {
+      i <- ints
+      l <- longs.map(_.toString)
+      d <- doubles.flatMap(d => IoRs.pure(d + 1))
+      failed <- IoRs.pureInt(1).map(x => if (x > 0) throw new RuntimeException("boom") else x).attempt
+    } yield (i, l, d, failed.left.map(_.getMessage))
+
+    assert(specialized, "the maps of the specialized nodes aren't specialized")
+    assertEquals("the program", (6, "2", 4.0, Left("boom")), program.unsafeRunSync())
+  }
+
+  def specializedMapsArentFused(): Unit = {
+    // the node classes down to the pure one, and whether any of their functions is fused
+    def nodes(io: IoRs[Any]): (List[String], Boolean) = io match {
+      case IoRs.MapIntInt(source, f) => step(io, source, f)
+      case IoRs.MapLongLong(source, f) => step(io, source, f)
+      case IoRs.MapDoubleDouble(source, f) => step(io, source, f)
+      case _ => (List(io.getClass.getSimpleName), false)
+    }
+    def step(io: IoRs[Any], source: IoRs[Any], f: AnyRef): (List[String], Boolean) = {
+      val (rest, fused) = nodes(source)
+      (io.getClass.getSimpleName :: rest, fused || f.isInstanceOf[IoRs.Fused[_, _, _]])
+    }
+
+    assertEquals("a map of pureInt", (List("MapIntInt", "PureInt"), false), nodes(IoRs.pureInt(1).map(_ + 1)))
+    assertEquals(
+      "chained maps of pureInt",
+      (List("MapIntInt", "MapIntInt", "PureInt"), false),
+      nodes(IoRs.pureInt(1).map(_ + 1).map(_ * 2)),
+    )
+    assertEquals(
+      "chained maps of pureLong",
+      (List("MapLongLong", "MapLongLong", "PureLong"), false),
+      nodes(IoRs.pureLong(1L).map(_ + 1L).map(_ * 2L)),
+    )
+    assertEquals(
+      "chained maps of pureDouble",
+      (List("MapDoubleDouble", "MapDoubleDouble", "PureDouble"), false),
+      nodes(IoRs.pureDouble(1.0).map(_ + 1.0).map(_ * 2.0)),
+    )
+    assertEquals("the chained maps of pureInt", 4, IoRs.pureInt(1).map(_ + 1).map(_ * 2).unsafeRunSync())
+  }
+
+  def nativeCallsAgree(): Unit = {
+    val runtime = IoRsRuntime()
+    val ran = IoRs.async[Int](cb => cb(Right(1))).map(_ + 1).unsafeRunSync(runtime) == 2
+    val handle = runtime.nativeHandle
+    val stats = runtime.stats
+
+    // sbt builds the jar on the JDK the tests run on, the foreign binding is there from 22 on
+    val foreign = Option(NativeCalls.foreign())
+    if (Runtime.version().feature() >= 22) assert(foreign.isDefined, "no foreign binding on JDK 22+")
+    val bindings = Seq("jni" -> NativeCalls.JNI) ++ foreign.map("foreign" -> _)
+    for ((name, calls) <- bindings) {
+      assertEquals(s"nodesEvaluated through $name", stats.nodesEvaluated, calls.runtimeStat(handle, 0))
+      assertEquals(s"ffiClosuresCreated through $name", stats.ffiClosuresCreated, calls.runtimeStat(handle, 3))
+      assertEquals(s"an unknown counter through $name", -1L, calls.runtimeStat(handle, 5))
+    }
+    assertEquals("jniUpcalls through the object", stats.jniUpcalls, IoRs.runtimeStat0(handle, 1))
+    val _ = runtime.shutdown(1.second)
+    assert(ran, "the program didn't run")
+  }
+
+  def statsCountWhatRan(): Unit = {
+    val runtime = IoRsRuntime()
+    def counted(io: IoRs[Any]): RuntimeStats = {
+      val before = runtime.stats
+      io.unsafeRunSync(runtime)
+      val after = runtime.stats
+      RuntimeStats(
+        after.nodesEvaluated - before.nodesEvaluated,
+        after.jniUpcalls - before.jniUpcalls,
+        after.globalRefsCreated - before.globalRefsCreated,
+        after.ffiClosuresCreated - before.ffiClosuresCreated,
+        after.asyncBoundaries - before.asyncBoundaries,
+      )
+    }
+
+    val synchronous = counted(IoRs.pure(1).map(_ + 1).flatMap(x => IoRs(x * 2)).attempt)
+    val async = counted(IoRs.async[Int](cb => cb(Right(1))).map(_ + 1))
+    runtime.shutdown(1.second)
+
+    // Attempt, FlatMap, Map, Pure and Delay. The two functions, the thunk and `Right.apply`, the value is returned to the
+    // caller without a global reference
+    assertEquals("the stats of the synchronous program", RuntimeStats(5, 4, 0, 0, 0), synchronous)
+    // Map, Async and the Pure the callback resumes with. The register function, `IoRs.fromEither` and the map, the
+    // callback is only constructed
+    assertEquals("the nodes of the async program", 3L, async.nodesEvaluated)
+    assertEquals("the upcalls of the async program", 3L, async.jniUpcalls)
+    assertEquals("the closures of the async program", 1L, async.ffiClosuresCreated)
+    assertEquals("the async boundaries of the async program", 1L, async.asyncBoundaries)
+  }
+
+  def hybridRunsTheSame(): Unit = {
+    val runtimes = Seq(ExecutionMode.Native, ExecutionMode.Hybrid, ExecutionMode.Auto).map { mode =>
+      IoRsRuntime(IoRsRuntimeConfig(execution = mode))
+    }
+    def loop(i: Int): IoRs[Int] = if (i < 1000) IoRs.pure(i + 1).flatMap(loop) else IoRs.pure(i)
+    def program: IoRs[(Int, Either[String, Int], Either[String, Int], Int)] = for {
+      looped <- IoRs.pure(0).flatMap(loop)
+      thrown <- IoRs(1).map(x => if (x > 0) throw new RuntimeException("thrown") else x).attempt
+      // the frames below the sleep go to the native side and back
+      raised <- IoRs.sleep(1.milli).flatMap(_ => IoRs.raiseError[Int](new RuntimeException("raised"))).attempt
+      specialized <- IoRs.pureInt(1).map(_ + 1).map(_ * 3)
+    } yield (looped, thrown.left.map(_.getMessage), raised.left.map(_.getMessage), specialized)
+
+    val results = runtimes.map(program.unsafeRunSync(_))
+
+    // a single upcall for the whole loop, instead of one per function
+    val hybrid = runtimes(1)
+    val before = hybrid.stats.jniUpcalls
+    val looped = IoRs.pure(0).flatMap(loop).unsafeRunSync(hybrid)
+    val upcalls = hybrid.stats.jniUpcalls - before
+    runtimes.foreach(_.shutdown(1.second))
+
+    assertEquals("the results of the modes", Seq.fill(3)((1000, Left("thrown"), Left("raised"), 6)), results)
+    assertEquals("the loop", 1000, looped)
+    assert(upcalls < 10, s"$upcalls upcalls for the loop")
+  }
+
+  def hybridLoopErrorsCanBeAttempted(): Unit = {
+    val hybrid = IoRsRuntime(IoRsRuntimeConfig(execution = ExecutionMode.Hybrid))
+    // a node without its frames, which SyncLoop itself trips over
+    val broken = IoRs.Compiled[Int](null, IoRs.pure(1))
+    val attempted = IoRs.pure(1).flatMap(_ => broken).attempt.map(_.left.map(_.getClass)).unsafeRunSync(hybrid)
+    hybrid.shutdown(1.second)
+
+    assertEquals("the attempted program", Left(classOf[NullPointerException]), attempted)
+  }
+
+  def unsafeRunSyncReturnsDirectly(): Unit = {
+    val runtime = IoRsRuntime()
+    def failure(io: IoRs[Int]): Option[String] =
+      try {
+        val _ = io.unsafeRunSync(runtime)
+        None
+      } catch {
+        case e: RuntimeException => Some(e.getMessage)
+      }
+
+    // no `Right` and no callback to call
+    val before = runtime.stats.jniUpcalls
+    val value = IoRs.pure(1).unsafeRunSync(runtime)
+    val upcalls = runtime.stats.jniUpcalls - before
+
+    val nullValue = IoRs.pure[String](null).unsafeRunSync(runtime)
+    val raised = failure(IoRs.raiseError[Int](new RuntimeException("raised")))
+    // completed on another thread while the calling one is parked
+    val async = IoRs.sleep(1.milli).map(_ => 2).unsafeRunSync(runtime)
+    val asyncRaised = failure(IoRs.sleep(1.milli).flatMap(_ => IoRs.raiseError[Int](new RuntimeException("async"))))
+    val _ = runtime.shutdown(1.second)
+
+    assertEquals("the value", 1, value)
+    assertEquals("the upcalls", 0L, upcalls)
+    assertEquals("the null value", null, nullValue)
+    assertEquals("the error", Some("raised"), raised)
+    assertEquals("the async value", 2, async)
+    assertEquals("the async error", Some("async"), asyncRaised)
+  }
+
   def expensive(): Unit = {
     val dummy = new RuntimeException("dummy")
     val id = IoRs.pure[Int] _
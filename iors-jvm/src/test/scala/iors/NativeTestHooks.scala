@@ -0,0 +1,23 @@
+package iors
+
+/** What the tests do to the native library that programs never should. Its natives are found by their
+ * `Java_iors_NativeTestHooks_...` symbols, which only a library built with the `test-hooks` feature has, as the
+ * integration tests build it.
+ */
+object NativeTestHooks {
+
+  /** Resolves the classes of the runtime again, like loading the library does, replacing the global runtime. */
+  def reloadGlobals(): Unit = IoRsRuntime.reloadGlobal(reloadGlobals0())
+
+  /** Shuts down every runtime and forgets the node kinds like unloading the library does, then loads the globals again
+   * so that the tests after this one have a global runtime.
+   */
+  def unloadAndReload(): Unit = IoRsRuntime.reloadGlobal {
+    unload0()
+    reloadGlobals0()
+  }
+
+  @native private def reloadGlobals0(): Unit
+
+  @native private def unload0(): Unit
+}
@@ -0,0 +1,71 @@
+package iors
+
+import java.io.File
+import java.net.URLClassLoader
+import java.nio.charset.StandardCharsets
+import java.nio.file.{Files, Path, Paths, StandardCopyOption}
+
+import scala.util.Using
+
+/** Loads the iors and Scala classes again, apart from the ones of the loader it's created from, along with a copy of the
+ * native library of their own, since the JVM won't load one library file into two class loaders.
+ *
+ * @param nativeProtocol the object in this package that stands in for `NativeProtocol`, which has to have a name as long
+ *                       as that, or `None` for a jar without it
+ */
+class OtherClassLoader(nativeProtocol: Option[String] = Some("NativeProtocol"))
+    extends URLClassLoader(
+      Array(classOf[IoRs[_]], classOf[OtherClassLoader], classOf[Option[_]])
+        .map(_.getProtectionDomain.getCodeSource.getLocation)
+        .distinct,
+      ClassLoader.getPlatformClassLoader,
+    ) {
+
+  private val library: Path = {
+    val name = System.mapLibraryName("iors")
+    val original = System
+      .getProperty("java.library.path")
+      .split(File.pathSeparator)
+      .iterator
+      .map(Paths.get(_, name))
+      .find(Files.isRegularFile(_))
+      .getOrElse(throw new IllegalStateException(s"no $name on java.library.path"))
+    val copy = Files.createTempFile("iors", name)
+    copy.toFile.deleteOnExit()
+    Files.copy(original, copy, StandardCopyOption.REPLACE_EXISTING)
+  }
+
+  override protected def findLibrary(libname: String): String =
+    if (libname == "iors") library.toString else super.findLibrary(libname)
+
+  override protected def findClass(name: String): Class[_] = (name, nativeProtocol) match {
+    case ("iors.NativeProtocol" | "iors.NativeProtocol$", None) => throw new ClassNotFoundException(name)
+    case ("iors.NativeProtocol" | "iors.NativeProtocol$", Some(standIn)) if standIn != "NativeProtocol" =>
+      // the stand-in's class file, with its name replaced everywhere it appears
+      val suffix = name.stripPrefix("iors.NativeProtocol")
+      val original = Using.resource(getResourceAsStream(s"iors/$standIn$suffix.class")) { stream =>
+        new String(stream.readAllBytes(), StandardCharsets.ISO_8859_1)
+      }
+      val bytes = original.replace(s"iors/$standIn", "iors/NativeProtocol").getBytes(StandardCharsets.ISO_8859_1)
+      defineClass(name, bytes, 0, bytes.length)
+    case _ => super.findClass(name)
+  }
+
+  /** Calls `OtherLoaderPrograms.<program>()` as loaded by this loader. */
+  def run(program: String): AnyRef = loadClass("iors.OtherLoaderPrograms").getMethod(program).invoke(null)
+}
+
+/** What the tests run in an [[OtherClassLoader]]. They return classes of the JDK only, the others aren't the same. */
+object OtherLoaderPrograms {
+
+  /** Runs a program on the global runtime, returns how many nodes that runtime has evaluated. */
+  def globalRuntimeNodes(): java.lang.Long = {
+    val _ = IoRs.pure(1).map(_ + 1).unsafeRunSync()
+    IoRs.runtimeStats().nodesEvaluated
+  }
+}
+
+/** Stands in for `NativeProtocol` in the jar of an [[OtherClassLoader]] that doesn't match the native library. */
+private[iors] object OldNativeProto {
+  final val Version = 1
+}
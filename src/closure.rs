@@ -1,36 +1,91 @@
-use crate::Result;
+use crate::{metrics::count, runtime::Runtime, Result};
 use jni::objects::JValue;
-use jni::{objects::JObject, JNIEnv};
-use std::sync::MutexGuard;
+use jni::{
+    objects::{JClass, JObject},
+    sys::jlong,
+    JNIEnv,
+};
+use std::sync::Mutex;
 
-// todo: the *_rust_field methods are doing a lot of lookups :/ we may want to do this ourselves
-//   and add caching
+struct FfiClosure {
+    apply: Box<dyn FnOnce(JNIEnv, JObject) + Send + Sync + 'static>,
+    // called instead of `apply` if the closure gets collected without ever being applied
+    lost: Box<dyn FnOnce(JNIEnv) + Send + Sync + 'static>,
+}
+
+// the `nativePointer` of an `FfiClosure` points at one of these
+type Closure = Mutex<Option<FfiClosure>>;
 
-type Closure = Option<Box<dyn FnOnce(JNIEnv, JObject) + Send + Sync + 'static>>;
+/// Takes the closure out of its handle, so that it runs once at most.
+fn take(handle: jlong) -> Option<FfiClosure> {
+    // the handle stays valid until the finalizer drops it, and the closure object, which can't be
+    // finalized during a call of its instance method, is the only one that hands it out
+    let closure = unsafe { &*(handle as *const Closure) };
+    closure.lock().unwrap().take()
+}
 
-#[export_name = "Java_iors_IoRs_00024FfiClosure_doApply"]
-extern "system" fn apply_ffi_closure(env: JNIEnv, this: JObject, argument: JObject) {
-    let mut guard: MutexGuard<Closure> = env.get_rust_field(this, "nativePointer").unwrap();
-    let closure = guard.take();
-    drop(guard);
-    if let Some(closure) = closure {
-        closure(env, argument);
+/// Gets the handle as an argument, sparing a field lookup, and the closure object only to keep it
+/// from being finalized during the call.
+pub(crate) extern "system" fn apply_ffi_closure(
+    env: JNIEnv,
+    _this: JObject,
+    handle: jlong,
+    argument: JObject,
+) {
+    if let Some(closure) = take(handle) {
+        (closure.apply)(env, argument);
     }
 }
 
-#[export_name = "Java_iors_IoRs_00024FfiClosure_finalize"]
-extern "system" fn drop_ffi_closure(env: JNIEnv, this: JObject) {
-    let c: Closure = env.take_rust_field(this, "nativePointer").unwrap();
-    drop(c);
+pub(crate) extern "system" fn drop_ffi_closure(env: JNIEnv, _class: JClass, handle: jlong) {
+    if handle == 0 {
+        return;
+    }
+    let closure = unsafe { Box::from_raw(handle as *mut Closure) };
+    if let Some(closure) = closure.into_inner().unwrap() {
+        (closure.lost)(env);
+    }
 }
 
-pub fn make_ffi_closure<'a>(
+/// A new `FfiClosure` object, with the handle of its closure.
+pub(crate) struct FfiClosureObject<'a> {
+    pub(crate) obj: JObject<'a>,
+    handle: jlong,
+}
+
+impl FfiClosureObject<'_> {
+    /// Drops the closure without running it, unless it ran already, returns whether it did so. The
+    /// handle stays valid while `obj` is alive, which the local reference to it makes sure of.
+    pub(crate) fn disarm(&self) -> bool {
+        take(self.handle).is_some()
+    }
+}
+
+pub(crate) fn make_ffi_closure<'a>(
     env: &'a JNIEnv<'a>,
+    runtime: &Runtime,
     f: impl FnOnce(JNIEnv, JObject) + Send + Sync + 'static,
-) -> Result<JObject> {
-    let boxed = Some(Box::new(f) as Box<dyn FnOnce(JNIEnv, JObject) + Send + Sync + 'static>);
+    lost: impl FnOnce(JNIEnv) + Send + Sync + 'static,
+) -> Result<FfiClosureObject<'a>> {
+    let closure: Box<Closure> = Box::new(Mutex::new(Some(FfiClosure {
+        apply: Box::new(f),
+        lost: Box::new(lost),
+    })));
+    count(&runtime.metrics.ffi_closures_created);
     // todo: cache the ctor maybe?
-    let obj = env.new_object("iors/IoRs$FfiClosure", "(J)V", &[JValue::Long(0)])?;
-    env.set_rust_field(obj, "nativePointer", boxed)?;
-    Ok(obj)
+    let handle = Box::into_raw(closure);
+    match env.new_object(
+        "iors/IoRs$FfiClosure",
+        "(J)V",
+        &[JValue::Long(handle as jlong)],
+    ) {
+        Ok(obj) => Ok(FfiClosureObject {
+            obj,
+            handle: handle as jlong,
+        }),
+        Err(e) => {
+            drop(unsafe { Box::from_raw(handle) });
+            Err(e.into())
+        }
+    }
 }
@@ -0,0 +1,317 @@
+use crate::{fatal::FatalErrorPolicy, hybrid::Execution, runtime::Runtime, Result};
+use jni::{
+    objects::{GlobalRef, JClass, JObject, JString},
+    sys::{jlong, jobjectArray},
+    JNIEnv,
+};
+use std::{str::FromStr, thread};
+
+const PROPERTIES: &[&str] = &[
+    "iors.computeThreads",
+    "iors.autoYieldThreshold",
+    "iors.localFrameCapacity",
+    "iors.asyncStackTraceDepth",
+    "iors.executionTracing",
+    "iors.executionTraceBufferSize",
+    "iors.executionTraceDumpOnFailure",
+    "iors.fatalErrors",
+    "iors.failOnLostAsyncCallback",
+    "iors.watchdogThresholdMillis",
+    "iors.watchdogIntervalMillis",
+    "iors.compilePrograms",
+    "iors.bindStack",
+    "iors.nodeDispatch",
+    "iors.execution",
+];
+
+/// Read by `IoRs` itself rather than by a runtime, maps get fused as they're built, before any
+/// runtime gets to them. So they're JVM-wide, system properties only, but they're validated along
+/// with the others when the library is loaded.
+const JVM_WIDE_PROPERTIES: &[&str] = &["iors.fusionMaxStackDepth"];
+
+/// Everything tunable about a runtime. The global one is set from the `iors.*` system properties
+/// when the library is loaded, all of them from `IoRsRuntimeConfig`, which uses the same names.
+#[derive(Clone)]
+pub(crate) struct Config {
+    pub(crate) compute_threads: usize,
+    /// after how many nodes a fiber gives up its thread and continues on the compute pool, 0 means
+    /// never
+    pub(crate) auto_yield_threshold: usize,
+    /// capacity of the local reference frame the run loop pushes for every node
+    pub(crate) local_frame_capacity: i32,
+    pub(crate) async_stack_trace_depth: usize,
+    /// every how many fibers get traced, 0 for none
+    pub(crate) execution_tracing: usize,
+    pub(crate) execution_trace_buffer_size: usize,
+    pub(crate) execution_trace_dump_on_failure: bool,
+    pub(crate) fatal_errors: FatalErrorPolicy,
+    pub(crate) fail_on_lost_async_callback: bool,
+    /// 0 turns the watchdog off
+    pub(crate) watchdog_threshold_millis: u64,
+    pub(crate) watchdog_interval_millis: u64,
+    /// whether programs get their known frames flattened by `IoRs.compile` before they start
+    pub(crate) compile_programs: bool,
+    /// whether fibers keep their frames in a Java array instead of a reference each, see
+    /// `BindStack::Shared`
+    pub(crate) shared_bind_stack: bool,
+    /// whether the run loop tells nodes apart by their class instead of their `tag` field
+    pub(crate) dispatch_by_class: bool,
+    pub(crate) execution: Execution,
+}
+
+impl Default for Config {
+    fn default() -> Config {
+        Config {
+            compute_threads: thread::available_parallelism().map_or(1, |n| n.get()),
+            auto_yield_threshold: 0,
+            local_frame_capacity: 16,
+            async_stack_trace_depth: 0,
+            execution_tracing: 0,
+            execution_trace_buffer_size: 64,
+            execution_trace_dump_on_failure: false,
+            fatal_errors: FatalErrorPolicy::NonFatal,
+            fail_on_lost_async_callback: false,
+            watchdog_threshold_millis: 0,
+            watchdog_interval_millis: 1000,
+            compile_programs: false,
+            shared_bind_stack: false,
+            dispatch_by_class: false,
+            execution: Execution::Native,
+        }
+    }
+}
+
+fn parse<T: FromStr>(key: &str, value: &str, expected: &str) -> Result<T, String> {
+    value
+        .trim()
+        .parse()
+        .map_err(|_| format!("{} must be {}, got '{}'", key, expected, value))
+}
+
+fn positive<T: FromStr + Default + PartialOrd>(key: &str, value: &str) -> Result<T, String> {
+    let n = parse(key, value, "a positive integer")?;
+    if n <= T::default() {
+        return Err(format!(
+            "{} must be a positive integer, got '{}'",
+            key, value
+        ));
+    }
+    Ok(n)
+}
+
+impl Config {
+    fn set(
+        &mut self,
+        key: &str,
+        value: &str,
+        classifier: Option<&GlobalRef>,
+    ) -> Result<(), String> {
+        match key {
+            "iors.computeThreads" => self.compute_threads = positive(key, value)?,
+            "iors.autoYieldThreshold" => {
+                self.auto_yield_threshold = parse(key, value, "a non-negative integer")?
+            }
+            "iors.localFrameCapacity" => self.local_frame_capacity = positive(key, value)?,
+            "iors.asyncStackTraceDepth" => {
+                self.async_stack_trace_depth = parse(key, value, "a non-negative integer")?
+            }
+            "iors.executionTracing" => {
+                self.execution_tracing = match value.trim() {
+                    "off" => 0,
+                    "on" => 1,
+                    sampled => match sampled.strip_prefix("sampled:") {
+                        Some(every) => positive(key, every)?,
+                        None => {
+                            return Err(format!(
+                                "{} must be one of off, on or sampled:<n>, got '{}'",
+                                key, value
+                            ))
+                        }
+                    },
+                }
+            }
+            "iors.executionTraceBufferSize" => {
+                self.execution_trace_buffer_size = positive(key, value)?
+            }
+            "iors.executionTraceDumpOnFailure" => {
+                self.execution_trace_dump_on_failure = parse(key, value, "true or false")?
+            }
+            "iors.fatalErrors" => {
+                self.fatal_errors = match value.trim() {
+                    "nonfatal" => FatalErrorPolicy::NonFatal,
+                    "never" => FatalErrorPolicy::Never,
+                    "custom" => FatalErrorPolicy::Custom(classifier.cloned().ok_or_else(|| {
+                        format!(
+                            "{}=custom can only be set from scala, with a classifier",
+                            key
+                        )
+                    })?),
+                    _ => {
+                        return Err(format!(
+                            "{} must be one of nonfatal, never or custom, got '{}'",
+                            key, value
+                        ))
+                    }
+                }
+            }
+            "iors.failOnLostAsyncCallback" => {
+                self.fail_on_lost_async_callback = parse(key, value, "true or false")?
+            }
+            "iors.watchdogThresholdMillis" => {
+                self.watchdog_threshold_millis = parse(key, value, "a non-negative integer")?
+            }
+            "iors.watchdogIntervalMillis" => self.watchdog_interval_millis = positive(key, value)?,
+            "iors.compilePrograms" => self.compile_programs = parse(key, value, "true or false")?,
+            "iors.bindStack" => {
+                self.shared_bind_stack = match value.trim() {
+                    "references" => false,
+                    "shared" => true,
+                    _ => {
+                        return Err(format!(
+                            "{} must be one of references or shared, got '{}'",
+                            key, value
+                        ))
+                    }
+                }
+            }
+            "iors.nodeDispatch" => {
+                self.dispatch_by_class = match value.trim() {
+                    "tagField" => false,
+                    "nodeClass" => true,
+                    _ => {
+                        return Err(format!(
+                            "{} must be one of tagField or nodeClass, got '{}'",
+                            key, value
+                        ))
+                    }
+                }
+            }
+            "iors.execution" => {
+                self.execution = match value.trim() {
+                    "native" => Execution::Native,
+                    "hybrid" => Execution::Hybrid,
+                    "auto" => Execution::Auto,
+                    _ => {
+                        return Err(format!(
+                            "{} must be one of native, hybrid or auto, got '{}'",
+                            key, value
+                        ))
+                    }
+                }
+            }
+            _ if JVM_WIDE_PROPERTIES.contains(&key) => {
+                return Err(format!(
+                    "{} is JVM-wide, it can only be set as a system property",
+                    key
+                ))
+            }
+            _ => return Err(format!("unknown iors runtime setting {}", key)),
+        }
+        Ok(())
+    }
+
+    /// Validates all of the settings first, so that a bad one doesn't leave the runtime half
+    /// reconfigured. Settings that aren't mentioned keep their current values.
+    pub(crate) fn updated(
+        &self,
+        settings: &[(String, String)],
+        classifier: Option<&GlobalRef>,
+    ) -> Result<Config> {
+        let mut updated = self.clone();
+        for (key, value) in settings {
+            updated.set(key, value, classifier)?;
+        }
+        Ok(updated)
+    }
+
+    pub(crate) fn watchdog_changed(&self, previous: &Config) -> bool {
+        self.watchdog_threshold_millis != previous.watchdog_threshold_millis
+            || self.watchdog_interval_millis != previous.watchdog_interval_millis
+    }
+}
+
+/// The `iors.*` system properties that are set, for configuring the global runtime. Fails if one
+/// of the [`JVM_WIDE_PROPERTIES`] is invalid, but leaves them out.
+pub(crate) fn system_properties(env: &JNIEnv) -> Result<Vec<(String, String)>> {
+    let mut settings = vec![];
+    for &key in PROPERTIES {
+        if let Some(value) = system_property(env, key)? {
+            settings.push((key.to_string(), value));
+        }
+    }
+    for &key in JVM_WIDE_PROPERTIES {
+        if let Some(value) = system_property(env, key)? {
+            positive::<i32>(key, &value)?;
+        }
+    }
+    Ok(settings)
+}
+
+fn system_property(env: &JNIEnv, key: &str) -> Result<Option<String>> {
+    let value = env
+        .call_static_method(
+            "java/lang/System",
+            "getProperty",
+            "(Ljava/lang/String;)Ljava/lang/String;",
+            &[JObject::from(env.new_string(key)?).into()],
+        )?
+        .l()?;
+    if value.is_null() {
+        return Ok(None);
+    }
+    Ok(Some(env.get_string(JString::from(value))?.into()))
+}
+
+pub(crate) fn read_settings(
+    env: &JNIEnv,
+    keys: jobjectArray,
+    values: jobjectArray,
+) -> Result<Vec<(String, String)>> {
+    let len = env.get_array_length(keys)?;
+    if len != env.get_array_length(values)? {
+        return Err("every setting needs a value".into());
+    }
+
+    let mut settings = Vec::with_capacity(len as usize);
+    for i in 0..len {
+        let key = env.get_object_array_element(keys, i)?;
+        let value = env.get_object_array_element(values, i)?;
+        settings.push((
+            env.get_string(JString::from(key))?.into(),
+            env.get_string(JString::from(value))?.into(),
+        ));
+        env.delete_local_ref(key)?;
+        env.delete_local_ref(value)?;
+    }
+    Ok(settings)
+}
+
+pub(crate) fn classifier(env: &JNIEnv, classifier: JObject) -> Result<Option<GlobalRef>> {
+    if classifier.is_null() {
+        Ok(None)
+    } else {
+        Ok(Some(env.new_global_ref(classifier)?))
+    }
+}
+
+pub(crate) extern "system" fn configure(
+    env: JNIEnv,
+    _class: JClass,
+    runtime: jlong,
+    keys: jobjectArray,
+    values: jobjectArray,
+    classifier: JObject,
+) {
+    let runtime = Runtime::from_handle(runtime);
+    let res = read_settings(&env, keys, values).and_then(|settings| {
+        runtime.reconfigure(
+            &env,
+            &settings,
+            self::classifier(&env, classifier)?.as_ref(),
+        )
+    });
+
+    if let Err(e) = res {
+        let _ = env.throw_new("java/lang/IllegalArgumentException", e.to_string());
+    }
+}
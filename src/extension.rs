@@ -0,0 +1,278 @@
+use crate::{Globals, Result};
+use jni::{
+    objects::{GlobalRef, JFieldID, JObject, JString},
+    signature::{JavaType, Primitive},
+    sys::{self, jint, jlong, jobject, jobjectArray},
+    JNIEnv,
+};
+use once_cell::sync::Lazy;
+use std::{
+    collections::HashMap,
+    error::Error,
+    ffi::{c_void, CStr},
+    os::raw::c_char,
+    sync::{Arc, RwLock},
+};
+
+/// What the run loop does in place of an extension node.
+pub enum Step<'a> {
+    /// completes the node with a value, like `IoRs.pure`
+    Value(JObject<'a>),
+    /// continues with another program instead
+    Program(JObject<'a>),
+    /// evaluates `source` and continues with the program `f` returns for its result, like `flatMap`
+    Bind { source: JObject<'a>, f: JObject<'a> },
+    /// parks the fiber until the callback handed to `register` gets called, like `IoRs.async`
+    Suspend(JObject<'a>),
+}
+
+/// Evaluates the extension nodes of one kind. An exception left pending by `step` fails the
+/// program like one thrown from a `delay` thunk would.
+pub trait NodeHandler: Send + Sync {
+    /// `fields` is the `Object[]` the node was created with.
+    fn step<'a>(
+        &self,
+        env: &'a JNIEnv<'a>,
+        fields: JObject<'a>,
+    ) -> Result<Step<'a>, Box<dyn Error>>;
+}
+
+struct NodeKind {
+    name: String,
+    fields: Vec<String>,
+    handler: Arc<dyn NodeHandler>,
+}
+
+// the tags are shared by every runtime
+static NODE_KINDS: Lazy<RwLock<HashMap<jint, NodeKind>>> = Lazy::new(Default::default);
+
+/// Adds a kind of node to the run loop. From Scala, `NodeKind`s created with `IoRs.nodeKind(tag)`
+/// make nodes of it.
+///
+/// This registers it with the copy of iors the caller is linked against, which is only the one the
+/// JVM loaded if the caller is part of the same library. Other native libraries register their
+/// kinds through [`iors_register_node_kind`] instead.
+pub fn register_node_kind(
+    tag: jint,
+    name: &str,
+    fields: &[&str],
+    handler: Arc<dyn NodeHandler>,
+) -> Result<(), Box<dyn Error>> {
+    let mut kinds = NODE_KINDS.write().unwrap();
+    if let Some(taken) = kinds.get(&tag) {
+        return Err(format!("node kind tag {} is already taken by {}", tag, taken.name).into());
+    }
+    kinds.insert(
+        tag,
+        NodeKind {
+            name: name.to_string(),
+            fields: fields.iter().map(|f| f.to_string()).collect(),
+            handler,
+        },
+    );
+    Ok(())
+}
+
+/// Forgets every kind, and with them the handlers and what they refer to in the JVM. Called when
+/// the library is unloaded.
+pub(crate) fn clear() {
+    NODE_KINDS.write().unwrap().clear();
+}
+
+/// The handler of the kind of an `Extension` node.
+pub(crate) fn handler(
+    env: &JNIEnv,
+    globals: &Globals,
+    kind: JObject,
+) -> Result<Arc<dyn NodeHandler>> {
+    let tag = env
+        .get_field_unchecked(
+            kind,
+            JFieldID::from(globals.node_kind_tag),
+            JavaType::Primitive(Primitive::Int),
+        )?
+        .i()?;
+    let kinds = NODE_KINDS.read().unwrap();
+    let kind = kinds
+        .get(&tag)
+        .ok_or_else(|| format!("no node kind registered for tag {}", tag))?;
+    Ok(kind.handler.clone())
+}
+
+/// A handler registered from Scala, a function from the fields to the program to continue with.
+/// It keeps the function, and with it its class loader, until the library is unloaded.
+struct ScalaHandler(GlobalRef);
+
+impl NodeHandler for ScalaHandler {
+    fn step<'a>(
+        &self,
+        env: &'a JNIEnv<'a>,
+        fields: JObject<'a>,
+    ) -> Result<Step<'a>, Box<dyn Error>> {
+        let program = env
+            .call_method(
+                JObject::from(self.0.as_obj().into_inner()),
+                "apply",
+                "(Ljava/lang/Object;)Ljava/lang/Object;",
+                &[fields.into()],
+            )?
+            .l()?;
+        Ok(Step::Program(program))
+    }
+}
+
+/// What a handler registered through [`iors_register_node_kind`] fills in, one of the
+/// `IORS_STEP_` kinds of [`Step`] and its local references. Only binds use `second`, for `f`.
+#[repr(C)]
+pub struct IorsStep {
+    pub kind: jint,
+    pub first: jobject,
+    pub second: jobject,
+}
+
+pub const IORS_STEP_VALUE: jint = 0;
+pub const IORS_STEP_PROGRAM: jint = 1;
+pub const IORS_STEP_BIND: jint = 2;
+pub const IORS_STEP_SUSPEND: jint = 3;
+
+/// [`NodeHandler::step`] for native libraries: gets the `data` it was registered with, fills in
+/// `step` and returns 0. Anything else fails the program, with the exception it left pending if
+/// there is one.
+pub type IorsStepFn = unsafe extern "C" fn(
+    env: *mut sys::JNIEnv,
+    fields: jobject,
+    data: *mut c_void,
+    step: *mut IorsStep,
+) -> jint;
+
+struct NativeHandler {
+    step: IorsStepFn,
+    data: *mut c_void,
+}
+
+// whoever registers `data` has to make it usable from any thread
+unsafe impl Send for NativeHandler {}
+unsafe impl Sync for NativeHandler {}
+
+impl NodeHandler for NativeHandler {
+    fn step<'a>(
+        &self,
+        env: &'a JNIEnv<'a>,
+        fields: JObject<'a>,
+    ) -> Result<Step<'a>, Box<dyn Error>> {
+        let mut step = IorsStep {
+            kind: -1,
+            first: std::ptr::null_mut(),
+            second: std::ptr::null_mut(),
+        };
+        let status = unsafe {
+            (self.step)(
+                env.get_native_interface(),
+                fields.into_inner(),
+                self.data,
+                &mut step,
+            )
+        };
+        if status != 0 {
+            return Err(format!("the node handler failed with {}", status).into());
+        }
+        let first = JObject::from(step.first);
+        match step.kind {
+            IORS_STEP_VALUE => Ok(Step::Value(first)),
+            IORS_STEP_PROGRAM => Ok(Step::Program(first)),
+            IORS_STEP_BIND => Ok(Step::Bind {
+                source: first,
+                f: JObject::from(step.second),
+            }),
+            IORS_STEP_SUSPEND => Ok(Step::Suspend(first)),
+            kind => Err(format!("the node handler returned step kind {}", kind).into()),
+        }
+    }
+}
+
+/// [`register_node_kind`] for native libraries that aren't linked into the copy of iors the JVM
+/// loaded, and would only get their own registry otherwise. They find it in the loaded library
+/// with `dlsym`/`GetProcAddress`, or take its address from `IoRs.nodeKindRegistrar`. Returns 0, or
+/// -1 if the tag is taken or a name isn't UTF-8.
+///
+/// Kinds are forgotten when the library is unloaded, and `data` isn't used after that.
+///
+/// # Safety
+///
+/// `name` and the `field_count` strings `fields` points to are nul-terminated, they're copied.
+/// `step` can be called from any thread with `data` until the library is unloaded.
+#[no_mangle]
+pub unsafe extern "C" fn iors_register_node_kind(
+    tag: jint,
+    name: *const c_char,
+    fields: *const *const c_char,
+    field_count: usize,
+    step: IorsStepFn,
+    data: *mut c_void,
+) -> jint {
+    let res = (|| -> Result<()> {
+        let name = CStr::from_ptr(name).to_str()?;
+        let mut field_names = vec![];
+        for i in 0..field_count {
+            field_names.push(CStr::from_ptr(*fields.add(i)).to_str()?);
+        }
+        register_node_kind(
+            tag,
+            name,
+            &field_names,
+            Arc::new(NativeHandler { step, data }),
+        )
+    })();
+    match res {
+        Ok(()) => 0,
+        Err(_) => -1,
+    }
+}
+
+/// The address of [`iors_register_node_kind`] in this copy of the library.
+pub(crate) extern "system" fn node_kind_registrar(_env: JNIEnv, _this: JObject) -> jlong {
+    iors_register_node_kind as *const () as jlong
+}
+
+pub(crate) extern "system" fn register_scala_node_kind(
+    env: JNIEnv,
+    _this: JObject,
+    tag: jint,
+    name: JString,
+    fields: jobjectArray,
+    handler: JObject,
+) {
+    let res = (|| -> Result<()> {
+        let name: String = env.get_string(name)?.into();
+        let mut field_names = vec![];
+        for i in 0..env.get_array_length(fields)? {
+            let field = env.get_object_array_element(fields, i)?;
+            field_names.push(String::from(env.get_string(JString::from(field))?));
+            env.delete_local_ref(field)?;
+        }
+        let field_names: Vec<&str> = field_names.iter().map(String::as_str).collect();
+        let handler = Arc::new(ScalaHandler(env.new_global_ref(handler)?));
+        register_node_kind(tag, &name, &field_names, handler)
+    })();
+
+    if let Err(e) = res {
+        let _ = env.throw_new("java/lang/IllegalArgumentException", e.to_string());
+    }
+}
+
+/// The name and the field names of the kind registered for `tag`, `null` if there's none.
+pub(crate) extern "system" fn node_kind(env: JNIEnv, _this: JObject, tag: jint) -> jobjectArray {
+    let kinds = NODE_KINDS.read().unwrap();
+    match kinds.get(&tag) {
+        Some(kind) => {
+            let described: Vec<String> = std::iter::once(kind.name.clone())
+                .chain(kind.fields.iter().cloned())
+                .collect();
+            crate::trace::string_array(&env, &described).unwrap_or_else(|e| {
+                crate::throw_error(&env, &*e);
+                std::ptr::null_mut()
+            })
+        }
+        None => std::ptr::null_mut(),
+    }
+}
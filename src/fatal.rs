@@ -0,0 +1,64 @@
+use crate::{runtime::Runtime, Result};
+use jni::{
+    objects::{GlobalRef, JClass, JMethodID, JObject, JThrowable},
+    signature::JavaType,
+    JNIEnv,
+};
+
+/// Decides which throwables the run loop is allowed to turn into a `RaiseError`. Fatal ones skip
+/// every `attempt` on the stack and are rethrown to whoever is currently driving the fiber.
+#[derive(Clone)]
+pub(crate) enum FatalErrorPolicy {
+    /// same classification as `scala.util.control.NonFatal`
+    NonFatal,
+    /// everything is recoverable
+    Never,
+    /// a `Throwable => Boolean` supplied from scala
+    Custom(GlobalRef),
+}
+
+/// Classifies `throwable` according to the policy `runtime` is configured with.
+pub(crate) fn is_fatal(env: &JNIEnv, runtime: &Runtime, throwable: JThrowable) -> Result<bool> {
+    let classifier = {
+        let config = runtime
+            .config
+            .read()
+            .map_err(|_| "runtime config lock poisoned")?;
+        match &config.fatal_errors {
+            FatalErrorPolicy::NonFatal => None,
+            FatalErrorPolicy::Never => return Ok(false),
+            FatalErrorPolicy::Custom(classifier) => Some(classifier.clone()),
+        }
+    };
+
+    match classifier {
+        None => {
+            for &class in runtime.globals.fatal_classes.iter() {
+                if env.is_instance_of(throwable, JClass::from(class))? {
+                    return Ok(true);
+                }
+            }
+            Ok(false)
+        }
+        Some(classifier) => {
+            // not going through call_function1, as that would classify the classifier's exceptions
+            let res = env
+                .call_method_unchecked(
+                    classifier.as_obj(),
+                    JMethodID::from(runtime.globals.function1_apply),
+                    JavaType::Object(String::new()),
+                    &[JObject::from(throwable).into()],
+                )
+                .and_then(|res| env.call_method(res.l()?, "booleanValue", "()Z", &[])?.z());
+            match res {
+                Ok(is_fatal) => Ok(is_fatal),
+                Err(_) if env.exception_check()? => {
+                    // a broken classifier shouldn't take the whole fiber down with it
+                    env.exception_clear()?;
+                    Ok(false)
+                }
+                Err(e) => Err(e.into()),
+            }
+        }
+    }
+}
@@ -0,0 +1,386 @@
+use crate::{
+    hybrid::Execution,
+    runtime::Runtime,
+    stack::BindStack,
+    throw_error,
+    trace::{class_name, BindTrace, ExecutionTrace},
+    warnings::warn,
+    Result,
+};
+use jni::{
+    objects::{GlobalRef, JClass},
+    sys::{jlong, jstring},
+    JNIEnv,
+};
+use once_cell::sync::OnceCell;
+use std::{
+    collections::BTreeMap,
+    fmt::Write,
+    sync::{
+        atomic::{AtomicU64, AtomicUsize, Ordering},
+        Arc, Condvar, Mutex,
+    },
+    time::{Duration, Instant},
+};
+
+static NEXT_FIBER_ID: AtomicU64 = AtomicU64::new(1);
+
+/// Every fiber of a runtime that was started and hasn't fired its callback yet.
+#[derive(Default)]
+pub(crate) struct Fibers {
+    live: Mutex<BTreeMap<u64, Arc<Fiber>>>,
+    finished: Condvar,
+}
+
+impl Fibers {
+    pub(crate) fn live(&self) -> Vec<Arc<Fiber>> {
+        self.live.lock().unwrap().values().cloned().collect()
+    }
+
+    /// Waits until every fiber has finished, returns whether they did before `deadline`.
+    pub(crate) fn await_empty(&self, deadline: Instant) -> bool {
+        let mut live = self.live.lock().unwrap();
+        while !live.is_empty() {
+            let now = Instant::now();
+            if now >= deadline {
+                return false;
+            }
+            live = self.finished.wait_timeout(live, deadline - now).unwrap().0;
+        }
+        true
+    }
+
+    /// Describes every live fiber, innermost bind frames first, similar to a JVM thread dump.
+    fn dump(&self, env: &JNIEnv) -> Result<String> {
+        // not holding the registry lock while calling into the JVM
+        let fibers = self.live();
+
+        let mut out = String::new();
+        writeln!(out, "iors fiber dump ({} fibers):", fibers.len())?;
+        for fiber in fibers {
+            writeln!(out)?;
+            fiber.describe(env, &mut out)?;
+        }
+        Ok(out)
+    }
+}
+
+/// Everything a fiber needs to continue evaluation after an async boundary.
+pub(crate) struct Continuation {
+    pub(crate) stack: BindStack,
+    pub(crate) trace: Option<BindTrace>,
+    pub(crate) exec_trace: Option<ExecutionTrace>,
+}
+
+impl Continuation {
+    /// What a fiber starts out with, depending on the configuration of its runtime.
+    pub(crate) fn new(fiber: &Fiber) -> Continuation {
+        let config = fiber.runtime.config.read().unwrap();
+        Continuation {
+            // the JVM loop and compiled programs come with shared frames, see `hybrid::run_segment`
+            // and `BindStack::push_compiled`
+            stack: BindStack::new(
+                config.shared_bind_stack
+                    || config.compile_programs
+                    || config.execution != Execution::Native,
+            ),
+            trace: BindTrace::new(config.async_stack_trace_depth),
+            exec_trace: ExecutionTrace::new(&config, fiber.id),
+        }
+    }
+}
+
+enum State {
+    Running,
+    /// inside a `Delay` thunk, only tracked while the watchdog is running
+    InThunk {
+        thunk: GlobalRef,
+        since: Instant,
+        warned: bool,
+    },
+    /// parked on an `Async` node until `register` calls back
+    Suspended {
+        continuation: Continuation,
+        register: GlobalRef,
+        since: Instant,
+        warned: bool,
+    },
+    /// cancelled by a runtime shutdown while suspended, its continuation is gone
+    Cancelled,
+}
+
+/// Where the outcome of a fiber goes.
+pub(crate) enum Callback {
+    /// the `Either[Throwable, A] => ()` handed to `unsafeRunAsync`
+    Function(GlobalRef),
+    /// the thread that called `unsafeRunSync`. It gets the outcome returned directly while it's still
+    /// running the fiber itself, the `SyncOutcome` is only created once the fiber left it
+    Sync(Arc<OnceCell<SyncOutcome>>),
+}
+
+/// The outcome of a fiber started by `unsafeRunSync` that suspended or yielded, for the thread
+/// waiting on it.
+#[derive(Default)]
+pub(crate) struct SyncOutcome {
+    outcome: Mutex<Option<Result<GlobalRef, GlobalRef>>>,
+    completed: Condvar,
+}
+
+impl SyncOutcome {
+    pub(crate) fn complete(&self, outcome: Result<GlobalRef, GlobalRef>) {
+        *self.outcome.lock().unwrap() = Some(outcome);
+        self.completed.notify_all();
+    }
+
+    /// Parks the calling thread until the fiber completes, with its value or its error.
+    pub(crate) fn wait(&self) -> Result<GlobalRef, GlobalRef> {
+        let mut slot = self.outcome.lock().unwrap();
+        loop {
+            match slot.take() {
+                Some(outcome) => return outcome,
+                None => slot = self.completed.wait(slot).unwrap(),
+            }
+        }
+    }
+}
+
+/// A program started with `unsafeRunAsync` or `unsafeRunSync`, from the moment it's started until
+/// its callback fires.
+pub(crate) struct Fiber {
+    id: u64,
+    pub(crate) runtime: Arc<Runtime>,
+    pub(crate) callback: Callback,
+    state: Mutex<State>,
+    // the bind stack is owned by the run loop while running, so we only keep its depth around
+    depth: AtomicUsize,
+}
+
+impl Fiber {
+    pub(crate) fn start(runtime: Arc<Runtime>, callback: Callback) -> Arc<Fiber> {
+        let fiber = Arc::new(Fiber {
+            id: NEXT_FIBER_ID.fetch_add(1, Ordering::Relaxed),
+            runtime,
+            callback,
+            state: Mutex::new(State::Running),
+            depth: AtomicUsize::new(0),
+        });
+        let fibers = &fiber.runtime.fibers;
+        fibers.live.lock().unwrap().insert(fiber.id, fiber.clone());
+        fiber
+    }
+
+    pub(crate) fn set_depth(&self, depth: usize) {
+        self.depth.store(depth, Ordering::Relaxed);
+    }
+
+    pub(crate) fn suspend(&self, continuation: Continuation, register: GlobalRef) {
+        *self.state.lock().unwrap() = State::Suspended {
+            continuation,
+            register,
+            since: Instant::now(),
+            warned: false,
+        };
+    }
+
+    /// `None` if the fiber got cancelled while it was suspended.
+    pub(crate) fn resume(&self) -> Option<Continuation> {
+        let mut state = self.state.lock().unwrap();
+        match std::mem::replace(&mut *state, State::Running) {
+            State::Suspended { continuation, .. } => Some(continuation),
+            State::Cancelled => {
+                *state = State::Cancelled;
+                None
+            }
+            State::Running | State::InThunk { .. } => {
+                panic!("fiber {} resumed while running", self.id)
+            }
+        }
+    }
+
+    /// Takes the continuation back from a fiber that's still suspended, for when `register` threw
+    /// instead of calling back. `None` if the fiber got cancelled in the meantime.
+    pub(crate) fn abandon(&self) -> Option<Continuation> {
+        let mut state = self.state.lock().unwrap();
+        match std::mem::replace(&mut *state, State::Running) {
+            State::Suspended { continuation, .. } => Some(continuation),
+            other => {
+                *state = other;
+                None
+            }
+        }
+    }
+
+    /// Fails the fiber with a `CancellationException` if it's suspended, returns whether it was.
+    /// Running fibers cancel themselves at their next step once their runtime is shutting down.
+    pub(crate) fn cancel_if_suspended(&self, env: &JNIEnv) -> Result<bool> {
+        {
+            let mut state = self.state.lock().unwrap();
+            if !matches!(*state, State::Suspended { .. }) {
+                return Ok(false);
+            }
+            *state = State::Cancelled;
+        }
+
+        crate::cancel_fiber(env, self)?;
+        Ok(true)
+    }
+
+    pub(crate) fn enter_thunk(&self, thunk: GlobalRef) {
+        *self.state.lock().unwrap() = State::InThunk {
+            thunk,
+            since: Instant::now(),
+            warned: false,
+        };
+    }
+
+    pub(crate) fn exit_thunk(&self) {
+        *self.state.lock().unwrap() = State::Running;
+    }
+
+    /// Describes the fiber if it's been stuck in a thunk or an async boundary for longer than
+    /// `threshold`. Each stuck episode is reported only once.
+    pub(crate) fn check_stuck(&self, env: &JNIEnv, threshold: Duration) -> Result<Option<String>> {
+        let (what, closure, elapsed) = {
+            let mut state = self.state.lock().unwrap();
+            let (what, closure, since, warned) = match &mut *state {
+                State::Running | State::Cancelled => return Ok(None),
+                State::InThunk {
+                    thunk,
+                    since,
+                    warned,
+                } => ("running the delay thunk", thunk, since, warned),
+                State::Suspended {
+                    register,
+                    since,
+                    warned,
+                    ..
+                } => ("suspended on async", register, since, warned),
+            };
+
+            let elapsed = since.elapsed();
+            if *warned || elapsed < threshold {
+                return Ok(None);
+            }
+
+            *warned = true;
+            // not holding the lock while calling into the JVM, the fiber would have to wait for it
+            (what, closure.clone(), elapsed)
+        };
+
+        Ok(Some(format!(
+            "fiber-{} has been {} {} for {:.1?}",
+            self.id,
+            what,
+            class_name(env, closure.as_obj())?,
+            elapsed
+        )))
+    }
+
+    /// Called when the callback handed to the `Async` register function got garbage collected
+    /// without being called. Returns the continuation if the fiber should be failed with an error
+    /// instead of being left hanging forever.
+    pub(crate) fn callback_lost(&self, env: &JNIEnv) -> Result<Option<Continuation>> {
+        let state = std::mem::replace(&mut *self.state.lock().unwrap(), State::Running);
+        let (continuation, register) = match state {
+            State::Suspended {
+                continuation,
+                register,
+                ..
+            } => (continuation, register),
+            state @ State::Cancelled => {
+                *self.state.lock().unwrap() = state;
+                return Ok(None);
+            }
+            State::Running | State::InThunk { .. } => return Ok(None),
+        };
+
+        let fail = self
+            .runtime
+            .config
+            .read()
+            .unwrap()
+            .fail_on_lost_async_callback;
+        let warning = format!(
+            "iors: the async callback of fiber-{} registered by {} was garbage collected without \
+             being called, {}",
+            self.id,
+            class_name(env, register.as_obj())?,
+            if fail {
+                "failing the fiber"
+            } else {
+                "the fiber will never complete"
+            }
+        );
+        warn(env, &self.runtime.globals, &warning);
+
+        if fail {
+            Ok(Some(continuation))
+        } else {
+            let _ = self.finish();
+            Ok(None)
+        }
+    }
+
+    /// Called when the callback is about to fire (or the fiber is given up on), the fiber is
+    /// forgotten afterwards. Returns whether it was still live, only the first call does anything.
+    pub(crate) fn finish(&self) -> bool {
+        let fibers = &self.runtime.fibers;
+        let live = fibers.live.lock().unwrap().remove(&self.id).is_some();
+        fibers.finished.notify_all();
+        live
+    }
+
+    pub(crate) fn describe(&self, env: &JNIEnv, out: &mut String) -> Result<()> {
+        let state = self.state.lock().unwrap();
+        match &*state {
+            State::Cancelled => writeln!(out, "\"fiber-{}\" cancelled", self.id)?,
+            State::Running => {
+                writeln!(
+                    out,
+                    "\"fiber-{}\" running, stack depth {}",
+                    self.id,
+                    self.depth.load(Ordering::Relaxed)
+                )?;
+            }
+            State::InThunk { thunk, since, .. } => {
+                writeln!(
+                    out,
+                    "\"fiber-{}\" running the delay thunk {} for {:.1?}, stack depth {}",
+                    self.id,
+                    class_name(env, thunk.as_obj())?,
+                    since.elapsed(),
+                    self.depth.load(Ordering::Relaxed)
+                )?;
+            }
+            State::Suspended {
+                continuation,
+                register,
+                ..
+            } => {
+                writeln!(
+                    out,
+                    "\"fiber-{}\" suspended on async {}, stack depth {}",
+                    self.id,
+                    class_name(env, register.as_obj())?,
+                    continuation.stack.len()
+                )?;
+                continuation.stack.describe(env, out)?;
+            }
+        }
+        Ok(())
+    }
+}
+
+pub(crate) extern "system" fn fiber_dump(env: JNIEnv, _class: JClass, runtime: jlong) -> jstring {
+    let res = Runtime::from_handle(runtime)
+        .fibers
+        .dump(&env)
+        .and_then(|dump| Ok(env.new_string(dump)?));
+    match res {
+        Ok(dump) => dump.into_inner(),
+        Err(e) => {
+            throw_error(&env, &*e);
+            std::ptr::null_mut()
+        }
+    }
+}
@@ -0,0 +1,154 @@
+use crate::{
+    metrics::count, runtime::Runtime, stack::BindStack, JvmResult, Result, ResultExt, Tag,
+};
+use jni::{
+    objects::{JClass, JFieldID, JObject, JStaticMethodID, JThrowable},
+    signature::{JavaType, Primitive},
+    sys::{jfieldID, jint},
+    JNIEnv,
+};
+
+/// Where the synchronous parts of programs run, see `iors.execution`.
+#[derive(Copy, Clone, Debug, Eq, PartialEq)]
+pub(crate) enum Execution {
+    /// everything in the native run loop
+    Native,
+    /// synchronous segments in `iors.SyncLoop`, the native loop only takes the nodes it doesn't
+    /// run and the errors thrown by user code
+    Hybrid,
+    /// hybrid, unless the fiber is traced or watched, which only the native loop keeps track of
+    Auto,
+}
+
+impl Execution {
+    /// Whether a segment gets handed to the JVM loop, decided every time a fiber (re)starts.
+    pub(crate) fn hybrid(self, traced: bool, watched: bool) -> bool {
+        match self {
+            Execution::Native => false,
+            Execution::Hybrid => true,
+            Execution::Auto => !traced && !watched,
+        }
+    }
+}
+
+/// The fields of `iors.SyncLoop.Segment`.
+pub(crate) struct SegmentFields {
+    pub(crate) outcome: jfieldID,
+    pub(crate) result: jfieldID,
+    pub(crate) frames: jfieldID,
+    pub(crate) steps: jfieldID,
+}
+
+/// How a segment of the JVM loop ended, the `SyncLoop` constants.
+pub(crate) enum Handoff<'a> {
+    /// the program completed with the value, no frames are left
+    Completed(JObject<'a>),
+    /// `raiseError` failed the program and none of the frames handled it, no frames are left
+    Failed(JThrowable<'a>),
+    /// user code threw, the frames are back on the stack and still have to handle it
+    Thrown(JThrowable<'a>),
+    /// the loop stopped at the node, the frames are back on the stack
+    Stopped(JObject<'a>),
+}
+
+/// Whether the JVM loop runs nodes of kind `tag`. Segments start at one of them.
+pub(crate) fn runs(tag: Tag) -> bool {
+    !matches!(tag, Tag::Async | Tag::Extension)
+}
+
+/// Hands `io` and the frames of `stack` over to the JVM loop, which runs them in place for up to
+/// `max_steps` nodes. Returns how the segment ended and how many nodes it went through, with the
+/// frames it didn't run left on `stack`. Everything it returns is a local reference of the frame
+/// of the whole synchronous segment, like the functions on `stack`.
+pub(crate) fn run_segment<'a>(
+    env: &'a JNIEnv<'a>,
+    runtime: &Runtime,
+    io: JObject,
+    stack: &mut BindStack,
+    max_steps: usize,
+) -> Result<JvmResult<'a, (Handoff<'a>, usize)>> {
+    if let BindStack::References(_) = stack {
+        // only if the runtime switched to hybrid execution after the fiber started, the frames are
+        // moved over once and stay shared from then on
+        *stack = drain(env, runtime, stack)?;
+    }
+    let frames = stack.shared_frames();
+    let globals = &runtime.globals;
+    count(&runtime.metrics.jni_upcalls);
+    let res: Result<_> = (|| -> Result<_> {
+        Ok(env
+            .call_static_method_unchecked(
+                JClass::from(globals.sync_loop_class),
+                JStaticMethodID::from(globals.sync_loop_run),
+                JavaType::Object(String::new()),
+                &[
+                    io.into(),
+                    frames.into(),
+                    (max_steps.min(jint::MAX as usize) as jint).into(),
+                ],
+            )?
+            .l()?)
+    })();
+    let segment = match res.check_exception(env, runtime)? {
+        JvmResult::Value(segment) => segment,
+        JvmResult::Exception(exc) => {
+            // the loop itself threw, the frames are wherever it left them, like for an error of
+            // user code
+            let frames = JObject::from(stack.shared_frames().into_inner());
+            stack.sync_shared(env, runtime, frames)?;
+            return Ok(JvmResult::Value((Handoff::Thrown(exc), 0)));
+        }
+        JvmResult::Fatal(exc) => return Ok(JvmResult::Fatal(exc)),
+    };
+
+    let fields = &globals.segment;
+    let int = |field| -> Result<jint> {
+        Ok(env
+            .get_field_unchecked(
+                segment,
+                JFieldID::from(field),
+                JavaType::Primitive(Primitive::Int),
+            )?
+            .i()?)
+    };
+    let object = |field| -> Result<JObject<'a>> {
+        Ok(env
+            .get_field_unchecked(
+                segment,
+                JFieldID::from(field),
+                JavaType::Object(String::new()),
+            )?
+            .l()?)
+    };
+    let outcome = int(fields.outcome)?;
+    let steps = int(fields.steps)? as usize;
+    let result = object(fields.result)?;
+    let frames = object(fields.frames)?;
+    stack.sync_shared(env, runtime, frames)?;
+    if !frames.is_null() {
+        env.delete_local_ref(frames)?;
+    }
+    env.delete_local_ref(segment)?;
+
+    let handoff = match outcome {
+        0 => Handoff::Completed(result),
+        1 => Handoff::Failed(result.into()),
+        2 => Handoff::Thrown(result.into()),
+        3 => Handoff::Stopped(result),
+        outcome => return Err(format!("unknown outcome {} of a segment", outcome).into()),
+    };
+    Ok(JvmResult::Value((handoff, steps)))
+}
+
+/// Moves the frames of `stack` into a shared one, in the same order.
+fn drain(env: &JNIEnv, runtime: &Runtime, stack: &mut BindStack) -> Result<BindStack> {
+    let mut binds = vec![];
+    while let Some(bind) = stack.pop(env)? {
+        binds.push(bind);
+    }
+    let mut drained = BindStack::new(true);
+    for bind in binds.into_iter().rev() {
+        drained.push(env, runtime, bind.try_map(|f| f.into_local(env))?)?;
+    }
+    Ok(drained)
+}
@@ -1,43 +1,93 @@
-use crate::closure::make_ffi_closure;
+use crate::{
+    closure::make_ffi_closure,
+    fatal::is_fatal,
+    fiber::{Callback, Continuation, Fiber, SyncOutcome},
+    hybrid::Handoff,
+    metrics::{count, count_many},
+    runtime::Runtime,
+    specialized::{Specialized, Value},
+    trace::{ActiveTrace, BindTrace},
+    warnings::warn,
+};
 use jni::{
     descriptors::Desc,
-    objects::{GlobalRef, JClass, JFieldID, JMethodID, JObject, JStaticMethodID, JThrowable},
+    objects::{
+        GlobalRef, JClass, JFieldID, JMethodID, JObject, JStaticMethodID, JString, JThrowable,
+    },
     signature::{JavaType, Primitive},
-    sys::{self, jclass, jfieldID, jmethodID},
+    sys::{self, jclass, jdouble, jfieldID, jint, jlong, jmethodID, jobject},
     JNIEnv, JNIVersion, JavaVM,
 };
 use once_cell::sync::OnceCell;
 use std::{
-    cell::RefCell,
     collections::HashMap,
     convert::{TryFrom, TryInto},
     error::Error,
-    ops::DerefMut,
+    sync::Arc,
 };
 
 mod closure;
+mod config;
+mod extension;
+mod fatal;
+mod fiber;
+mod hybrid;
+mod metrics;
+mod natives;
+mod runtime;
+mod scheduler;
+mod specialized;
+mod stack;
+#[cfg(feature = "test-hooks")]
+mod test_hooks;
+mod trace;
+mod warnings;
+mod watchdog;
+
+pub use extension::{
+    iors_register_node_kind, register_node_kind, IorsStep, IorsStepFn, NodeHandler, Step,
+    IORS_STEP_BIND, IORS_STEP_PROGRAM, IORS_STEP_SUSPEND, IORS_STEP_VALUE,
+};
 
 type Result<T, E = Box<dyn Error + 'static>> = std::result::Result<T, E>;
 
 enum JvmResult<'a, T> {
     Value(T),
     Exception(JThrowable<'a>),
+    /// an exception that `attempt` is not allowed to catch, see [`fatal::is_fatal`]
+    Fatal(JThrowable<'a>),
+}
+
+fn caught_exception<'a, T>(env: &'a JNIEnv<'a>, runtime: &Runtime) -> Result<JvmResult<'a, T>> {
+    let exc = env.exception_occurred()?;
+    env.exception_clear()?;
+    if is_fatal(env, runtime, exc)? {
+        Ok(JvmResult::Fatal(exc))
+    } else {
+        Ok(JvmResult::Exception(exc))
+    }
 }
 
 trait ResultExt<T> {
-    fn check_exception<'a>(self, env: &'a JNIEnv<'a>) -> Result<JvmResult<'a, T>>;
+    fn check_exception<'a>(
+        self,
+        env: &'a JNIEnv<'a>,
+        runtime: &Runtime,
+    ) -> Result<JvmResult<'a, T>>;
 }
 
 impl<T> ResultExt<T> for Result<T> {
-    fn check_exception<'a>(self, env: &'a JNIEnv<'a>) -> Result<JvmResult<'a, T>> {
+    fn check_exception<'a>(
+        self,
+        env: &'a JNIEnv<'a>,
+        runtime: &Runtime,
+    ) -> Result<JvmResult<'a, T>> {
         match self {
             Ok(t) => Ok(JvmResult::Value(t)),
             Err(e) => match e.downcast::<jni::errors::Error>() {
                 Ok(jni_err) => {
                     if let jni::errors::ErrorKind::JavaException = jni_err.kind() {
-                        let exc = env.exception_occurred()?;
-                        env.exception_clear()?;
-                        Ok(JvmResult::Exception(exc))
+                        caught_exception(env, runtime)
                     } else {
                         Err(jni_err.into())
                     }
@@ -49,14 +99,16 @@ impl<T> ResultExt<T> for Result<T> {
 }
 
 impl<T> ResultExt<T> for jni::errors::Result<T> {
-    fn check_exception<'a>(self, env: &'a JNIEnv<'a>) -> Result<JvmResult<'a, T>> {
+    fn check_exception<'a>(
+        self,
+        env: &'a JNIEnv<'a>,
+        runtime: &Runtime,
+    ) -> Result<JvmResult<'a, T>> {
         match self {
             Ok(t) => Ok(JvmResult::Value(t)),
             Err(e) => {
                 if let jni::errors::ErrorKind::JavaException = e.kind() {
-                    let exc = env.exception_occurred()?;
-                    env.exception_clear()?;
-                    return Ok(JvmResult::Exception(exc));
+                    return caught_exception(env, runtime);
                 }
 
                 Err(e.into())
@@ -65,251 +117,487 @@ impl<T> ResultExt<T> for jni::errors::Result<T> {
     }
 }
 
-#[repr(i32)]
-#[derive(Debug, Copy, Clone, Eq, PartialEq)]
-pub enum Tag {
-    Pure = 0,
-    Delay = 1,
-    RaiseError = 2,
-    Async = 3,
-    Map = 4,
-    FlatMap = 5,
-    Attempt = 6,
+/// Throws `e` from a native as an `IllegalStateException`, or leaves the Java exception behind it
+/// pending. Natives must not panic, that aborts the JVM.
+pub(crate) fn throw_error(env: &JNIEnv, e: &dyn Error) {
+    if !env.exception_check().unwrap_or(true) {
+        let _ = env.throw_new("java/lang/IllegalStateException", e.to_string());
+    }
 }
 
-impl TryFrom<i32> for Tag {
-    type Error = &'static str;
+/// Bumped whenever anything `Globals` resolves from the jar, or the natives it declares, change.
+/// Must match `iors.NativeProtocol.Version`.
+const PROTOCOL_VERSION: i32 = 14;
 
-    fn try_from(value: i32) -> Result<Self, Self::Error> {
-        if value < 0 || value > 6 {
-            Err("invalid tag value")
-        } else {
-            Ok(unsafe { std::mem::transmute(value) })
+/// The lookups leave a NoClassDefFoundError, NoSuchFieldError or NoSuchMethodError pending, this
+/// replaces it by an error naming what's missing.
+fn missing(env: &JNIEnv, what: &str) -> Box<dyn Error> {
+    let _ = env.exception_clear();
+    format!("missing {}", what).into()
+}
+
+/// The getter of a node field, by its JNI signature. `node_kinds!` passes the signature on as a
+/// `tt`, a `literal` fragment couldn't be matched against the primitive ones.
+macro_rules! node_field {
+    ($field:ident "I") => {
+        node_field!(@primitive $field, jint, Int, i);
+    };
+    ($field:ident "J") => {
+        node_field!(@primitive $field, jlong, Long, j);
+    };
+    ($field:ident "D") => {
+        node_field!(@primitive $field, jdouble, Double, d);
+    };
+    ($field:ident $sig:literal) => {
+        pub(crate) fn $field<'a, 'x>(
+            &self,
+            env: &JNIEnv<'a>,
+            node: impl Into<JObject<'x>>,
+        ) -> Result<JObject<'a>> {
+            Ok(env
+                .get_field_unchecked(
+                    node.into().into_inner(),
+                    JFieldID::from(self.$field),
+                    JavaType::Object(String::new()),
+                )?
+                .l()?)
         }
-    }
+    };
+    (@primitive $field:ident, $ty:ty, $primitive:ident, $unwrap:ident) => {
+        pub(crate) fn $field<'x>(&self, env: &JNIEnv, node: impl Into<JObject<'x>>) -> Result<$ty> {
+            Ok(env
+                .get_field_unchecked(
+                    node.into().into_inner(),
+                    JFieldID::from(self.$field),
+                    JavaType::Primitive(Primitive::$primitive),
+                )?
+                .$unwrap()?)
+        }
+    };
+}
+
+macro_rules! node_kinds {
+    ($($kind:ident $name:ident = $tag:literal : $params:literal => $supertype:literal {
+        $($field:ident : $scala_type:literal = $sig:tt),*
+    })*) => {
+        #[repr(i32)]
+        #[derive(Debug, Copy, Clone, Eq, PartialEq)]
+        pub enum Tag {
+            $($kind = $tag,)*
+        }
+
+        impl TryFrom<i32> for Tag {
+            type Error = &'static str;
+
+            fn try_from(value: i32) -> Result<Self, Self::Error> {
+                match value {
+                    $($tag => Ok(Tag::$kind),)*
+                    _ => Err("invalid tag value"),
+                }
+            }
+        }
+
+        /// The class, constructor and fields of every node kind, with a getter per field.
+        mod node {
+            use super::*;
+
+            $(
+                pub(crate) struct $kind {
+                    pub(crate) jclass: jclass,
+                    // only some of the kinds get constructed from the native side
+                    #[allow(dead_code)]
+                    pub(crate) ctor: jmethodID,
+                    $(pub(super) $field: jfieldID,)*
+                }
+
+                impl $kind {
+                    $(node_field!($field $sig);)*
+                }
+            )*
+        }
+
+        struct Nodes {
+            $($name: node::$kind,)*
+        }
+
+        impl Nodes {
+            fn new(
+                env: &JNIEnv,
+                class_objects: &mut HashMap<&'static str, GlobalRef>,
+            ) -> Result<Nodes> {
+                Ok(Nodes {$(
+                    $name: {
+                        const CLASS: &str = concat!("iors/IoRs$", stringify!($kind));
+                        let class: JClass = CLASS
+                            .lookup(env)
+                            .map_err(|_| missing(env, concat!("class iors/IoRs$", stringify!($kind))))?;
+                        let class = env.new_global_ref(class)?;
+                        let ctor_sig = concat!("(", $($sig,)* ")V");
+                        let kind = node::$kind {
+                            jclass: class.as_obj().into_inner(),
+                            ctor: env
+                                .get_method_id(&class, "<init>", ctor_sig)
+                                .map_err(|_| missing(env, &format!("constructor {}{}", CLASS, ctor_sig)))?
+                                .into_inner(),
+                            $($field: env
+                                .get_field_id(&class, stringify!($field), $sig)
+                                .map_err(|_| missing(env, concat!(
+                                    "field iors/IoRs$", stringify!($kind), ".", stringify!($field), " ", $sig
+                                )))?
+                                .into_inner(),)*
+                        };
+                        class_objects.insert(CLASS, class);
+                        kind
+                    },
+                )*})
+            }
+
+            /// The kind of the nodes of `class`, `None` if it isn't one of the node classes.
+            fn tag_of_class(&self, env: &JNIEnv, class: JClass) -> Result<Option<Tag>> {
+                $(
+                    if env.is_same_object(class, JObject::from(self.$name.jclass))? {
+                        return Ok(Some(Tag::$kind));
+                    }
+                )*
+                Ok(None)
+            }
+        }
+    };
 }
 
-static GLOBALS: OnceCell<Globals> = OnceCell::new();
+include!("nodes.rs");
 
+/// Classes, fields and methods resolved through the class loader that loaded the library, which
+/// every runtime holds on to. Immutable once created, the classes are let go of when the last
+/// runtime using them is gone.
 struct Globals {
-    class_objects: RefCell<HashMap<&'static str, GlobalRef>>,
+    // never read, only keeps the classes below loaded
+    _class_objects: HashMap<&'static str, GlobalRef>,
     // fields and methods from iors
     tag: jfieldID,
     iors_class: jclass,
     iors_from_either: jmethodID,
-    pure_value: jfieldID,
-    pure_ctor: jmethodID,
-    pure_class: jclass,
-    delay_thunk: jfieldID,
-    raise_error_throwable: jfieldID,
-    raise_error_class: jclass,
-    raise_error_ctor: jmethodID,
-    async_f: jfieldID,
-    map_source: jfieldID,
-    map_f: jfieldID,
-    flat_map_source: jfieldID,
-    flat_map_f: jfieldID,
-    attempt_source: jfieldID,
+    iors_add_trace: jmethodID,
+    iors_unfused: jmethodID,
+    iors_warn: jmethodID,
+    nodes: Nodes,
+    node_kind_tag: jfieldID,
+    sync_loop_class: jclass,
+    sync_loop_run: jmethodID,
+    segment: hybrid::SegmentFields,
+    shared_frames_class: jclass,
+    shared_frames: stack::SharedFramesIds,
     // fields and methods from scala std
     function0_apply: jmethodID,
     function1_apply: jmethodID,
+    // by `Specialized` kind, the unboxed applies of Function1 and the `valueOf`s of the boxes
+    function1_apply_specialized: [jmethodID; 3],
+    box_classes: [jclass; 3],
+    box_methods: [jmethodID; 3],
     left_class: jclass,
     left_apply: jmethodID,
     right_class: jclass,
     right_apply: jmethodID,
+    // classes that scala.util.control.NonFatal doesn't match
+    fatal_classes: [jclass; 5],
 }
 
-// the jfield_ids are not thread safe normally... but they are if the backing jclass is a GlobalRef
+// SAFETY: the raw jclasses are the global refs in `_class_objects`, which can be used from any
+// thread. Field and method IDs are valid on every thread for as long as their class isn't unloaded,
+// which those global refs prevent for as long as the IDs exist. Nothing is mutated after `new`.
 unsafe impl Send for Globals {}
 
 unsafe impl Sync for Globals {}
 
-macro_rules! field_id_getters {
-    ($($id:ident),*) => {$(
-        fn $id<'a>() -> JFieldID<'a> {
-            Globals::get().$id.into()
-        }
-    )*};
-}
-
 impl Globals {
-    fn init(env: JNIEnv) -> Result<&'static Globals> {
-        GLOBALS.get_or_try_init(|| {
-            let mut class_objects = HashMap::new();
-
-            macro_rules! cache_class_and_get_id {
-                ($class_name:literal; $($field_or_method:ident $name:literal : $sig:literal),*) => {{
-                    let class: JClass = $class_name.lookup(&env)?;
-                    let class = env.new_global_ref(class)?;
-                    let res = ($(
-                        cache_class_and_get_id!(@do $field_or_method, class, $name, $sig)
-                    ),*);
-
-                    class_objects.insert($class_name, class);
-                    res
-                }};
-                (@do field, $class:ident, $name:literal, $sig:literal) => {
-                    env.get_field_id(&$class, $name, $sig)?.into_inner()
-                };
-                (@do method, $class:ident, $name:literal, $sig:literal) => {
-                    env.get_method_id(&$class, $name, $sig)?.into_inner()
-                };
-                (@do static_method, $class:ident, $name:literal, $sig:literal) => {
-                    env.get_static_method_id(&$class, $name, $sig)?.into_inner()
-                }
+    fn new(env: JNIEnv) -> Result<Globals> {
+        let mut class_objects = HashMap::new();
+
+        macro_rules! cache_class_and_get_id {
+            ($class_name:literal; $($field_or_method:ident $name:literal : $sig:literal),*) => {{
+                let class: JClass = $class_name
+                    .lookup(&env)
+                    .map_err(|_| missing(&env, concat!("class ", $class_name)))?;
+                let class = env.new_global_ref(class)?;
+                let res = ($(
+                    cache_class_and_get_id!(@do $field_or_method, class, $class_name, $name, $sig)
+                ),*);
+
+                class_objects.insert($class_name, class);
+                res
+            }};
+            (@do field, $class:ident, $class_name:literal, $name:literal, $sig:literal) => {
+                env.get_field_id(&$class, $name, $sig)
+                    .map_err(|_| missing(&env, concat!("field ", $class_name, ".", $name, " ", $sig)))?
+                    .into_inner()
+            };
+            (@do method, $class:ident, $class_name:literal, $name:literal, $sig:literal) => {
+                env.get_method_id(&$class, $name, $sig)
+                    .map_err(|_| missing(&env, concat!("method ", $class_name, ".", $name, $sig)))?
+                    .into_inner()
+            };
+            (@do static_method, $class:ident, $class_name:literal, $name:literal, $sig:literal) => {
+                env.get_static_method_id(&$class, $name, $sig)
+                    .map_err(|_| missing(&env, concat!("static method ", $class_name, ".", $name, $sig)))?
+                    .into_inner()
             }
+        }
 
-            let (tag, iors_from_either) = cache_class_and_get_id!("iors/IoRs"; 
-                field "tag": "I",
-                static_method "fromEither": "(Lscala/util/Either;)Liors/IoRs;"
-            );
-            let (pure_value, pure_ctor) = cache_class_and_get_id!("iors/IoRs$Pure"; 
-                field "value": "Ljava/lang/Object;",
-                method "<init>": "(Ljava/lang/Object;)V"
-            );
-            let delay_thunk = cache_class_and_get_id!("iors/IoRs$Delay"; field "thunk": "Lscala/Function0;");
-            let (raise_error_throwable, raise_error_ctor) = cache_class_and_get_id!("iors/IoRs$RaiseError"; 
-                field "throwable": "Ljava/lang/Throwable;",
-                method "<init>": "(Ljava/lang/Throwable;)V"
-            );
-            let async_f = cache_class_and_get_id!("iors/IoRs$Async"; field "f": "Lscala/Function1;");
-            let (map_source, map_f) = cache_class_and_get_id!("iors/IoRs$Map";
-                field "source": "Liors/IoRs;",
-                field "f": "Lscala/Function1;"
-            );
-            let (flat_map_source, flat_map_f) = cache_class_and_get_id!("iors/IoRs$FlatMap";
-                field "source": "Liors/IoRs;",
-                field "f": "Lscala/Function1;"
-            );
-            let attempt_source = cache_class_and_get_id!("iors/IoRs$Attempt"; field "source": "Liors/IoRs;");
+        let (tag, iors_from_either, iors_add_trace, iors_unfused, iors_warn) = cache_class_and_get_id!("iors/IoRs";
+            field "tag": "I",
+            static_method "fromEither": "(Lscala/util/Either;)Liors/IoRs;",
+            static_method "addTrace": "(Ljava/lang/Throwable;[Ljava/lang/String;[Ljava/lang/String;)V",
+            static_method "unfused": "(Ljava/lang/Object;)[Ljava/lang/Object;",
+            static_method "warn": "(Ljava/lang/String;)V"
+        );
+        let nodes = Nodes::new(&env, &mut class_objects)?;
+        let node_kind_tag = cache_class_and_get_id!("iors/NodeKind"; field "tag": "I");
+        let sync_loop_run = cache_class_and_get_id!("iors/SyncLoop";
+            static_method "run": "(Liors/IoRs;Liors/SharedFrames;I)Liors/SyncLoop$Segment;"
+        );
+        let (outcome, result, segment_frames, steps) = cache_class_and_get_id!("iors/SyncLoop$Segment";
+            field "outcome": "I",
+            field "result": "Ljava/lang/Object;",
+            field "frames": "Liors/SharedFrames;",
+            field "steps": "I"
+        );
+        let (shared_frames_new, shared_frames_grow, shared_frames_functions, shared_frames_kinds) = cache_class_and_get_id!("iors/SharedFrames";
+            method "<init>": "(I)V",
+            method "grow": "()V",
+            field "functions": "[Ljava/lang/Object;",
+            field "kinds": "Ljava/nio/ByteBuffer;"
+        );
 
-            let function0_apply = cache_class_and_get_id!("scala/Function0"; 
-                method "apply": "()Ljava/lang/Object;"
-            );
-            let function1_apply = cache_class_and_get_id!("scala/Function1"; 
-                method "apply": "(Ljava/lang/Object;)Ljava/lang/Object;"
-            );
-            let left_apply = cache_class_and_get_id!("scala/util/Left";
-                static_method "apply": "(Ljava/lang/Object;)Lscala/util/Left;"
-            );
-            let right_apply = cache_class_and_get_id!("scala/util/Right";
-                static_method "apply": "(Ljava/lang/Object;)Lscala/util/Right;"
-            );
+        let function0_apply = cache_class_and_get_id!("scala/Function0";
+            method "apply": "()Ljava/lang/Object;"
+        );
+        let (function1_apply, apply_int, apply_long, apply_double) = cache_class_and_get_id!("scala/Function1";
+            method "apply": "(Ljava/lang/Object;)Ljava/lang/Object;",
+            method "apply$mcII$sp": "(I)I",
+            method "apply$mcJJ$sp": "(J)J",
+            method "apply$mcDD$sp": "(D)D"
+        );
+        let box_int = cache_class_and_get_id!("java/lang/Integer";
+            static_method "valueOf": "(I)Ljava/lang/Integer;"
+        );
+        let box_long = cache_class_and_get_id!("java/lang/Long";
+            static_method "valueOf": "(J)Ljava/lang/Long;"
+        );
+        let box_double = cache_class_and_get_id!("java/lang/Double";
+            static_method "valueOf": "(D)Ljava/lang/Double;"
+        );
+        let left_apply = cache_class_and_get_id!("scala/util/Left";
+            static_method "apply": "(Ljava/lang/Object;)Lscala/util/Left;"
+        );
+        let right_apply = cache_class_and_get_id!("scala/util/Right";
+            static_method "apply": "(Ljava/lang/Object;)Lscala/util/Right;"
+        );
 
+        const FATAL_CLASS_NAMES: [&str; 5] = [
+            "java/lang/VirtualMachineError",
+            "java/lang/ThreadDeath",
+            "java/lang/InterruptedException",
+            "java/lang/LinkageError",
+            "scala/util/control/ControlThrowable",
+        ];
+        cache_class_and_get_id!("java/lang/VirtualMachineError";);
+        cache_class_and_get_id!("java/lang/ThreadDeath";);
+        cache_class_and_get_id!("java/lang/InterruptedException";);
+        cache_class_and_get_id!("java/lang/LinkageError";);
+        cache_class_and_get_id!("scala/util/control/ControlThrowable";);
 
-            let pure_class = class_objects.get("iors/IoRs$Pure").ok_or("no class for Pure")?.as_obj().into_inner();
-            let iors_class = class_objects.get("iors/IoRs").ok_or("no class for IoRs")?.as_obj().into_inner();
-            let raise_error_class = class_objects.get("iors/IoRs$RaiseError").ok_or("no class for RaiseError")?.as_obj().into_inner();
-
-            let left_class = class_objects.get("scala/util/Left").ok_or("no class for Left")?.as_obj().into_inner();
-            let right_class = class_objects.get("scala/util/Right").ok_or("no class for Right")?.as_obj().into_inner();
-
-            let class_objects = RefCell::new(class_objects);
-
-            Ok(Globals {
-                class_objects,
-                tag,
-                iors_class,
-                iors_from_either,
-                pure_value,
-                pure_ctor,
-                pure_class,
-                delay_thunk,
-                raise_error_throwable,
-                raise_error_ctor,
-                raise_error_class,
-                async_f,
-                map_source,
-                map_f,
-                flat_map_source,
-                flat_map_f,
-                attempt_source,
-
-                function0_apply,
-                function1_apply,
-                left_class,
-                left_apply,
-                right_class,
-                right_apply,
-            })
-        })
-    }
+        let iors_class = class_objects
+            .get("iors/IoRs")
+            .ok_or("no class for IoRs")?
+            .as_obj()
+            .into_inner();
 
-    fn get() -> &'static Globals {
-        unsafe { GLOBALS.get_unchecked() }
-    }
+        let sync_loop_class = class_objects
+            .get("iors/SyncLoop")
+            .ok_or("no class for SyncLoop")?
+            .as_obj()
+            .into_inner();
 
-    field_id_getters! {
-        tag,
-        pure_value,
-        delay_thunk,
-        raise_error_throwable,
-        async_f,
-        map_source,
-        map_f,
-        flat_map_source,
-        flat_map_f,
-        attempt_source
-    }
-}
+        let shared_frames_class = class_objects
+            .get("iors/SharedFrames")
+            .ok_or("no class for SharedFrames")?
+            .as_obj()
+            .into_inner();
 
-macro_rules! getters {
-    ($($getter_name:ident $(<$l:lifetime>)? -> $res:ty, $field_id:ident, $java_type:expr, $variant:ident;)*) => {$(
-        fn $getter_name<$($l,)? 'x>(env: &$($l)? JNIEnv, io: impl Into<JObject<'x>>) -> Result<$res> {
-            Ok(env
-                .get_field_unchecked(io.into().into_inner(), Globals::$field_id(), $java_type)?
-                .$variant()?
-                .try_into()?)
+        let left_class = class_objects
+            .get("scala/util/Left")
+            .ok_or("no class for Left")?
+            .as_obj()
+            .into_inner();
+        let right_class = class_objects
+            .get("scala/util/Right")
+            .ok_or("no class for Right")?
+            .as_obj()
+            .into_inner();
+
+        let mut box_classes = [std::ptr::null_mut(); 3];
+        for (class, name) in
+            box_classes
+                .iter_mut()
+                .zip(&["java/lang/Integer", "java/lang/Long", "java/lang/Double"])
+        {
+            *class = class_objects
+                .get(name)
+                .ok_or("no class for a box")?
+                .as_obj()
+                .into_inner();
+        }
+
+        let mut fatal_classes = [std::ptr::null_mut(); 5];
+        for (class, name) in fatal_classes.iter_mut().zip(FATAL_CLASS_NAMES.iter()) {
+            *class = class_objects
+                .get(name)
+                .ok_or("no class for a fatal throwable")?
+                .as_obj()
+                .into_inner();
         }
-    )*};
+
+        Ok(Globals {
+            _class_objects: class_objects,
+            tag,
+            iors_class,
+            iors_from_either,
+            iors_add_trace,
+            iors_unfused,
+            iors_warn,
+            nodes,
+            node_kind_tag,
+            sync_loop_class,
+            sync_loop_run,
+            segment: hybrid::SegmentFields {
+                outcome,
+                result,
+                frames: segment_frames,
+                steps,
+            },
+            shared_frames_class,
+            shared_frames: stack::SharedFramesIds {
+                new: shared_frames_new,
+                grow: shared_frames_grow,
+                functions: shared_frames_functions,
+                kinds: shared_frames_kinds,
+            },
+
+            function0_apply,
+            function1_apply,
+            function1_apply_specialized: [apply_int, apply_long, apply_double],
+            box_classes,
+            box_methods: [box_int, box_long, box_double],
+            left_class,
+            left_apply,
+            right_class,
+            right_apply,
+            fatal_classes,
+        })
+    }
 }
 
-getters! {
-    get_tag<'a> -> Tag, tag, JavaType::Primitive(Primitive::Int), i;
-    get_pure_value<'a> -> JObject<'a>, pure_value, JavaType::Object(String::new()), l;
-    get_delay_thunk<'a> -> JObject<'a>, delay_thunk, JavaType::Object(String::new()), l;
-    get_raise_error_throwable<'a> -> JThrowable<'a>, raise_error_throwable, JavaType::Object(String::new()), l;
-    get_async_f<'a> -> JObject<'a>, async_f, JavaType::Object(String::new()), l;
-    get_map_source<'a> -> JObject<'a>, map_source, JavaType::Object(String::new()), l;
-    get_map_f<'a> -> JObject<'a>, map_f, JavaType::Object(String::new()), l;
-    get_flat_map_source<'a> -> JObject<'a>, flat_map_source, JavaType::Object(String::new()), l;
-    get_flat_map_f<'a> -> JObject<'a>, flat_map_f, JavaType::Object(String::new()), l;
-    get_attempt_source<'a> -> JObject<'a>, attempt_source, JavaType::Object(String::new()), l;
+/// The kind of `io`, from its `tag` field or, with `by_class`, from its class, see `iors.nodeDispatch`.
+fn get_tag<'x>(
+    env: &JNIEnv,
+    globals: &Globals,
+    by_class: bool,
+    io: impl Into<JObject<'x>>,
+) -> Result<Tag> {
+    let io = io.into();
+    if by_class {
+        let class = env.get_object_class(io)?;
+        let tag = globals.nodes.tag_of_class(env, class)?;
+        env.delete_local_ref(class.into())?;
+        return Ok(tag.ok_or("not an iors node")?);
+    }
+    Ok(env
+        .get_field_unchecked(
+            io.into_inner(),
+            JFieldID::from(globals.tag),
+            JavaType::Primitive(Primitive::Int),
+        )?
+        .i()?
+        .try_into()?)
 }
 
 fn call_function0<'a, 'f>(
     env: &'a JNIEnv,
+    runtime: &Runtime,
     f: impl Into<JObject<'f>>,
 ) -> Result<JvmResult<'a, JObject<'a>>> {
+    count(&runtime.metrics.jni_upcalls);
     let res = (|| -> Result<_> {
         Ok(env
             .call_method_unchecked(
                 f.into().into_inner(),
-                JMethodID::from(Globals::get().function0_apply),
+                JMethodID::from(runtime.globals.function0_apply),
                 JavaType::Object(String::new()),
                 &[],
             )?
             .l()?)
     })();
-    Ok(res.check_exception(env)?)
+    res.check_exception(env, runtime)
 }
 
 fn call_function1<'a, 'f, 'x>(
     env: &'a JNIEnv,
+    runtime: &Runtime,
     f: impl Into<JObject<'f>>,
     x: impl Into<JObject<'x>>,
 ) -> Result<JvmResult<'a, JObject<'a>>> {
+    count(&runtime.metrics.jni_upcalls);
     let res: Result<_> = (|| -> Result<_> {
         Ok(env
             .call_method_unchecked(
                 f.into().into_inner(),
-                JMethodID::from(Globals::get().function1_apply),
+                JMethodID::from(runtime.globals.function1_apply),
                 JavaType::Object(String::new()),
                 &[x.into().into()],
             )?
             .l()?)
     })();
-    Ok(res.check_exception(env)?)
+    res.check_exception(env, runtime)
+}
+
+/// The protocol version of the jar the library is being loaded from, `None` for jars from before
+/// there was one.
+fn jar_protocol_version(env: &JNIEnv) -> Option<i32> {
+    let version = env
+        .call_static_method("iors/NativeProtocol", "Version", "()I", &[])
+        .and_then(|v| v.i());
+    if version.is_err() {
+        let _ = env.exception_clear();
+    }
+    version.ok()
+}
+
+/// Takes the exception that's pending, if there is one, and describes it with its `toString`.
+fn pending_exception(env: &JNIEnv) -> Option<String> {
+    let throwable = env.exception_occurred().ok()?;
+    if throwable.is_null() {
+        return None;
+    }
+    env.exception_clear().ok()?;
+    let description = env
+        .call_method(throwable, "toString", "()Ljava/lang/String;", &[])
+        .and_then(|description| description.l())
+        .and_then(|description| env.get_string(JString::from(description)));
+    match description {
+        Ok(description) => Some(description.into()),
+        Err(_) => {
+            let _ = env.exception_clear();
+            None
+        }
+    }
+}
+
+/// Fails `JNI_OnLoad`, `loadLibrary` throws an `UnsatisfiedLinkError` with `message` and the
+/// natives stay unlinked.
+fn fail_loading(env: &JNIEnv, message: String) -> sys::jint {
+    let _ = env.exception_clear();
+    let _ = env.throw_new("java/lang/UnsatisfiedLinkError", message);
+    sys::JNI_ERR
 }
 
 #[no_mangle]
@@ -317,35 +605,160 @@ extern "system" fn JNI_OnLoad(jvm: *mut sys::JavaVM, _reserved: *const ()) -> sy
     let jvm = unsafe { JavaVM::from_raw(jvm) }.unwrap();
     let env = jvm.get_env().expect("Could not get the JNI environment");
 
+    match jar_protocol_version(&env) {
+        Some(PROTOCOL_VERSION) => {}
+        Some(jar_version) => {
+            return fail_loading(
+                &env,
+                format!(
+                    "the iors jar (protocol version {}) doesn't match the iors native library \
+                     (protocol version {})",
+                    jar_version, PROTOCOL_VERSION
+                ),
+            )
+        }
+        None => {
+            return fail_loading(
+                &env,
+                format!(
+                    "the iors jar has no NativeProtocol.Version, it's older than the iors native \
+                     library (protocol version {})",
+                    PROTOCOL_VERSION
+                ),
+            )
+        }
+    }
+
     // we must make global refs to all the relevant class objects if we want to cache their
-    // field_ids and method_ids forever
-    Globals::init(env).expect("Could not initialize the globals");
+    // field_ids and method_ids forever. FindClass resolves them through the class loader that is
+    // loading the library right now.
+    let globals = match Globals::new(env.clone()) {
+        Ok(globals) => globals,
+        Err(e) => {
+            let reason = pending_exception(&env).unwrap_or_else(|| e.to_string());
+            return fail_loading(
+                &env,
+                format!(
+                    "couldn't resolve the classes of the iors jar (protocol version {}): {}",
+                    PROTOCOL_VERSION, reason
+                ),
+            );
+        }
+    };
+
+    if let Err(e) = natives::register(&env) {
+        let reason = pending_exception(&env).unwrap_or_else(|| e.to_string());
+        return fail_loading(
+            &env,
+            format!(
+                "couldn't register the natives of the iors jar (protocol version {}): {}",
+                PROTOCOL_VERSION, reason
+            ),
+        );
+    }
+
+    if let Err(e) = runtime::init_global(&env, globals) {
+        let _ = env.throw_new("java/lang/IllegalArgumentException", e.to_string());
+        return sys::JNI_ERR;
+    }
 
     JNIVersion::V8.into()
 }
 
 #[no_mangle]
 extern "system" fn JNI_OnUnload(jvm: *mut sys::JavaVM, _reserved: *const ()) {
-    let _jvm = unsafe { JavaVM::from_raw(jvm) }.unwrap();
-    if let Some(globals) = GLOBALS.get() {
-        std::mem::take(globals.class_objects.borrow_mut().deref_mut());
+    let jvm = unsafe { JavaVM::from_raw(jvm) }.unwrap();
+    if let Ok(env) = jvm.get_env() {
+        runtime::shutdown_all(&env);
     }
 }
 
-#[export_name = "Java_iors_IoRs_00024_printVersion"]
-extern "system" fn print_version(_env: JNIEnv, _this: JObject) {
-    println!("iors ver. {}", env!("CARGO_PKG_VERSION"));
+pub(crate) extern "system" fn print_version(_env: JNIEnv, _this: JObject) {
+    println!(
+        "iors ver. {} (protocol version {})",
+        env!("CARGO_PKG_VERSION"),
+        PROTOCOL_VERSION
+    );
 }
 
-// todo: maybe it's worth to have two versions of this - with GlobalRefs and with AutoRefs
-enum Bind {
-    Map(GlobalRef),
-    FlatMap(GlobalRef),
+enum Bind<F> {
+    Map(F),
+    /// a map whose function takes and returns the primitive unboxed
+    SpecializedMap(Specialized, F),
+    FlatMap(F),
     Attempt,
 }
 
-fn right<'a>(env: &'a JNIEnv, o: JObject) -> Result<JObject<'a>> {
-    let globals = Globals::get();
+impl<F> Bind<F> {
+    /// The frame of a node of kind `tag`, `None` if nodes of that kind don't push one.
+    fn from_tag(tag: Tag, f: F) -> Option<Bind<F>> {
+        match tag {
+            Tag::Map => Some(Bind::Map(f)),
+            Tag::FlatMap => Some(Bind::FlatMap(f)),
+            Tag::Attempt => Some(Bind::Attempt),
+            tag => Specialized::of_map(tag).map(|kind| Bind::SpecializedMap(kind, f)),
+        }
+    }
+
+    /// The kind of the nodes that push this frame.
+    fn tag(&self) -> Tag {
+        match self {
+            Bind::Map(_) => Tag::Map,
+            Bind::SpecializedMap(kind, _) => kind.map_tag(),
+            Bind::FlatMap(_) => Tag::FlatMap,
+            Bind::Attempt => Tag::Attempt,
+        }
+    }
+
+    /// The same frame, with its function converted.
+    fn try_map<G>(self, convert: impl FnOnce(F) -> Result<G>) -> Result<Bind<G>> {
+        Ok(match self {
+            Bind::Map(f) => Bind::Map(convert(f)?),
+            Bind::SpecializedMap(kind, f) => Bind::SpecializedMap(kind, convert(f)?),
+            Bind::FlatMap(f) => Bind::FlatMap(convert(f)?),
+            Bind::Attempt => Bind::Attempt,
+        })
+    }
+
+    fn function(&self) -> Option<&F> {
+        match self {
+            Bind::Map(f) | Bind::SpecializedMap(_, f) | Bind::FlatMap(f) => Some(f),
+            Bind::Attempt => None,
+        }
+    }
+
+    fn function_mut(&mut self) -> Option<&mut F> {
+        match self {
+            Bind::Map(f) | Bind::SpecializedMap(_, f) | Bind::FlatMap(f) => Some(f),
+            Bind::Attempt => None,
+        }
+    }
+
+    fn into_function(self) -> Option<F> {
+        match self {
+            Bind::Map(f) | Bind::SpecializedMap(_, f) | Bind::FlatMap(f) => Some(f),
+            Bind::Attempt => None,
+        }
+    }
+}
+
+/// What the run loop holds between steps. Values and errors only get wrapped in `Pure` and
+/// `RaiseError` nodes when they leave it.
+#[derive(Copy, Clone, Eq, PartialEq)]
+enum Register {
+    Node,
+    Value,
+    Error,
+}
+
+fn new_global_ref(env: &JNIEnv, runtime: &Runtime, o: JObject) -> Result<GlobalRef> {
+    count(&runtime.metrics.global_refs_created);
+    Ok(env.new_global_ref(o)?)
+}
+
+fn right<'a>(env: &'a JNIEnv, runtime: &Runtime, o: JObject) -> Result<JObject<'a>> {
+    count(&runtime.metrics.jni_upcalls);
+    let globals = &runtime.globals;
 
     Ok(env
         .call_static_method_unchecked(
@@ -357,8 +770,9 @@ fn right<'a>(env: &'a JNIEnv, o: JObject) -> Result<JObject<'a>> {
         .l()?)
 }
 
-fn left<'a>(env: &'a JNIEnv, o: JObject) -> Result<JObject<'a>> {
-    let globals = Globals::get();
+fn left<'a>(env: &'a JNIEnv, runtime: &Runtime, o: JObject) -> Result<JObject<'a>> {
+    count(&runtime.metrics.jni_upcalls);
+    let globals = &runtime.globals;
 
     Ok(env
         .call_static_method_unchecked(
@@ -370,28 +784,46 @@ fn left<'a>(env: &'a JNIEnv, o: JObject) -> Result<JObject<'a>> {
         .l()?)
 }
 
-fn pure<'a>(env: &'a JNIEnv, o: JObject) -> Result<JObject<'a>> {
-    let globals = Globals::get();
+fn pure<'a>(env: &'a JNIEnv, runtime: &Runtime, o: JObject) -> Result<JObject<'a>> {
+    let globals = &runtime.globals;
 
     Ok(env.new_object_unchecked(
-        JClass::from(globals.pure_class),
-        globals.pure_ctor.into(),
+        JClass::from(globals.nodes.pure.jclass),
+        globals.nodes.pure.ctor.into(),
         &[o.into()],
     )?)
 }
 
-fn raise_error<'a>(env: &'a JNIEnv, o: JThrowable) -> Result<JObject<'a>> {
-    let globals = Globals::get();
+fn raise_error<'a>(env: &'a JNIEnv, runtime: &Runtime, o: JThrowable) -> Result<JObject<'a>> {
+    let globals = &runtime.globals;
 
     Ok(env.new_object_unchecked(
-        JClass::from(globals.raise_error_class),
-        globals.raise_error_ctor.into(),
+        JClass::from(globals.nodes.raise_error.jclass),
+        globals.nodes.raise_error.ctor.into(),
         &[o.into()],
     )?)
 }
 
-fn iors_from_either<'a, 'b>(env: &'a JNIEnv<'a>, either: JObject<'b>) -> Result<JObject<'a>> {
-    let globals = Globals::get();
+/// Annotates an exception thrown by user code with the trace, before the run loop raises it.
+fn user_error<'a>(
+    env: &JNIEnv,
+    runtime: &Runtime,
+    exc: JThrowable<'a>,
+    trace: &Option<BindTrace>,
+) -> Result<JObject<'a>> {
+    if let Some(trace) = trace {
+        trace.attach(env, &runtime.globals, exc)?;
+    }
+    Ok(exc.into())
+}
+
+fn iors_from_either<'a, 'b>(
+    env: &'a JNIEnv<'a>,
+    runtime: &Runtime,
+    either: JObject<'b>,
+) -> Result<JObject<'a>> {
+    count(&runtime.metrics.jni_upcalls);
+    let globals = &runtime.globals;
 
     Ok(env
         .call_static_method_unchecked(
@@ -403,153 +835,745 @@ fn iors_from_either<'a, 'b>(env: &'a JNIEnv<'a>, either: JObject<'b>) -> Result<
         .l()?)
 }
 
-#[export_name = "Java_iors_IoRs_unsafeRunAsync"]
-extern "system" fn eval_loop(env: JNIEnv, io: JObject, callback: JObject) {
-    let callback = env.new_global_ref(callback).unwrap();
-    eval_loop_with_stack(env, io, callback, Some(vec![]));
+/// Finishes the fiber with its value or error. A callback from `unsafeRunAsync` gets it wrapped in
+/// an `Either`, a thread waiting in `unsafeRunSync` gets it as is. Only the first outcome of a fiber
+/// goes anywhere, like a fatal error thrown by an async register function after it called back.
+fn complete(
+    env: &JNIEnv,
+    fiber: &Fiber,
+    outcome: std::result::Result<JObject, JThrowable>,
+) -> Result<()> {
+    if !fiber.finish() {
+        return Ok(());
+    }
+    let rt = &fiber.runtime;
+    match &fiber.callback {
+        Callback::Function(callback) => {
+            let either = match outcome {
+                Ok(value) => right(env, rt, value)?,
+                Err(exc) => left(env, rt, exc.into())?,
+            };
+            // we ignore the result of that so we don't panic on java exception
+            let _ = call_function1(env, rt, callback.as_obj(), either);
+        }
+        Callback::Sync(waiter) => {
+            waiter
+                .get_or_init(SyncOutcome::default)
+                .complete(match outcome {
+                    Ok(value) => Ok(new_global_ref(env, rt, value)?),
+                    Err(exc) => Err(new_global_ref(env, rt, exc.into())?),
+                })
+        }
+    }
+    Ok(())
+}
+
+/// A new `IllegalStateException`, for the errors the runtime fails fibers with.
+fn illegal_state<'a>(env: &JNIEnv<'a>, message: &str) -> Result<JObject<'a>> {
+    Ok(env.new_object(
+        "java/lang/IllegalStateException",
+        "(Ljava/lang/String;)V",
+        &[JObject::from(env.new_string(message)?).into()],
+    )?)
+}
+
+/// Fails a fiber of a runtime that is shutting down with a `CancellationException`, without running
+/// any of the handlers it has left.
+fn cancel_fiber(env: &JNIEnv, fiber: &Fiber) -> Result<()> {
+    let exc = env.new_object(
+        "java/util/concurrent/CancellationException",
+        "(Ljava/lang/String;)V",
+        &[JObject::from(env.new_string("the iors runtime shut down")?).into()],
+    )?;
+    complete(env, fiber, Err(exc.into()))
 }
 
-fn eval_loop_with_stack(
+/// Called on the finalizer thread once the callback of a suspended fiber got garbage collected
+/// without being called. If the fiber is to fail, it does so on the compute pool, like a fiber that
+/// yielded, not running any user code on the finalizer thread.
+fn callback_lost(env: &JNIEnv, fiber: Arc<Fiber>) -> Result<()> {
+    let continuation = match fiber.callback_lost(env)? {
+        Some(continuation) => continuation,
+        None => return Ok(()),
+    };
+    let compute = match fiber.runtime.compute(env) {
+        Ok(compute) => compute,
+        // the runtime shut down in the meantime, which cancels its fibers wherever it finds them
+        Err(_) => return cancel_fiber(env, &fiber),
+    };
+
+    compute.execute(Box::new(move |env| {
+        let lost = (|| -> Result<_> {
+            let exc = illegal_state(&env, "async callback lost")?;
+            Ok(raise_error(&env, &fiber.runtime, exc.into())?.into_inner())
+        })();
+        match lost {
+            Ok(io) => eval_loop_with_stack(env, JObject::from(io), fiber, continuation),
+            Err(e) => {
+                let warning = format!("iors: failing a fiber that lost its callback failed: {}", e);
+                warn(&env, &fiber.runtime.globals, &warning);
+            }
+        }
+    }));
+    Ok(())
+}
+
+pub(crate) extern "system" fn eval_loop(
     env: JNIEnv,
     io: JObject,
-    callback: GlobalRef,
-    mut stack: Option<Vec<Bind>>,
+    runtime: jlong,
+    callback: JObject,
 ) {
+    let runtime = Runtime::from_handle(runtime);
+    let io = match accept(&env, &runtime, io) {
+        Some(io) => io,
+        None => return,
+    };
+    let callback = new_global_ref(&env, &runtime, callback).unwrap();
+    let fiber = Fiber::start(runtime, Callback::Function(callback));
+    let continuation = Continuation::new(&fiber);
+    eval_loop_with_stack(env, io, fiber, continuation);
+}
+
+/// Runs the program on the calling thread and returns its value, or throws its error, without
+/// going through a callback. The thread only parks if the program goes async, until another
+/// thread completes it.
+pub(crate) extern "system" fn run_sync(env: JNIEnv, io: JObject, runtime: jlong) -> jobject {
+    let runtime = Runtime::from_handle(runtime);
+    let io = match accept(&env, &runtime, io) {
+        Some(io) => io,
+        None => return JObject::null().into_inner(),
+    };
+    let waiter = Arc::new(OnceCell::new());
+    let fiber = Fiber::start(runtime, Callback::Sync(waiter.clone()));
+    let continuation = Continuation::new(&fiber);
+    match eval_framed(&env, io, fiber, continuation, true) {
+        Ended::Returned(Register::Error, exc) => {
+            let _ = env.throw(JThrowable::from(exc));
+            return JObject::null().into_inner();
+        }
+        Ended::Returned(_, value) => return value.into_inner(),
+        Ended::Fatal(exc) => {
+            let _ = env.throw(JThrowable::from(exc.as_obj()));
+            return JObject::null().into_inner();
+        }
+        Ended::Handed => {}
+    }
+    match waiter.get_or_init(SyncOutcome::default).wait() {
+        Ok(value) => env
+            .new_local_ref::<JObject>(value.as_obj())
+            .unwrap()
+            .into_inner(),
+        Err(exc) => {
+            let _ = env.throw(JThrowable::from(exc.as_obj()));
+            JObject::null().into_inner()
+        }
+    }
+}
+
+/// Checks that the runtime can run `io`, throws if it can't.
+fn accept<'a>(env: &JNIEnv, runtime: &Runtime, io: JObject<'a>) -> Option<JObject<'a>> {
+    if !runtime.is_accepting() {
+        let _ = env.throw_new(
+            "java/lang/IllegalStateException",
+            "the iors runtime is shut down",
+        );
+        return None;
+    }
+    // the field IDs of a runtime are only valid for the classes of its own class loader
+    let iors_class = JClass::from(runtime.globals.iors_class);
+    if !env.is_instance_of(io, iors_class).unwrap() {
+        let _ = env.throw_new(
+            "java/lang/IllegalArgumentException",
+            "the iors runtime was created by another class loader than the program",
+        );
+        return None;
+    }
+    Some(io)
+}
+
+fn eval_loop_with_stack(env: JNIEnv, io: JObject, fiber: Arc<Fiber>, continuation: Continuation) {
+    if let Ended::Fatal(exc) = eval_framed(&env, io, fiber, continuation, false) {
+        env.throw(JThrowable::from(exc.as_obj())).unwrap();
+    }
+}
+
+/// How a segment of a fiber's evaluation ended.
+enum Ended<'a> {
+    /// the fiber finished through its callback, suspended or yielded
+    Handed,
+    /// the fiber died of an error that must not be recovered from
+    Fatal(GlobalRef),
+    /// the fiber finished on the thread of its `unsafeRunSync` call without ever leaving it, with
+    /// the value or the error in the register
+    Returned(Register, JObject<'a>),
+}
+
+/// [`eval_segment`] in a local frame of its own, out of which only a returned value or error is
+/// carried.
+fn eval_framed<'a>(
+    env: &JNIEnv<'a>,
+    io: JObject,
+    fiber: Arc<Fiber>,
+    continuation: Continuation,
+    returns: bool,
+) -> Ended<'a> {
+    let local_frame_capacity = fiber.runtime.config.read().unwrap().local_frame_capacity;
+    // the functions of the frames pushed while the fiber runs synchronously are local references of
+    // this frame, see `BindStack::promote`
+    env.push_local_frame(local_frame_capacity).unwrap();
+    let ended = eval_segment(env.clone(), io, fiber, continuation, returns);
+    match ended {
+        Ended::Returned(register, outcome) => {
+            Ended::Returned(register, env.pop_local_frame(outcome).unwrap())
+        }
+        ended => {
+            env.pop_local_frame(JObject::null()).unwrap();
+            ended
+        }
+    }
+}
+
+/// Ends the fiber with `outcome`. If the fiber `returns` it to the caller of `unsafeRunSync` that is
+/// running it, only takes it off the live ones and tells whether the caller still gets it. The
+/// callback gets it otherwise.
+fn end_fiber(
+    env: &JNIEnv,
+    fiber: &Fiber,
+    outcome: std::result::Result<JObject, JThrowable>,
+    returns: bool,
+) -> Result<bool> {
+    if returns {
+        return Ok(fiber.finish());
+    }
+    complete(env, fiber, outcome)?;
+    Ok(false)
+}
+
+/// Runs the fiber until it finishes, suspends or yields. A fiber that `returns` to the caller of
+/// `unsafeRunSync` running this segment ends with its value or error, instead of handing it to the
+/// callback.
+fn eval_segment<'a>(
+    env: JNIEnv<'a>,
+    io: JObject,
+    fiber: Arc<Fiber>,
+    continuation: Continuation,
+    returns: bool,
+) -> Ended<'a> {
+    let Continuation {
+        mut stack,
+        mut trace,
+        exec_trace,
+    } = continuation;
+
+    let rt = &fiber.runtime;
+    let _entered = runtime::enter(rt.clone());
     let mut current = env.auto_local(io);
+    let mut exec_trace = ActiveTrace::activate(exec_trace);
+    // set when user code throws something that must not be recovered from
+    let mut fatal = None;
+    // set when the fiber ended with the value or error it returns
+    let mut returned = None;
+    let (auto_yield_threshold, local_frame_capacity, dispatch_by_class, execution) = {
+        let config = rt.config.read().unwrap();
+        (
+            config.auto_yield_threshold,
+            config.local_frame_capacity,
+            config.dispatch_by_class,
+            config.execution,
+        )
+    };
+    let hybrid = execution.hybrid(exec_trace.is_tracing() || trace.is_some(), rt.is_watched());
+    let mut steps = 0;
+    let mut register = Register::Node;
 
     loop {
-        // the loop ends when next = null
-        let next = env
-            .with_local_frame(16, || {
-                let mut next = JObject::null();
-                let tag = get_tag(&env, &current).unwrap();
-                let mut unwrapped_value = None;
-                match tag {
-                    Tag::Pure => {
-                        unwrapped_value = Some(get_pure_value(&env, &current).unwrap());
-                    }
-                    Tag::Delay => {
-                        let thunk = get_delay_thunk(&env, &current).unwrap();
-                        let thunk_res = call_function0(&env, thunk).unwrap();
-                        match thunk_res {
-                            JvmResult::Value(value) => {
-                                unwrapped_value = Some(value);
-                            }
-                            JvmResult::Exception(exc) => {
-                                next = raise_error(&env, exc).unwrap();
+        if rt.is_cancelling() {
+            cancel_fiber(&env, &fiber).unwrap();
+            break;
+        }
+
+        steps += 1;
+        if auto_yield_threshold != 0 && steps > auto_yield_threshold {
+            // give other fibers a chance, this one continues from the current node on the pool
+            let compute = match rt.compute(&env) {
+                Ok(compute) => compute,
+                // the runtime shut down in the meantime
+                Err(_) => {
+                    cancel_fiber(&env, &fiber).unwrap();
+                    break;
+                }
+            };
+            let node = match register {
+                Register::Node => env.new_local_ref::<JObject>(current.as_obj()).unwrap(),
+                Register::Value => pure(&env, rt, current.as_obj()).unwrap(),
+                Register::Error => raise_error(&env, rt, current.as_obj().into()).unwrap(),
+            };
+            let node = {
+                let global = new_global_ref(&env, rt, node).unwrap();
+                env.delete_local_ref(node).unwrap();
+                global
+            };
+            stack.promote(&env, rt).unwrap();
+            let continuation = Continuation {
+                stack: std::mem::take(&mut stack),
+                trace: trace.take(),
+                exec_trace: exec_trace.suspend(),
+            };
+            let fiber = fiber.clone();
+            compute.execute(Box::new(move |env| {
+                // the node has to outlive its global ref, which the loop doesn't hold on to
+                let io = env
+                    .new_local_ref::<JObject>(JObject::from(node.as_obj().into_inner()))
+                    .unwrap();
+                drop(node);
+                eval_loop_with_stack(env, io, fiber, continuation)
+            }));
+            break;
+        }
+
+        let held = std::mem::replace(&mut register, Register::Node);
+        let tag = match held {
+            Register::Node => {
+                count(&rt.metrics.nodes_evaluated);
+                get_tag(&env, &rt.globals, dispatch_by_class, &current).unwrap()
+            }
+            Register::Value => Tag::Pure,
+            Register::Error => Tag::RaiseError,
+        };
+        exec_trace
+            .record(&env, &rt.globals, tag, current.as_obj())
+            .unwrap();
+
+        if hybrid && held == Register::Node && hybrid::runs(tag) {
+            let max_steps = match auto_yield_threshold {
+                0 => usize::MAX,
+                // this step included
+                threshold => threshold + 1 - steps,
+            };
+            let next = match hybrid::run_segment(&env, rt, current.as_obj(), &mut stack, max_steps)
+                .unwrap()
+            {
+                JvmResult::Value((handoff, segment_steps)) => {
+                    // the first node got counted already
+                    steps += segment_steps.saturating_sub(1);
+                    count_many(&rt.metrics.nodes_evaluated, segment_steps.saturating_sub(1));
+                    match handoff {
+                        Handoff::Completed(value) => {
+                            register = Register::Value;
+                            value
+                        }
+                        Handoff::Failed(exc) => {
+                            register = Register::Error;
+                            exc.into()
+                        }
+                        Handoff::Thrown(exc) => {
+                            if is_fatal(&env, rt, exc).unwrap() {
+                                fatal = Some(new_global_ref(&env, rt, exc.into()).unwrap());
+                                JObject::null()
+                            } else {
+                                register = Register::Error;
+                                user_error(&env, rt, exc, &trace).unwrap()
                             }
                         }
+                        Handoff::Stopped(node) => node,
                     }
-                    Tag::RaiseError => {
-                        let exc = get_raise_error_throwable(&env, &current).unwrap();
-                        let wrapped = left(&env, exc.into()).unwrap();
+                }
+                // the errors of the loop itself that can be recovered from come as `Thrown`
+                JvmResult::Exception(exc) | JvmResult::Fatal(exc) => {
+                    fatal = Some(new_global_ref(&env, rt, exc.into()).unwrap());
+                    JObject::null()
+                }
+            };
+            fiber.set_depth(stack.len());
+            if register == Register::Node && next.is_null() {
+                break;
+            }
+            current = env.auto_local(next);
+            continue;
+        }
 
-                        let mut attempt_bind = stack.as_mut().unwrap().pop();
-                        while let Some(Bind::Map(_)) | Some(Bind::FlatMap(_)) = attempt_bind {
-                            attempt_bind = stack.as_mut().unwrap().pop()
+        // the loop ends when next is a null node, values can be null
+        let next = match tag {
+            // outside of the local frame of a step, so that the functions can stay on the stack as
+            // local references
+            Tag::Map => {
+                let source = rt.globals.nodes.map.source(&env, &current).unwrap();
+                let f = rt.globals.nodes.map.f(&env, &current).unwrap();
+                stack.push(&env, rt, Bind::Map(f)).unwrap();
+                source
+            }
+            Tag::FlatMap => {
+                let source = rt.globals.nodes.flat_map.source(&env, &current).unwrap();
+                let f = rt.globals.nodes.flat_map.f(&env, &current).unwrap();
+                stack.push(&env, rt, Bind::FlatMap(f)).unwrap();
+                source
+            }
+            Tag::MapIntInt | Tag::MapLongLong | Tag::MapDoubleDouble => {
+                let nodes = &rt.globals.nodes;
+                let (source, f) = match tag {
+                    Tag::MapIntInt => (
+                        nodes.map_int_int.source(&env, &current).unwrap(),
+                        nodes.map_int_int.f(&env, &current).unwrap(),
+                    ),
+                    Tag::MapLongLong => (
+                        nodes.map_long_long.source(&env, &current).unwrap(),
+                        nodes.map_long_long.f(&env, &current).unwrap(),
+                    ),
+                    _ => (
+                        nodes.map_double_double.source(&env, &current).unwrap(),
+                        nodes.map_double_double.f(&env, &current).unwrap(),
+                    ),
+                };
+                let kind = Specialized::of_map(tag).unwrap();
+                stack.push(&env, rt, Bind::SpecializedMap(kind, f)).unwrap();
+                source
+            }
+            Tag::Attempt => {
+                let source = rt.globals.nodes.attempt.source(&env, &current).unwrap();
+                stack.push(&env, rt, Bind::Attempt).unwrap();
+                source
+            }
+            Tag::Compiled => {
+                let nodes = &rt.globals.nodes;
+                let frames = nodes.compiled.frames(&env, &current).unwrap();
+                let pushed = stack.push_compiled(&env, rt, frames);
+                if !frames.is_null() {
+                    env.delete_local_ref(frames).unwrap();
+                }
+                match pushed {
+                    Ok(pushed) => {
+                        // every frame counts as a node, like the ones it was compiled from
+                        count_many(&rt.metrics.nodes_evaluated, pushed);
+                        nodes.compiled.leaf(&env, &current).unwrap()
+                    }
+                    // the node was put together wrong, the program fails like it would with an
+                    // error of user code
+                    Err(e) => {
+                        register = Register::Error;
+                        illegal_state(&env, &e.to_string()).unwrap()
+                    }
+                }
+            }
+            _ => env
+                .with_local_frame(local_frame_capacity, || {
+                    let mut next = JObject::null();
+                    let mut unwrapped_value = None;
+                    match tag {
+                        Tag::Pure | Tag::PureInt | Tag::PureLong | Tag::PureDouble => {
+                            unwrapped_value = Some(match held {
+                                // a reference of its own, the values get deleted once they're used
+                                Register::Value => Value::Object(
+                                    env.new_local_ref::<JObject>(current.as_obj()).unwrap(),
+                                ),
+                                _ => Value::of_pure(&env, rt, tag, &current).unwrap(),
+                            });
                         }
-
-                        match attempt_bind {
-                            None => {
-                                // we've reached the top of the callstack, let's fire the callback
-                                // we ignore the result of that so we don't panic on java exception
-                                let _ = call_function1(&env, &callback, wrapped);
-                                next = JObject::null();
-                                return Ok(next);
+                        Tag::Delay => {
+                            let thunk = rt.globals.nodes.delay.thunk(&env, &current).unwrap();
+                            let watched = rt.is_watched();
+                            if watched {
+                                fiber.enter_thunk(new_global_ref(&env, rt, thunk).unwrap());
+                            }
+                            let thunk_res = call_function0(&env, rt, thunk).unwrap();
+                            if watched {
+                                fiber.exit_thunk();
                             }
-                            Some(_) => {
-                                // we've reached an attempt frame, so the next frames expect Left with
-                                // the error
-                                next = pure(&env, wrapped).unwrap();
+                            match thunk_res {
+                                JvmResult::Value(value) => {
+                                    unwrapped_value = Some(Value::Object(value));
+                                }
+                                JvmResult::Exception(exc) => {
+                                    next = user_error(&env, rt, exc, &trace).unwrap();
+                                    register = Register::Error;
+                                }
+                                JvmResult::Fatal(exc) => {
+                                    fatal = Some(new_global_ref(&env, rt, exc.into()).unwrap());
+                                    return Ok(JObject::null());
+                                }
                             }
                         }
-                    }
-                    Tag::Async => {
-                        let f = get_async_f(&env, &current).unwrap();
-                        let stack = stack.take();
-                        let callback = callback.clone();
-                        let async_cb = make_ffi_closure(&env, move |env, async_result| {
-                            // the JObject dance is due to the borrowchk, but I'm pretty sure this is safe
-                            let io = JObject::from(
-                                iors_from_either(&env, async_result).unwrap().into_inner(),
-                            );
-                            eval_loop_with_stack(env, io, callback, stack)
-                        })
-                        .unwrap();
-                        match call_function1(&env, f, async_cb).unwrap() {
-                            JvmResult::Value(_) => {}
-                            JvmResult::Exception(_) => {}
+                        Tag::RaiseError => {
+                            let exc = JThrowable::from(match held {
+                                Register::Error => current.as_obj(),
+                                _ => rt
+                                    .globals
+                                    .nodes
+                                    .raise_error
+                                    .throwable(&env, &current)
+                                    .unwrap(),
+                            });
+                            match stack.unwind_to_attempt(&env).unwrap() {
+                                false => {
+                                    // we've reached the top of the callstack, let's fire the callback
+                                    exec_trace.dump_failure(&env, &rt.globals, exc).unwrap();
+                                    if end_fiber(&env, &fiber, Err(exc), returns).unwrap() {
+                                        returned = Some(Register::Error);
+                                        return Ok(exc.into());
+                                    }
+                                    next = JObject::null();
+                                    return Ok(next);
+                                }
+                                true => {
+                                    // we've reached an attempt frame, so the next frames expect Left with
+                                    // the error
+                                    next = left(&env, rt, exc.into()).unwrap();
+                                    register = Register::Value;
+                                }
+                            }
                         }
-                        next = JObject::null();
-                        return Ok(next);
-                    }
-                    Tag::Map => {
-                        let source = get_map_source(&env, &current).unwrap();
-                        let bind = Bind::Map(
-                            env.new_global_ref(get_map_f(&env, &current).unwrap())
-                                .unwrap(),
-                        );
-                        stack.as_mut().unwrap().push(bind);
-                        next = source;
-                    }
-                    Tag::FlatMap => {
-                        let source = get_flat_map_source(&env, &current).unwrap();
-                        let bind = Bind::FlatMap(
-                            env.new_global_ref(get_flat_map_f(&env, &current).unwrap())
-                                .unwrap(),
-                        );
-                        stack.as_mut().unwrap().push(bind);
-                        next = source;
-                    }
-                    Tag::Attempt => {
-                        let source = get_attempt_source(&env, &current).unwrap();
-                        stack.as_mut().unwrap().push(Bind::Attempt);
-                        next = source;
-                    }
-                }
-
-                if let Some(unwrapped_value) = unwrapped_value {
-                    match stack.as_mut().unwrap().pop() {
-                        None => {
-                            // we ignore the result of that so we don't panic on java exception
-                            let _ = call_function1(
-                                &env,
-                                &callback,
-                                right(&env, unwrapped_value).unwrap(),
+                        Tag::Async => {
+                            count(&rt.metrics.async_boundaries);
+                            let f = rt.globals.nodes.r#async.f(&env, &current).unwrap();
+                            stack.promote(&env, rt).unwrap();
+                            fiber.suspend(
+                                Continuation {
+                                    stack: std::mem::take(&mut stack),
+                                    trace: trace.take(),
+                                    exec_trace: exec_trace.suspend(),
+                                },
+                                new_global_ref(&env, rt, f).unwrap(),
                             );
+                            let resumed_fiber = fiber.clone();
+                            let lost_fiber = fiber.clone();
+                            let async_cb = make_ffi_closure(
+                                &env,
+                                rt,
+                                move |env, async_result| {
+                                    let continuation = match resumed_fiber.resume() {
+                                        Some(continuation) => continuation,
+                                        // the runtime shut down while we were waiting
+                                        None => return,
+                                    };
+                                    // the JObject dance is due to the borrowchk, but I'm pretty sure this is safe
+                                    let io = JObject::from(
+                                        iors_from_either(
+                                            &env,
+                                            &resumed_fiber.runtime,
+                                            async_result,
+                                        )
+                                        .unwrap()
+                                        .into_inner(),
+                                    );
+                                    eval_loop_with_stack(env, io, resumed_fiber, continuation)
+                                },
+                                move |env| {
+                                    if let Err(e) = callback_lost(&env, lost_fiber.clone()) {
+                                        let warning = format!(
+                                            "iors: handling the lost async callback failed: {}",
+                                            e
+                                        );
+                                        warn(&env, &lost_fiber.runtime.globals, &warning);
+                                    }
+                                },
+                            )
+                            .unwrap();
+                            match call_function1(&env, rt, f, async_cb.obj).unwrap() {
+                                JvmResult::Value(_) => {}
+                                // register threw instead of arranging for the callback to be
+                                // called, so the fiber continues here with the error. Unless the
+                                // callback got called before, then the error has nowhere to go
+                                JvmResult::Exception(exc) => {
+                                    if let Some(continuation) =
+                                        async_cb.disarm().then(|| fiber.abandon()).flatten()
+                                    {
+                                        stack = continuation.stack;
+                                        trace = continuation.trace;
+                                        exec_trace = ActiveTrace::activate(continuation.exec_trace);
+                                        next = user_error(&env, rt, exc, &trace).unwrap();
+                                        register = Register::Error;
+                                        return Ok(next);
+                                    }
+                                }
+                                JvmResult::Fatal(exc) => {
+                                    // the continuation isn't needed anymore, the fiber dies
+                                    if async_cb.disarm() {
+                                        drop(fiber.abandon());
+                                    }
+                                    fatal = Some(new_global_ref(&env, rt, exc.into()).unwrap());
+                                }
+                            }
                             next = JObject::null();
                             return Ok(next);
                         }
-                        Some(Bind::Map(f)) => {
-                            let f_res = call_function1(&env, &f, unwrapped_value).unwrap();
-                            next = match f_res {
-                                // f: value -> value, so we need to wrap in a pure
-                                JvmResult::Value(new_value) => pure(&env, new_value).unwrap(),
-                                JvmResult::Exception(exc) => raise_error(&env, exc).unwrap(),
-                            };
-                        }
-                        Some(Bind::FlatMap(f)) => {
-                            let f_res = call_function1(&env, &f, unwrapped_value).unwrap();
-                            next = match f_res {
-                                // f: value -> io, so we just pass it along
-                                JvmResult::Value(new_value) => new_value,
-                                JvmResult::Exception(exc) => raise_error(&env, exc).unwrap(),
+                        Tag::Map
+                        | Tag::MapIntInt
+                        | Tag::MapLongLong
+                        | Tag::MapDoubleDouble
+                        | Tag::FlatMap
+                        | Tag::Attempt
+                        | Tag::Compiled => unreachable!("frames are pushed outside of the step"),
+                        Tag::Extension => {
+                            let nodes = &rt.globals.nodes;
+                            let kind = nodes.extension.kind(&env, &current).unwrap();
+                            let fields = nodes.extension.fields(&env, &current).unwrap();
+                            count(&rt.metrics.jni_upcalls);
+                            let stepped = extension::handler(&env, &rt.globals, kind)
+                                .and_then(|handler| handler.step(&env, fields))
+                                .check_exception(&env, rt);
+                            let stepped = match stepped {
+                                Ok(stepped) => stepped,
+                                // a native handler may fail with an exception left pending
+                                Err(_) if env.exception_check().unwrap() => {
+                                    caught_exception(&env, rt).unwrap()
+                                }
+                                Err(e) => JvmResult::Exception(
+                                    illegal_state(&env, &e.to_string()).unwrap().into(),
+                                ),
                             };
+                            match stepped {
+                                JvmResult::Value(Step::Value(value)) => {
+                                    unwrapped_value = Some(Value::Object(value));
+                                }
+                                JvmResult::Value(Step::Program(program)) => next = program,
+                                JvmResult::Value(Step::Bind { source, f }) => {
+                                    stack.push_global(&env, rt, Bind::FlatMap(f)).unwrap();
+                                    next = source;
+                                }
+                                JvmResult::Value(Step::Suspend(register)) => {
+                                    // evaluated as an async node right away
+                                    next = env
+                                        .new_object_unchecked(
+                                            JClass::from(nodes.r#async.jclass),
+                                            nodes.r#async.ctor.into(),
+                                            &[register.into()],
+                                        )
+                                        .unwrap();
+                                }
+                                JvmResult::Exception(exc) => {
+                                    next = user_error(&env, rt, exc, &trace).unwrap();
+                                    register = Register::Error;
+                                }
+                                JvmResult::Fatal(exc) => {
+                                    fatal = Some(new_global_ref(&env, rt, exc.into()).unwrap());
+                                    return Ok(JObject::null());
+                                }
+                            }
                         }
-                        Some(Bind::Attempt) => {
-                            next = pure(&env, right(&env, unwrapped_value).unwrap()).unwrap();
+                    }
+
+                    if let Some(mut value) = unwrapped_value {
+                        // the values of `map` functions and `attempt` frames go straight to the
+                        // frame below, without a pure node in between
+                        loop {
+                            match stack.pop(&env).unwrap() {
+                                None => {
+                                    let value = value.boxed(&env, rt).unwrap();
+                                    if end_fiber(&env, &fiber, Ok(value), returns).unwrap() {
+                                        returned = Some(Register::Value);
+                                        return Ok(value);
+                                    }
+                                    next = JObject::null();
+                                    return Ok(next);
+                                }
+                                Some(Bind::Map(f)) => {
+                                    if let Some(trace) = trace.as_mut() {
+                                        trace.record(&env, &rt.globals, "map", f.as_obj()).unwrap();
+                                    }
+                                    let arg = value.boxed(&env, rt).unwrap();
+                                    let f_res = call_function1(&env, rt, f.as_obj(), arg).unwrap();
+                                    f.release(&env).unwrap();
+                                    // a chain of maps would fill up the local frame otherwise
+                                    env.delete_local_ref(arg).unwrap();
+                                    next = match f_res {
+                                        JvmResult::Value(new_value) => {
+                                            value = Value::Object(new_value);
+                                            continue;
+                                        }
+                                        JvmResult::Exception(exc) => {
+                                            register = Register::Error;
+                                            user_error(&env, rt, exc, &trace).unwrap()
+                                        }
+                                        JvmResult::Fatal(exc) => {
+                                            fatal =
+                                                Some(new_global_ref(&env, rt, exc.into()).unwrap());
+                                            JObject::null()
+                                        }
+                                    };
+                                }
+                                Some(Bind::SpecializedMap(kind, f)) => {
+                                    if let Some(trace) = trace.as_mut() {
+                                        trace.record(&env, &rt.globals, "map", f.as_obj()).unwrap();
+                                    }
+                                    let f_res = value.apply(&env, rt, kind, f.as_obj()).unwrap();
+                                    f.release(&env).unwrap();
+                                    value.release(&env).unwrap();
+                                    next = match f_res {
+                                        JvmResult::Value(new_value) => {
+                                            value = new_value;
+                                            continue;
+                                        }
+                                        JvmResult::Exception(exc) => {
+                                            register = Register::Error;
+                                            user_error(&env, rt, exc, &trace).unwrap()
+                                        }
+                                        JvmResult::Fatal(exc) => {
+                                            fatal =
+                                                Some(new_global_ref(&env, rt, exc.into()).unwrap());
+                                            JObject::null()
+                                        }
+                                    };
+                                }
+                                Some(Bind::FlatMap(f)) => {
+                                    if let Some(trace) = trace.as_mut() {
+                                        trace
+                                            .record(&env, &rt.globals, "flatMap", f.as_obj())
+                                            .unwrap();
+                                    }
+                                    let arg = value.boxed(&env, rt).unwrap();
+                                    let f_res = call_function1(&env, rt, f.as_obj(), arg).unwrap();
+                                    f.release(&env).unwrap();
+                                    next = match f_res {
+                                        // f: value -> io, so we just pass it along
+                                        JvmResult::Value(new_value) => new_value,
+                                        JvmResult::Exception(exc) => {
+                                            register = Register::Error;
+                                            user_error(&env, rt, exc, &trace).unwrap()
+                                        }
+                                        JvmResult::Fatal(exc) => {
+                                            fatal =
+                                                Some(new_global_ref(&env, rt, exc.into()).unwrap());
+                                            JObject::null()
+                                        }
+                                    };
+                                }
+                                Some(Bind::Attempt) => {
+                                    let boxed = value.boxed(&env, rt).unwrap();
+                                    let wrapped = right(&env, rt, boxed).unwrap();
+                                    env.delete_local_ref(boxed).unwrap();
+                                    value = Value::Object(wrapped);
+                                    continue;
+                                }
+                            }
+                            break;
                         }
                     }
-                }
 
-                Ok(next)
-            })
-            .unwrap();
+                    Ok(next)
+                })
+                .unwrap(),
+        };
+        fiber.set_depth(stack.len());
 
-        if env.is_same_object(JObject::null(), next).unwrap() {
+        if let Some(register) = returned {
+            // still a local reference of the frame this segment runs in, see `eval_framed`
+            return Ended::Returned(register, JObject::from(next.into_inner()));
+        }
+        if register == Register::Node && next.is_null() {
             break;
         } else {
             current = env.auto_local(next);
         }
     } // main loop
+
+    if let Some(exc) = fatal {
+        // the fiber dies here without running any of the handlers left on the stack. The callback
+        // gets the error like any other, after a suspension there may be no java code driving the
+        // fiber to throw it to
+        let exc_obj = JThrowable::from(exc.as_obj());
+        exec_trace.dump_failure(&env, &rt.globals, exc_obj).unwrap();
+        end_fiber(&env, &fiber, Err(exc_obj), returns).unwrap();
+        return Ended::Fatal(exc);
+    }
+    Ended::Handed
 }
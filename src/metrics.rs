@@ -0,0 +1,94 @@
+use crate::{runtime::Runtime, throw_error};
+use jni::{
+    objects::{JClass, JObject},
+    sys::{jint, jlong, jobject},
+    JNIEnv,
+};
+use std::{
+    convert::TryFrom,
+    sync::atomic::{AtomicU64, Ordering},
+};
+
+macro_rules! counters {
+    ($($name:ident),*) => {
+        /// Lock-free counters of what a runtime does, for figuring out where the time goes.
+        pub(crate) struct Metrics {
+            $(pub(crate) $name: AtomicU64,)*
+        }
+
+        impl Metrics {
+            pub(crate) fn new() -> Metrics {
+                Metrics {
+                    $($name: AtomicU64::new(0),)*
+                }
+            }
+
+            fn snapshot(&self) -> [u64; [$(stringify!($name)),*].len()] {
+                [$(self.$name.load(Ordering::Relaxed)),*]
+            }
+
+            /// The counter at `index` in the declaration order, the order of the `RuntimeStats` fields.
+            fn get(&self, index: usize) -> Option<u64> {
+                [$(&self.$name),*].get(index).map(|counter| counter.load(Ordering::Relaxed))
+            }
+        }
+    };
+}
+
+counters! {
+    nodes_evaluated,
+    jni_upcalls,
+    global_refs_created,
+    ffi_closures_created,
+    async_boundaries
+}
+
+pub(crate) fn count(counter: &AtomicU64) {
+    counter.fetch_add(1, Ordering::Relaxed);
+}
+
+pub(crate) fn count_many(counter: &AtomicU64, n: usize) {
+    counter.fetch_add(n as u64, Ordering::Relaxed);
+}
+
+pub(crate) extern "system" fn runtime_stats(
+    env: JNIEnv,
+    _class: JClass,
+    runtime: jlong,
+) -> jobject {
+    let args: Vec<_> = Runtime::from_handle(runtime)
+        .metrics
+        .snapshot()
+        .iter()
+        .map(|&counter| (counter as i64).into())
+        .collect();
+
+    match env.new_object("iors/RuntimeStats", "(JJJJJ)V", &args) {
+        Ok(stats) => stats.into_inner(),
+        Err(e) => {
+            throw_error(&env, &e);
+            std::ptr::null_mut()
+        }
+    }
+}
+
+/// One counter of `runtime`, -1 if there's none at `counter`. Takes and returns nothing but
+/// primitives, so it's registered for the `IoRs` object and `NativeCalls` alike.
+pub(crate) extern "system" fn runtime_stat(
+    _env: JNIEnv,
+    _this: JObject,
+    runtime: jlong,
+    counter: jint,
+) -> jlong {
+    iors_runtime_stat(runtime, counter)
+}
+
+/// [`runtime_stat`] for the Foreign Function & Memory API, which calls it without going through
+/// JNI.
+#[no_mangle]
+pub extern "C" fn iors_runtime_stat(runtime: jlong, counter: jint) -> jlong {
+    usize::try_from(counter)
+        .ok()
+        .and_then(|counter| Runtime::from_handle(runtime).metrics.get(counter))
+        .map_or(-1, |value| value as jlong)
+}
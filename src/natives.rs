@@ -0,0 +1,136 @@
+use crate::{closure, config, extension, fiber, metrics, print_version, runtime, trace, Result};
+use jni::{strings::JNIString, JNIEnv, NativeMethod};
+use std::os::raw::c_void;
+
+/// Registers the native methods of the jar with `RegisterNatives`, instead of leaving the JVM to
+/// look up their `Java_iors_...` symbols. A method that's missing, or whose signature differs,
+/// fails loading the library with a `NoSuchMethodError`.
+///
+/// Whatever takes a runtime or closure handle and no receiver is a static method of
+/// `iors.NativeCalls`. `unsafeRunAsync0` and `unsafeRunSync0` stay instance methods, they run the
+/// program they're called on.
+pub(crate) fn register(env: &JNIEnv) -> Result<()> {
+    env.register_native_methods(
+        "iors/IoRs",
+        &[
+            method(
+                "unsafeRunAsync0",
+                "(JLscala/Function1;)V",
+                crate::eval_loop as *mut c_void,
+            ),
+            method(
+                "unsafeRunSync0",
+                "(J)Ljava/lang/Object;",
+                crate::run_sync as *mut c_void,
+            ),
+        ],
+    )?;
+
+    env.register_native_methods(
+        "iors/IoRs$",
+        &[
+            method("printVersion", "()V", print_version as *mut c_void),
+            method(
+                "trace",
+                "()[Ljava/lang/String;",
+                trace::current_trace as *mut c_void,
+            ),
+            method(
+                "globalRuntime0",
+                "()J",
+                runtime::global_runtime as *mut c_void,
+            ),
+            method(
+                "createRuntime0",
+                "([Ljava/lang/String;[Ljava/lang/String;Lscala/Function1;)J",
+                runtime::create_runtime as *mut c_void,
+            ),
+            method(
+                "registerNodeKind0",
+                "(ILjava/lang/String;[Ljava/lang/String;Lscala/Function1;)V",
+                extension::register_scala_node_kind as *mut c_void,
+            ),
+            method(
+                "nodeKind0",
+                "(I)[Ljava/lang/String;",
+                extension::node_kind as *mut c_void,
+            ),
+            method(
+                "nodeKindRegistrar0",
+                "()J",
+                extension::node_kind_registrar as *mut c_void,
+            ),
+            method(
+                "sleep0",
+                "(JLscala/Function0;)V",
+                runtime::sleep as *mut c_void,
+            ),
+            // the same call as the static one, for comparing the two in the benchmarks
+            method(
+                "runtimeStat0",
+                "(JI)J",
+                metrics::runtime_stat as *mut c_void,
+            ),
+        ],
+    )?;
+
+    env.register_native_methods(
+        "iors/IoRs$FfiClosure",
+        &[method(
+            "apply0",
+            "(JLjava/lang/Object;)V",
+            closure::apply_ffi_closure as *mut c_void,
+        )],
+    )?;
+
+    env.register_native_methods(
+        "iors/NativeCalls",
+        &[
+            method(
+                "configure0",
+                "(J[Ljava/lang/String;[Ljava/lang/String;Lscala/Function1;)V",
+                config::configure as *mut c_void,
+            ),
+            method(
+                "runtimeStats0",
+                "(J)Liors/RuntimeStats;",
+                metrics::runtime_stats as *mut c_void,
+            ),
+            method(
+                "runtimeStat0",
+                "(JI)J",
+                metrics::runtime_stat as *mut c_void,
+            ),
+            method(
+                "fiberDump0",
+                "(J)Ljava/lang/String;",
+                fiber::fiber_dump as *mut c_void,
+            ),
+            method(
+                "shutdown0",
+                "(JJ)[Ljava/lang/String;",
+                runtime::shutdown as *mut c_void,
+            ),
+            method(
+                "releaseRuntime0",
+                "(J)V",
+                runtime::release_runtime as *mut c_void,
+            ),
+            method(
+                "dropClosure0",
+                "(J)V",
+                closure::drop_ffi_closure as *mut c_void,
+            ),
+        ],
+    )?;
+
+    Ok(())
+}
+
+fn method(name: &str, sig: &str, fn_ptr: *mut c_void) -> NativeMethod {
+    NativeMethod {
+        name: JNIString::from(name),
+        sig: JNIString::from(sig),
+        fn_ptr,
+    }
+}
@@ -0,0 +1,62 @@
+// The node kinds of `IoRs`, described once. lib.rs defines `node_kinds!` to generate the `Tag`
+// enum and the field IDs of the nodes from it, build.rs defines its own to generate the `Tag`
+// object and the case classes of IoRs.scala (the `scala_nodes_are_generated` test keeps the file in
+// sync with them). Both `include!` this file, so nothing but the invocation goes in here.
+//
+// <Kind> <rust name> = <tag> : "<scala type parameters>" => "<scala supertype>" {
+//     <field>: "<scala type>" = "<jni signature>",
+// }
+node_kinds! {
+    Pure pure = 0 : "[+A]" => "IoRs[A]" {
+        value: "A" = "Ljava/lang/Object;"
+    }
+    Delay delay = 1 : "[+A]" => "IoRs[A]" {
+        thunk: "() => A" = "Lscala/Function0;"
+    }
+    RaiseError raise_error = 2 : "" => "IoRs[Nothing]" {
+        throwable: "Throwable" = "Ljava/lang/Throwable;"
+    }
+    Async r#async = 3 : "[+A]" => "IoRs[A]" {
+        f: "(Either[Throwable, A] => ()) => ()" = "Lscala/Function1;"
+    }
+    Map map = 4 : "[E, +A]" => "IoRs[A]" {
+        source: "IoRs[E]" = "Liors/IoRs;",
+        f: "E => A" = "Lscala/Function1;"
+    }
+    FlatMap flat_map = 5 : "[E, +A]" => "IoRs[A]" {
+        source: "IoRs[E]" = "Liors/IoRs;",
+        f: "E => IoRs[A]" = "Lscala/Function1;"
+    }
+    Attempt attempt = 6 : "[+A]" => "IoRs[Either[Throwable, A]]" {
+        source: "IoRs[A]" = "Liors/IoRs;"
+    }
+    Extension extension = 7 : "[+A]" => "IoRs[A]" {
+        kind: "NodeKind" = "Liors/NodeKind;",
+        fields: "Array[AnyRef]" = "[Ljava/lang/Object;"
+    }
+    Compiled compiled = 8 : "[+A]" => "IoRs[A]" {
+        frames: "SharedFrames" = "Liors/SharedFrames;",
+        leaf: "IoRs[Any]" = "Liors/IoRs;"
+    }
+    PureInt pure_int = 9 : "" => "IoRs[Int]" {
+        value: "Int" = "I"
+    }
+    PureLong pure_long = 10 : "" => "IoRs[Long]" {
+        value: "Long" = "J"
+    }
+    PureDouble pure_double = 11 : "" => "IoRs[Double]" {
+        value: "Double" = "D"
+    }
+    MapIntInt map_int_int = 12 : "" => "IoRs[Int]" {
+        source: "IoRs[Int]" = "Liors/IoRs;",
+        f: "Int => Int" = "Lscala/Function1;"
+    }
+    MapLongLong map_long_long = 13 : "" => "IoRs[Long]" {
+        source: "IoRs[Long]" = "Liors/IoRs;",
+        f: "Long => Long" = "Lscala/Function1;"
+    }
+    MapDoubleDouble map_double_double = 14 : "" => "IoRs[Double]" {
+        source: "IoRs[Double]" = "Liors/IoRs;",
+        f: "Double => Double" = "Lscala/Function1;"
+    }
+}
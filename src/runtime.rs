@@ -0,0 +1,433 @@
+use crate::{
+    config::{self, Config},
+    fatal::FatalErrorPolicy,
+    fiber::Fibers,
+    metrics::Metrics,
+    scheduler::{Job, Scheduler, Timer},
+    throw_error,
+    trace::string_array,
+    warnings::warn,
+    watchdog::Watchdog,
+    Globals, Result,
+};
+use jni::{
+    objects::{GlobalRef, JClass, JObject},
+    sys::{jlong, jobjectArray},
+    JNIEnv,
+};
+use once_cell::sync::Lazy;
+use std::{
+    cell::RefCell,
+    sync::{
+        atomic::{AtomicBool, AtomicU64, Ordering},
+        Arc, Mutex, RwLock, Weak,
+    },
+    time::{Duration, Instant},
+};
+
+static NEXT_RUNTIME_ID: AtomicU64 = AtomicU64::new(0);
+/// The runtime programs run on when they aren't given one.
+///
+/// The JVM loads a library file into one class loader only, another loader has to load a copy of
+/// it from a path of its own. The copies don't share their statics, so the statics here belong to
+/// the class loader that loaded this copy: one global runtime, and [`shutdown_all`] shuts down the
+/// runtimes of that loader only.
+static GLOBAL_RUNTIME: Lazy<RwLock<Option<Arc<Runtime>>>> = Lazy::new(|| RwLock::new(None));
+/// Every runtime that was created and hasn't been shut down or dropped, so that they can be shut
+/// down when the library is unloaded.
+static RUNTIMES: Lazy<Mutex<Vec<Weak<Runtime>>>> = Lazy::new(|| Mutex::new(vec![]));
+
+thread_local! {
+    /// The runtime of the fiber that is currently being evaluated on this thread.
+    static CURRENT: RefCell<Option<Arc<Runtime>>> = const { RefCell::new(None) };
+}
+
+/// An isolated instance of the runtime, with its own configuration, threads, metrics and fibers.
+/// Scala refers to it by the handle an `IoRsRuntime` wraps, which keeps it alive until that's
+/// garbage collected. The fibers that are still live keep it alive too.
+pub(crate) struct Runtime {
+    id: u64,
+    /// resolved when the runtime was created
+    pub(crate) globals: Arc<Globals>,
+    pub(crate) config: RwLock<Config>,
+    pub(crate) metrics: Metrics,
+    pub(crate) fibers: Fibers,
+    // both started on first use
+    compute: RwLock<Option<Arc<Scheduler>>>,
+    timer: Mutex<Option<Timer>>,
+    watchdog: Mutex<Option<Watchdog>>,
+    watched: AtomicBool,
+    // cleared when shutting down starts
+    accepting: AtomicBool,
+    // set once the fibers that are still alive after the shutdown timeout have to go
+    cancelling: AtomicBool,
+}
+
+impl Runtime {
+    pub(crate) fn new(
+        env: &JNIEnv,
+        globals: Arc<Globals>,
+        settings: &[(String, String)],
+        classifier: Option<&GlobalRef>,
+    ) -> Result<Arc<Runtime>> {
+        let runtime = Arc::new(Runtime {
+            id: NEXT_RUNTIME_ID.fetch_add(1, Ordering::Relaxed),
+            globals,
+            config: RwLock::new(Config::default()),
+            metrics: Metrics::new(),
+            fibers: Fibers::default(),
+            compute: RwLock::new(None),
+            timer: Mutex::new(None),
+            watchdog: Mutex::new(None),
+            watched: AtomicBool::new(false),
+            accepting: AtomicBool::new(true),
+            cancelling: AtomicBool::new(false),
+        });
+        runtime.reconfigure(env, settings, classifier)?;
+        let mut runtimes = RUNTIMES.lock().unwrap();
+        runtimes.retain(|runtime| runtime.strong_count() > 0);
+        runtimes.push(Arc::downgrade(&runtime));
+        Ok(runtime)
+    }
+
+    /// The runtime behind a handle from [`Runtime::into_handle`].
+    pub(crate) fn from_handle(handle: jlong) -> Arc<Runtime> {
+        let runtime = handle as *const Runtime;
+        unsafe {
+            Arc::increment_strong_count(runtime);
+            Arc::from_raw(runtime)
+        }
+    }
+
+    fn into_handle(self: Arc<Runtime>) -> jlong {
+        Arc::into_raw(self) as jlong
+    }
+
+    /// Lets go of the reference a handle holds, once nothing can use the handle anymore.
+    fn release_handle(handle: jlong) {
+        drop(unsafe { Arc::from_raw(handle as *const Runtime) });
+    }
+
+    /// Applies `settings` on top of the current configuration, or none of them if one is invalid.
+    pub(crate) fn reconfigure(
+        self: &Arc<Runtime>,
+        env: &JNIEnv,
+        settings: &[(String, String)],
+        classifier: Option<&GlobalRef>,
+    ) -> Result<()> {
+        let mut config = self.config.write().unwrap();
+        if !self.is_accepting() {
+            return Err(format!("iors runtime {} is shut down", self.id).into());
+        }
+        let updated = config.updated(settings, classifier)?;
+
+        if updated.compute_threads != config.compute_threads {
+            // the next fiber to need it starts a pool of the new size
+            if let Some(compute) = self.compute.write().unwrap().take() {
+                compute.shutdown();
+            }
+        }
+
+        if updated.watchdog_changed(&config) {
+            let mut watchdog = self.watchdog.lock().unwrap();
+            self.watched.store(false, Ordering::Relaxed);
+            if let Some(previous) = watchdog.take() {
+                previous.stop();
+            }
+            if updated.watchdog_threshold_millis != 0 {
+                *watchdog = Some(Watchdog::start(
+                    env,
+                    &format!("iors-{}-watchdog", self.id),
+                    Arc::downgrade(self),
+                    Duration::from_millis(updated.watchdog_threshold_millis),
+                    Duration::from_millis(updated.watchdog_interval_millis),
+                )?);
+                self.watched.store(true, Ordering::Relaxed);
+            }
+        }
+
+        *config = updated;
+        Ok(())
+    }
+
+    /// Whether fibers should tell the watchdog which thunk they're in.
+    pub(crate) fn is_watched(&self) -> bool {
+        self.watched.load(Ordering::Relaxed)
+    }
+
+    /// Whether new fibers can be started, false once the runtime starts shutting down.
+    pub(crate) fn is_accepting(&self) -> bool {
+        self.accepting.load(Ordering::SeqCst)
+    }
+
+    /// Whether fibers should give up at their next step, see [`Runtime::shutdown`].
+    pub(crate) fn is_cancelling(&self) -> bool {
+        self.cancelling.load(Ordering::Relaxed)
+    }
+
+    /// The pool for evaluating fibers, gone for good once the runtime is shut down.
+    pub(crate) fn compute(&self, env: &JNIEnv) -> Result<Arc<Scheduler>> {
+        if let Some(compute) = self.compute.read().unwrap().as_ref() {
+            return Ok(compute.clone());
+        }
+
+        let mut compute = self.compute.write().unwrap();
+        if let Some(compute) = compute.as_ref() {
+            return Ok(compute.clone());
+        }
+        if self.cancelling.load(Ordering::SeqCst) {
+            return Err(format!("iors runtime {} is shut down", self.id).into());
+        }
+
+        let threads = self.config.read().unwrap().compute_threads;
+        let started = Arc::new(Scheduler::start(
+            &env.get_java_vm()?,
+            &format!("iors-{}-compute", self.id),
+            threads,
+        )?);
+        *compute = Some(started.clone());
+        Ok(started)
+    }
+
+    /// Runs `job` on the compute pool once `delay` has passed.
+    pub(crate) fn schedule(
+        self: &Arc<Runtime>,
+        env: &JNIEnv,
+        delay: Duration,
+        job: Job,
+    ) -> Result<()> {
+        let mut timer = self.timer.lock().unwrap();
+        if self.cancelling.load(Ordering::SeqCst) {
+            return Err(format!("iors runtime {} is shut down", self.id).into());
+        }
+        if timer.is_none() {
+            *timer = Some(Timer::start(
+                &env.get_java_vm()?,
+                &format!("iors-{}-timer", self.id),
+            )?);
+        }
+
+        let runtime = self.clone();
+        timer.as_ref().unwrap().schedule(
+            Instant::now() + delay,
+            Box::new(move |env| {
+                // the fiber waiting for the job is cancelled if the runtime is shutting down
+                if let Ok(compute) = runtime.compute(&env) {
+                    compute.execute(job)
+                }
+            }),
+        );
+        Ok(())
+    }
+
+    /// Stops accepting new fibers and waits up to `timeout` for the live ones to finish. The ones
+    /// that don't are cancelled, and their descriptions returned. Then stops the threads of the
+    /// runtime, the compute pool after it finishes the jobs it already has, and releases the
+    /// references it held on to. Shutting down again does nothing.
+    pub(crate) fn shutdown(&self, env: &JNIEnv, timeout: Duration) -> Result<Vec<String>> {
+        let deadline = Instant::now() + timeout;
+        self.accepting.store(false, Ordering::SeqCst);
+
+        let mut cancelled = vec![];
+        if !self.fibers.await_empty(deadline) {
+            for fiber in self.fibers.live() {
+                let mut description = String::new();
+                fiber.describe(env, &mut description)?;
+                cancelled.push(description.trim_end().to_string());
+            }
+        }
+        self.cancelling.store(true, Ordering::SeqCst);
+
+        self.watched.store(false, Ordering::Relaxed);
+        if let Some(watchdog) = self.watchdog.lock().unwrap().take() {
+            watchdog.stop();
+        }
+        if let Some(timer) = self.timer.lock().unwrap().take() {
+            timer.shutdown();
+        }
+        let compute = self.compute.write().unwrap().take();
+        if let Some(compute) = compute {
+            compute.shutdown();
+            // the queued jobs only cancel their fibers by now, but a thunk can keep a thread busy
+            compute.await_termination(deadline);
+        }
+
+        // the running ones take care of themselves
+        for fiber in self.fibers.live() {
+            fiber.cancel_if_suspended(env)?;
+        }
+
+        // the classifier goes, the globals only once the last fiber and the handle are gone, as
+        // fibers can still be running the step they were in
+        let mut config = self.config.write().unwrap();
+        config.fatal_errors = FatalErrorPolicy::NonFatal;
+        drop(config);
+        RUNTIMES
+            .lock()
+            .unwrap()
+            .retain(|runtime| runtime.strong_count() > 0 && !std::ptr::eq(runtime.as_ptr(), self));
+        Ok(cancelled)
+    }
+}
+
+/// Stops the threads of a runtime that was never shut down, without waiting for them.
+impl Drop for Runtime {
+    fn drop(&mut self) {
+        if let Some(watchdog) = self.watchdog.get_mut().ok().and_then(Option::take) {
+            watchdog.stop();
+        }
+        if let Some(timer) = self.timer.get_mut().ok().and_then(Option::take) {
+            timer.shutdown();
+        }
+        if let Some(compute) = self.compute.get_mut().ok().and_then(Option::take) {
+            compute.shutdown();
+        }
+    }
+}
+
+/// Makes `runtime` the current one on this thread until the returned guard is dropped.
+pub(crate) fn enter(runtime: Arc<Runtime>) -> Entered {
+    Entered(CURRENT.with(|current| current.replace(Some(runtime))))
+}
+
+pub(crate) struct Entered(Option<Arc<Runtime>>);
+
+impl Drop for Entered {
+    fn drop(&mut self) {
+        CURRENT.with(|current| current.replace(self.0.take()));
+    }
+}
+
+/// The runtime of the fiber being evaluated on this thread, or outside of fibers the global one.
+pub(crate) fn current() -> Result<Arc<Runtime>> {
+    match CURRENT.with(|current| current.borrow().clone()) {
+        Some(runtime) => Ok(runtime),
+        None => global(),
+    }
+}
+
+/// Called from `JNI_OnUnload`, the threads of the runtimes and the node kinds must not outlive the
+/// library.
+pub(crate) fn shutdown_all(env: &JNIEnv) {
+    let runtimes = std::mem::take(&mut *RUNTIMES.lock().unwrap());
+    for runtime in runtimes.iter().filter_map(Weak::upgrade) {
+        if let Err(e) = runtime.shutdown(env, Duration::ZERO) {
+            let warning = format!("iors: shutting down runtime {} failed: {}", runtime.id, e);
+            warn(env, &runtime.globals, &warning);
+        }
+    }
+    // the runtimes let go of the globals once the last handle to them is gone
+    GLOBAL_RUNTIME.write().unwrap().take();
+    crate::extension::clear();
+}
+
+/// Called from `JNI_OnLoad` with the globals of the class loader loading the library, configured
+/// with the `iors.*` system properties. Replaces the global runtime if there is one, without a
+/// moment where it's missing. The replaced one isn't shut down, programs that got hold of it a
+/// moment ago still run on it, it stops its threads once its `IoRsRuntime` is collected and its
+/// last fiber is done.
+pub(crate) fn init_global(env: &JNIEnv, globals: Globals) -> Result<()> {
+    let settings = config::system_properties(env)?;
+    let runtime = Runtime::new(env, Arc::new(globals), &settings, None)?;
+    let replaced = GLOBAL_RUNTIME.write().unwrap().replace(runtime);
+    // not holding the lock while the replaced runtime's globals get released
+    drop(replaced);
+    Ok(())
+}
+
+pub(crate) fn global() -> Result<Arc<Runtime>> {
+    GLOBAL_RUNTIME
+        .read()
+        .unwrap()
+        .clone()
+        .ok_or_else(|| "the iors library has been unloaded".into())
+}
+
+pub(crate) extern "system" fn global_runtime(env: JNIEnv, _this: JObject) -> jlong {
+    match global() {
+        Ok(runtime) => runtime.into_handle(),
+        Err(e) => {
+            let _ = env.throw_new("java/lang/IllegalStateException", e.to_string());
+            0
+        }
+    }
+}
+
+pub(crate) extern "system" fn create_runtime(
+    env: JNIEnv,
+    _this: JObject,
+    keys: jobjectArray,
+    values: jobjectArray,
+    classifier: JObject,
+) -> jlong {
+    let globals = match global() {
+        Ok(runtime) => runtime.globals.clone(),
+        Err(e) => {
+            let _ = env.throw_new("java/lang/IllegalStateException", e.to_string());
+            return 0;
+        }
+    };
+
+    let res = config::read_settings(&env, keys, values).and_then(|settings| {
+        Runtime::new(
+            &env,
+            globals,
+            &settings,
+            config::classifier(&env, classifier)?.as_ref(),
+        )
+    });
+
+    match res {
+        Ok(runtime) => runtime.into_handle(),
+        Err(e) => {
+            let _ = env.throw_new("java/lang/IllegalArgumentException", e.to_string());
+            0
+        }
+    }
+}
+
+/// Called by the finalizer of an `IoRsRuntime`.
+pub(crate) extern "system" fn release_runtime(_env: JNIEnv, _class: JClass, runtime: jlong) {
+    if runtime != 0 {
+        Runtime::release_handle(runtime);
+    }
+}
+
+pub(crate) extern "system" fn shutdown(
+    env: JNIEnv,
+    _class: JClass,
+    runtime: jlong,
+    timeout_millis: jlong,
+) -> jobjectArray {
+    let timeout = Duration::from_millis(timeout_millis.max(0) as u64);
+    let res = Runtime::from_handle(runtime)
+        .shutdown(&env, timeout)
+        .and_then(|cancelled| string_array(&env, &cancelled));
+    match res {
+        Ok(cancelled) => cancelled,
+        Err(e) => {
+            throw_error(&env, &*e);
+            std::ptr::null_mut()
+        }
+    }
+}
+
+/// Calls `wakeup` on the compute pool of the current runtime after `nanos`.
+pub(crate) extern "system" fn sleep(env: JNIEnv, _this: JObject, nanos: jlong, wakeup: JObject) {
+    let res = current().and_then(|runtime| {
+        let wakeup = env.new_global_ref(wakeup)?;
+        let wakeup_runtime = runtime.clone();
+        runtime.schedule(
+            &env,
+            Duration::from_nanos(nanos.max(0) as u64),
+            Box::new(move |env| {
+                // an exception is the wakeup's business, the fiber it resumes has its own handlers
+                let _ = crate::call_function0(&env, &wakeup_runtime, wakeup.as_obj());
+            }),
+        )
+    });
+
+    if let Err(e) = res {
+        let _ = env.throw_new("java/lang/IllegalStateException", e.to_string());
+    }
+}
@@ -0,0 +1,231 @@
+use crate::Result;
+use jni::{JNIEnv, JavaVM};
+use std::{
+    cmp::{Ordering, Reverse},
+    collections::{BinaryHeap, VecDeque},
+    sync::{Arc, Condvar, Mutex},
+    thread::{self, ThreadId},
+    time::Instant,
+};
+
+pub(crate) type Job = Box<dyn FnOnce(JNIEnv) + Send + 'static>;
+
+/// Runs `job` on a thread of the runtime. A fatal error is still pending once a fiber dies of it,
+/// but there's no java code up the stack to throw it to, its callback got it already.
+fn run(env: &JNIEnv, job: Job) {
+    job(env.clone());
+    if env.exception_check().unwrap_or(false) {
+        let _ = env.exception_clear();
+    }
+}
+
+/// Spawns a native thread that stays attached to the JVM as a daemon for as long as it runs, it's
+/// detached when it exits.
+fn spawn_attached(
+    jvm: &JavaVM,
+    name: String,
+    f: impl FnOnce(JNIEnv) + Send + 'static,
+) -> Result<ThreadId> {
+    let jvm = unsafe { JavaVM::from_raw(jvm.get_java_vm_pointer()) }?;
+    let thread = thread::Builder::new().name(name).spawn(move || {
+        let env = jvm
+            .attach_current_thread_as_daemon()
+            .expect("Could not attach a runtime thread to the JVM");
+        f(env)
+    })?;
+    Ok(thread.thread().id())
+}
+
+struct Queue {
+    // (jobs, shutting down)
+    jobs: Mutex<(VecDeque<Job>, bool)>,
+    available: Condvar,
+    // threads that haven't exited yet
+    live: Mutex<usize>,
+    exited: Condvar,
+}
+
+/// A fixed pool of native threads, attached to the JVM as daemons, that fibers get resumed on.
+pub(crate) struct Scheduler {
+    queue: Arc<Queue>,
+    threads: Vec<ThreadId>,
+}
+
+impl Scheduler {
+    pub(crate) fn start(jvm: &JavaVM, name: &str, threads: usize) -> Result<Scheduler> {
+        let queue = Arc::new(Queue {
+            jobs: Mutex::new((VecDeque::new(), false)),
+            available: Condvar::new(),
+            live: Mutex::new(threads),
+            exited: Condvar::new(),
+        });
+
+        let mut scheduler = Scheduler {
+            queue,
+            threads: Vec::with_capacity(threads),
+        };
+        for i in 0..threads {
+            let queue = scheduler.queue.clone();
+            let started = spawn_attached(jvm, format!("{}-{}", name, i), move |env| {
+                while let Some(job) = queue.next() {
+                    run(&env, job);
+                }
+                *queue.live.lock().unwrap() -= 1;
+                queue.exited.notify_all();
+            });
+            match started {
+                Ok(thread) => scheduler.threads.push(thread),
+                Err(e) => {
+                    scheduler.shutdown();
+                    return Err(e);
+                }
+            }
+        }
+
+        Ok(scheduler)
+    }
+
+    pub(crate) fn execute(&self, job: Job) {
+        self.queue.jobs.lock().unwrap().0.push_back(job);
+        self.queue.available.notify_one();
+    }
+
+    /// The threads exit once they run out of jobs.
+    pub(crate) fn shutdown(&self) {
+        self.queue.jobs.lock().unwrap().1 = true;
+        self.queue.available.notify_all();
+    }
+
+    /// Waits for the threads to exit after a [`Scheduler::shutdown`], returns whether they all did
+    /// before `deadline`. The calling thread doesn't count if it's one of them.
+    pub(crate) fn await_termination(&self, deadline: Instant) -> bool {
+        let own = self.threads.contains(&thread::current().id()) as usize;
+        let mut live = self.queue.live.lock().unwrap();
+        while *live > own {
+            let now = Instant::now();
+            if now >= deadline {
+                return false;
+            }
+            live = self
+                .queue
+                .exited
+                .wait_timeout(live, deadline - now)
+                .unwrap()
+                .0;
+        }
+        true
+    }
+}
+
+impl Queue {
+    fn next(&self) -> Option<Job> {
+        let mut jobs = self.jobs.lock().unwrap();
+        loop {
+            match jobs.0.pop_front() {
+                Some(job) => return Some(job),
+                None if jobs.1 => return None,
+                None => jobs = self.available.wait(jobs).unwrap(),
+            }
+        }
+    }
+}
+
+struct Timeout {
+    deadline: Instant,
+    // keeps timeouts with the same deadline in the order they were scheduled
+    seq: u64,
+    job: Job,
+}
+
+impl PartialEq for Timeout {
+    fn eq(&self, other: &Timeout) -> bool {
+        self.cmp(other) == Ordering::Equal
+    }
+}
+
+impl Eq for Timeout {}
+
+impl PartialOrd for Timeout {
+    fn partial_cmp(&self, other: &Timeout) -> Option<Ordering> {
+        Some(self.cmp(other))
+    }
+}
+
+impl Ord for Timeout {
+    fn cmp(&self, other: &Timeout) -> Ordering {
+        (self.deadline, self.seq).cmp(&(other.deadline, other.seq))
+    }
+}
+
+struct Timeouts {
+    // (earliest deadline first, next seq, shutting down)
+    pending: Mutex<(BinaryHeap<Reverse<Timeout>>, u64, bool)>,
+    changed: Condvar,
+}
+
+/// A single native thread running jobs once their deadline passes. The jobs are supposed to hand
+/// the actual work over to a [`Scheduler`] instead of running it on the timer thread.
+pub(crate) struct Timer {
+    timeouts: Arc<Timeouts>,
+}
+
+impl Timer {
+    pub(crate) fn start(jvm: &JavaVM, name: &str) -> Result<Timer> {
+        let timeouts = Arc::new(Timeouts {
+            pending: Mutex::new((BinaryHeap::new(), 0, false)),
+            changed: Condvar::new(),
+        });
+
+        let thread_timeouts = timeouts.clone();
+        spawn_attached(jvm, name.to_string(), move |env| {
+            while let Some(job) = thread_timeouts.next() {
+                run(&env, job);
+            }
+        })?;
+
+        Ok(Timer { timeouts })
+    }
+
+    pub(crate) fn schedule(&self, deadline: Instant, job: Job) {
+        let mut pending = self.timeouts.pending.lock().unwrap();
+        let seq = pending.1;
+        pending.1 += 1;
+        pending.0.push(Reverse(Timeout { deadline, seq, job }));
+        self.timeouts.changed.notify_one();
+    }
+
+    /// Drops the jobs that aren't due yet, the thread exits right after.
+    pub(crate) fn shutdown(&self) {
+        let dropped = {
+            let mut pending = self.timeouts.pending.lock().unwrap();
+            pending.2 = true;
+            std::mem::take(&mut pending.0)
+        };
+        self.timeouts.changed.notify_one();
+        // not holding the lock while the jobs release whatever they hold on to
+        drop(dropped);
+    }
+}
+
+impl Timeouts {
+    fn next(&self) -> Option<Job> {
+        let mut pending = self.pending.lock().unwrap();
+        loop {
+            if pending.2 {
+                return None;
+            }
+
+            let now = Instant::now();
+            match pending.0.peek() {
+                Some(Reverse(timeout)) if timeout.deadline <= now => {
+                    return pending.0.pop().map(|Reverse(timeout)| timeout.job)
+                }
+                Some(Reverse(timeout)) => {
+                    let wait = timeout.deadline - now;
+                    pending = self.changed.wait_timeout(pending, wait).unwrap().0;
+                }
+                None => pending = self.changed.wait(pending).unwrap(),
+            }
+        }
+    }
+}
@@ -0,0 +1,166 @@
+use crate::{call_function1, metrics::count, runtime::Runtime, JvmResult, Result, ResultExt, Tag};
+use jni::{
+    objects::{JClass, JMethodID, JObject, JStaticMethodID, JValue},
+    signature::{JavaType, Primitive},
+    sys::{jdouble, jint, jlong},
+    JNIEnv,
+};
+
+/// The primitive types of the specialized node kinds, `PureInt`, `MapIntInt` and so on.
+#[derive(Copy, Clone, Debug, Eq, PartialEq)]
+pub(crate) enum Specialized {
+    Int,
+    Long,
+    Double,
+}
+
+impl Specialized {
+    /// The kind of the functions of a specialized map node.
+    pub(crate) fn of_map(tag: Tag) -> Option<Specialized> {
+        match tag {
+            Tag::MapIntInt => Some(Specialized::Int),
+            Tag::MapLongLong => Some(Specialized::Long),
+            Tag::MapDoubleDouble => Some(Specialized::Double),
+            _ => None,
+        }
+    }
+
+    pub(crate) fn map_tag(self) -> Tag {
+        match self {
+            Specialized::Int => Tag::MapIntInt,
+            Specialized::Long => Tag::MapLongLong,
+            Specialized::Double => Tag::MapDoubleDouble,
+        }
+    }
+
+    // the index into the arrays of `Globals`
+    fn index(self) -> usize {
+        self as usize
+    }
+
+    fn primitive(self) -> Primitive {
+        match self {
+            Specialized::Int => Primitive::Int,
+            Specialized::Long => Primitive::Long,
+            Specialized::Double => Primitive::Double,
+        }
+    }
+}
+
+/// A value on its way to the next frame. Primitives stay unboxed for as long as the frames are
+/// specialized for them.
+#[derive(Copy, Clone)]
+pub(crate) enum Value<'a> {
+    Object(JObject<'a>),
+    Int(jint),
+    Long(jlong),
+    Double(jdouble),
+}
+
+impl<'a> Value<'a> {
+    /// The value of a `Pure`, `PureInt`, `PureLong` or `PureDouble` node.
+    pub(crate) fn of_pure<'x>(
+        env: &JNIEnv<'a>,
+        runtime: &Runtime,
+        tag: Tag,
+        node: impl Into<JObject<'x>>,
+    ) -> Result<Value<'a>> {
+        let nodes = &runtime.globals.nodes;
+        Ok(match tag {
+            Tag::PureInt => Value::Int(nodes.pure_int.value(env, node)?),
+            Tag::PureLong => Value::Long(nodes.pure_long.value(env, node)?),
+            Tag::PureDouble => Value::Double(nodes.pure_double.value(env, node)?),
+            _ => Value::Object(nodes.pure.value(env, node)?),
+        })
+    }
+
+    fn specialized(self) -> Option<Specialized> {
+        match self {
+            Value::Object(_) => None,
+            Value::Int(_) => Some(Specialized::Int),
+            Value::Long(_) => Some(Specialized::Long),
+            Value::Double(_) => Some(Specialized::Double),
+        }
+    }
+
+    fn jvalue(self) -> JValue<'a> {
+        match self {
+            Value::Object(o) => JValue::Object(o),
+            Value::Int(i) => JValue::Int(i),
+            Value::Long(j) => JValue::Long(j),
+            Value::Double(d) => JValue::Double(d),
+        }
+    }
+
+    /// The value as an object, primitives get boxed into a new local reference.
+    pub(crate) fn boxed(self, env: &JNIEnv<'a>, runtime: &Runtime) -> Result<JObject<'a>> {
+        let kind = match self {
+            Value::Object(o) => return Ok(o),
+            Value::Int(_) => Specialized::Int,
+            Value::Long(_) => Specialized::Long,
+            Value::Double(_) => Specialized::Double,
+        };
+        count(&runtime.metrics.jni_upcalls);
+        let globals = &runtime.globals;
+        Ok(env
+            .call_static_method_unchecked(
+                JClass::from(globals.box_classes[kind.index()]),
+                JStaticMethodID::from(globals.box_methods[kind.index()]),
+                JavaType::Object(String::new()),
+                &[self.jvalue()],
+            )?
+            .l()?)
+    }
+
+    /// Deletes the local reference to the value, if it is an object.
+    pub(crate) fn release(self, env: &JNIEnv) -> Result<()> {
+        if let Value::Object(o) = self {
+            env.delete_local_ref(o)?;
+        }
+        Ok(())
+    }
+
+    /// Applies the function of a specialized map frame, through its unboxed `apply` if the value
+    /// is a primitive of the same kind.
+    pub(crate) fn apply(
+        self,
+        env: &'a JNIEnv<'a>,
+        runtime: &Runtime,
+        kind: Specialized,
+        f: JObject,
+    ) -> Result<JvmResult<'a, Value<'a>>> {
+        if self.specialized() != Some(kind) {
+            // a boxed value, or the function got mixed up with one of another kind
+            let arg = self.boxed(env, runtime)?;
+            let res = call_function1(env, runtime, f, arg)?;
+            if self.specialized().is_some() {
+                env.delete_local_ref(arg)?;
+            }
+            return Ok(match res {
+                JvmResult::Value(o) => JvmResult::Value(Value::Object(o)),
+                JvmResult::Exception(exc) => JvmResult::Exception(exc),
+                JvmResult::Fatal(exc) => JvmResult::Fatal(exc),
+            });
+        }
+
+        count(&runtime.metrics.jni_upcalls);
+        let res: Result<_> = (|| -> Result<_> {
+            Ok(env.call_method_unchecked(
+                f.into_inner(),
+                JMethodID::from(runtime.globals.function1_apply_specialized[kind.index()]),
+                JavaType::Primitive(kind.primitive()),
+                &[self.jvalue()],
+            )?)
+        })();
+        Ok(match res.check_exception(env, runtime)? {
+            JvmResult::Value(JValue::Int(i)) => JvmResult::Value(Value::Int(i)),
+            JvmResult::Value(JValue::Long(j)) => JvmResult::Value(Value::Long(j)),
+            JvmResult::Value(JValue::Double(d)) => JvmResult::Value(Value::Double(d)),
+            JvmResult::Value(other) => {
+                return Err(format!("unexpected {:?} from a specialized apply", other).into())
+            }
+            JvmResult::Exception(exc) => JvmResult::Exception(exc),
+            JvmResult::Fatal(exc) => JvmResult::Fatal(exc),
+        })
+    }
+}
@@ -0,0 +1,535 @@
+use crate::{new_global_ref, runtime::Runtime, trace::class_name, Bind, Result, Tag};
+use jni::{
+    objects::{GlobalRef, JByteBuffer, JClass, JFieldID, JMethodID, JObject},
+    signature::{JavaType, Primitive},
+    sys::{jfieldID, jmethodID, jobject},
+    JNIEnv,
+};
+use std::{convert::TryFrom, fmt::Write};
+
+const INITIAL_CAPACITY: usize = 64;
+
+/// A function popped off a [`BindStack`], `release` it once it has been called.
+pub(crate) enum Function<'a> {
+    Global(GlobalRef),
+    Local(JObject<'a>),
+}
+
+impl<'a> Function<'a> {
+    pub(crate) fn as_obj<'b>(&'b self) -> JObject<'b>
+    where
+        'a: 'b,
+    {
+        match self {
+            Function::Global(f) => f.as_obj(),
+            Function::Local(f) => *f,
+        }
+    }
+
+    /// A local reference to the function, which lets go of the global one if it was one.
+    pub(crate) fn into_local(self, env: &JNIEnv<'a>) -> Result<JObject<'a>> {
+        Ok(match self {
+            Function::Global(f) => {
+                env.new_local_ref::<JObject>(JObject::from(f.as_obj().into_inner()))?
+            }
+            Function::Local(f) => f,
+        })
+    }
+
+    /// Deletes the local reference to the function, if it is one.
+    pub(crate) fn release(self, env: &JNIEnv) -> Result<()> {
+        if let Function::Local(f) = self {
+            env.delete_local_ref(f)?;
+        }
+        Ok(())
+    }
+}
+
+/// The frames a fiber still has to run, see `iors.bindStack`.
+pub(crate) enum BindStack {
+    /// a local reference per frame while the fiber runs synchronously, global ones once it
+    /// suspends
+    References(RefStack),
+    /// an `iors.SharedFrames`, which `iors.SyncLoop` works on directly: the functions in a Java
+    /// `Object[]`, the depth and the kinds of the frames in a direct `ByteBuffer` that is plain
+    /// memory to this side. The native loop still takes a JNI call to push or pop a function.
+    Shared(SharedStack),
+}
+
+impl Default for BindStack {
+    fn default() -> BindStack {
+        BindStack::References(RefStack {
+            binds: vec![],
+            locals: 0,
+            reserved: 0,
+        })
+    }
+}
+
+impl BindStack {
+    pub(crate) fn new(shared: bool) -> BindStack {
+        if shared {
+            BindStack::Shared(SharedStack { frames: None })
+        } else {
+            BindStack::default()
+        }
+    }
+
+    pub(crate) fn len(&self) -> usize {
+        match self {
+            BindStack::References(refs) => refs.binds.len(),
+            BindStack::Shared(shared) => shared.len(),
+        }
+    }
+
+    /// Pushes a frame, taking over the local reference to its function. The reference has to
+    /// belong to the local frame of the whole synchronous segment, not to the one of a single step.
+    pub(crate) fn push(
+        &mut self,
+        env: &JNIEnv,
+        runtime: &Runtime,
+        bind: Bind<JObject>,
+    ) -> Result<()> {
+        match self {
+            BindStack::References(refs) => refs.push(env, bind),
+            BindStack::Shared(shared) => shared.push_bind(env, runtime, bind),
+        }
+    }
+
+    /// Pushes a frame whose function only lives as long as the local frame of the current step.
+    pub(crate) fn push_global(
+        &mut self,
+        env: &JNIEnv,
+        runtime: &Runtime,
+        bind: Bind<JObject>,
+    ) -> Result<()> {
+        match self {
+            BindStack::References(refs) => {
+                refs.binds
+                    .push(bind.try_map(|f| Ok(Ref::Global(new_global_ref(env, runtime, f)?)))?);
+                Ok(())
+            }
+            BindStack::Shared(shared) => shared.push_bind(env, runtime, bind),
+        }
+    }
+
+    pub(crate) fn pop<'a>(&mut self, env: &JNIEnv<'a>) -> Result<Option<Bind<Function<'a>>>> {
+        Ok(match self {
+            BindStack::References(refs) => refs
+                .pop()
+                .map(|bind| bind.try_map(|f| Ok(f.into_function())))
+                .transpose()?,
+            BindStack::Shared(shared) => shared
+                .pop(env)?
+                .map(|(tag, f)| Bind::from_tag(tag, Function::Local(f)).unwrap_or(Bind::Attempt)),
+        })
+    }
+
+    /// Drops the frames up to and including the first `attempt`, returns whether there was one.
+    pub(crate) fn unwind_to_attempt(&mut self, env: &JNIEnv) -> Result<bool> {
+        match self {
+            BindStack::References(refs) => {
+                while let Some(bind) = refs.pop() {
+                    match bind.function() {
+                        Some(Ref::Local(f)) => env.delete_local_ref(JObject::from(f.0))?,
+                        Some(Ref::Global(_)) => {}
+                        None => return Ok(true),
+                    }
+                }
+                Ok(false)
+            }
+            BindStack::Shared(shared) => shared.unwind_to_attempt(env),
+        }
+    }
+
+    /// Turns the local references into global ones, so that the frames can be run on another
+    /// thread or after the local frame of the segment is gone.
+    pub(crate) fn promote(&mut self, env: &JNIEnv, runtime: &Runtime) -> Result<()> {
+        if let BindStack::References(refs) = self {
+            for bind in refs.binds.iter_mut() {
+                if let Some(f) = bind.function_mut() {
+                    if let Ref::Local(local) = f {
+                        let local = JObject::from(local.0);
+                        *f = Ref::Global(new_global_ref(env, runtime, local)?);
+                        env.delete_local_ref(local)?;
+                    }
+                }
+            }
+            refs.locals = 0;
+            refs.reserved = 0;
+        }
+        Ok(())
+    }
+
+    /// Pushes the frames of a compiled program, an `iors.SharedFrames`, returns how many there were.
+    /// A shared stack without frames takes them over as they are, any other gets them copied.
+    pub(crate) fn push_compiled(
+        &mut self,
+        env: &JNIEnv,
+        runtime: &Runtime,
+        frames: JObject,
+    ) -> Result<usize> {
+        let mut compiled = SharedStack { frames: None };
+        compiled.sync(env, runtime, frames)?;
+        let len = compiled.len();
+        for i in 0..len {
+            let tag = compiled.tag_at(i)?;
+            if Bind::from_tag(tag, ()).is_none() {
+                return Err(
+                    format!("{:?} is not a frame a program can be compiled to", tag).into(),
+                );
+            }
+        }
+
+        match self {
+            BindStack::Shared(shared) if shared.len() == 0 => *shared = compiled,
+            stack => {
+                for i in 0..len {
+                    let (tag, f) = compiled.get(env, i)?;
+                    let bind = Bind::from_tag(tag, f).ok_or("a compiled frame changed its tag")?;
+                    stack.push(env, runtime, bind)?;
+                }
+            }
+        }
+        Ok(len)
+    }
+
+    /// The `iors.SharedFrames` of a shared stack, null if it doesn't have one (yet).
+    pub(crate) fn shared_frames(&self) -> JObject<'_> {
+        match self {
+            BindStack::References(_) => JObject::null(),
+            BindStack::Shared(shared) => shared.as_obj(),
+        }
+    }
+
+    /// Takes `frames` as the frames of a shared stack after the Scala side had them, see
+    /// [`SharedStack::sync`].
+    pub(crate) fn sync_shared(
+        &mut self,
+        env: &JNIEnv,
+        runtime: &Runtime,
+        frames: JObject,
+    ) -> Result<()> {
+        match self {
+            BindStack::References(_) => {
+                Err("only a shared bind stack has frames on the JVM".into())
+            }
+            BindStack::Shared(shared) => shared.sync(env, runtime, frames),
+        }
+    }
+
+    /// Describes the frames, innermost first.
+    pub(crate) fn describe(&self, env: &JNIEnv, out: &mut String) -> Result<()> {
+        let describe_frame = |out: &mut String, op: &str, f: JObject| -> Result<()> {
+            match op {
+                "attempt" => writeln!(out, "    attempt")?,
+                op => writeln!(out, "    {} {}", op, class_name(env, f)?)?,
+            }
+            Ok(())
+        };
+
+        match self {
+            BindStack::References(refs) => {
+                for bind in refs.binds.iter().rev() {
+                    match bind {
+                        Bind::Map(f) | Bind::SpecializedMap(_, f) => {
+                            describe_frame(out, "map", f.as_obj())?
+                        }
+                        Bind::FlatMap(f) => describe_frame(out, "flatMap", f.as_obj())?,
+                        Bind::Attempt => describe_frame(out, "attempt", JObject::null())?,
+                    }
+                }
+            }
+            BindStack::Shared(shared) => {
+                for i in (0..shared.len()).rev() {
+                    let (tag, f) = shared.get(env, i)?;
+                    let op = match tag {
+                        Tag::FlatMap => "flatMap",
+                        Tag::Attempt => "attempt",
+                        _ => "map",
+                    };
+                    describe_frame(out, op, f)?;
+                    if !f.is_null() {
+                        env.delete_local_ref(f)?;
+                    }
+                }
+            }
+        }
+        Ok(())
+    }
+}
+
+/// A local reference without the lifetime of the `JNIEnv` it was created through.
+struct LocalRef(jobject);
+
+// SAFETY: local references are only valid on the thread that created them. A stack holds them
+// while the run loop evaluates on that thread, and gets them promoted before it is handed over to
+// another one or the local frame they belong to is popped
+unsafe impl Send for LocalRef {}
+
+enum Ref {
+    Global(GlobalRef),
+    Local(LocalRef),
+}
+
+impl Ref {
+    fn as_obj(&self) -> JObject<'_> {
+        match self {
+            Ref::Global(f) => f.as_obj(),
+            Ref::Local(f) => JObject::from(f.0),
+        }
+    }
+
+    fn into_function<'a>(self) -> Function<'a> {
+        match self {
+            Ref::Global(f) => Function::Global(f),
+            Ref::Local(f) => Function::Local(JObject::from(f.0)),
+        }
+    }
+}
+
+pub(crate) struct RefStack {
+    binds: Vec<Bind<Ref>>,
+    // how many of the frames hold a local reference, and how many the local frame has room for
+    locals: usize,
+    reserved: usize,
+}
+
+impl RefStack {
+    fn push(&mut self, env: &JNIEnv, bind: Bind<JObject>) -> Result<()> {
+        let bind = bind.try_map(|f| self.take_local(env, f))?;
+        self.binds.push(bind);
+        Ok(())
+    }
+
+    fn take_local(&mut self, env: &JNIEnv, f: JObject) -> Result<Ref> {
+        self.locals += 1;
+        if self.locals > self.reserved {
+            self.reserved = (self.reserved * 2).max(INITIAL_CAPACITY);
+            env.ensure_local_capacity(self.reserved as i32)?;
+        }
+        Ok(Ref::Local(LocalRef(f.into_inner())))
+    }
+
+    fn pop(&mut self) -> Option<Bind<Ref>> {
+        let bind = self.binds.pop()?;
+        if let Some(Ref::Local(_)) = bind.function() {
+            self.locals -= 1;
+        }
+        Some(bind)
+    }
+}
+
+/// The fields and methods of `iors.SharedFrames`.
+pub(crate) struct SharedFramesIds {
+    pub(crate) new: jmethodID,
+    pub(crate) grow: jmethodID,
+    pub(crate) functions: jfieldID,
+    pub(crate) kinds: jfieldID,
+}
+
+/// The bytes in front of the tags in the buffer of an `iors.SharedFrames`, the depth as a
+/// native-endian `i32`.
+const HEADER: usize = 8;
+
+/// An `iors.SharedFrames` and what its fields held when they were last read.
+struct Frames {
+    frames: GlobalRef,
+    functions: GlobalRef,
+    // the buffer `frames` holds on to, it's only replaced while the Scala side has the stack
+    kinds_address: *mut u8,
+    capacity: usize,
+}
+
+pub(crate) struct SharedStack {
+    // created on the first push, so that fibers that never bind don't pay for it
+    frames: Option<Frames>,
+}
+
+// SAFETY: `kinds_address` points into the buffer that `frames` keeps alive, and only the fiber
+// owning the stack touches it, from one thread at a time
+unsafe impl Send for SharedStack {}
+
+impl SharedStack {
+    fn frames(&self) -> Result<&Frames> {
+        Ok(self
+            .frames
+            .as_ref()
+            .ok_or("the bind stack was never pushed to")?)
+    }
+
+    fn len(&self) -> usize {
+        self.frames.as_ref().map_or(0, |frames| unsafe {
+            (frames.kinds_address as *const i32).read() as usize
+        })
+    }
+
+    fn set_len(&self, len: usize) -> Result<()> {
+        let frames = self.frames()?;
+        unsafe { (frames.kinds_address as *mut i32).write(len as i32) };
+        Ok(())
+    }
+
+    fn tag_at(&self, i: usize) -> Result<Tag> {
+        let tag = unsafe { *self.frames()?.kinds_address.add(HEADER + i) };
+        Ok(Tag::try_from(tag as i32)?)
+    }
+
+    /// The `iors.SharedFrames`, null if nothing was ever pushed.
+    pub(crate) fn as_obj(&self) -> JObject<'_> {
+        self.frames
+            .as_ref()
+            .map_or_else(JObject::null, |frames| frames.frames.as_obj())
+    }
+
+    /// Takes `frames` as the stack, after the Scala side had it or created it. Reads its fields
+    /// again, it may have grown them.
+    pub(crate) fn sync(&mut self, env: &JNIEnv, runtime: &Runtime, frames: JObject) -> Result<()> {
+        if frames.is_null() {
+            return Ok(());
+        }
+        let ids = &runtime.globals.shared_frames;
+        let functions = env
+            .get_field_unchecked(
+                frames,
+                JFieldID::from(ids.functions),
+                JavaType::Object(String::new()),
+            )?
+            .l()?;
+        let kinds = env
+            .get_field_unchecked(
+                frames,
+                JFieldID::from(ids.kinds),
+                JavaType::Object(String::new()),
+            )?
+            .l()?;
+        let kinds_address = env
+            .get_direct_buffer_address(JByteBuffer::from(kinds))?
+            .as_mut_ptr();
+
+        match &mut self.frames {
+            Some(old) if env.is_same_object(old.frames.as_obj(), frames)? => {
+                if !env.is_same_object(old.functions.as_obj(), functions)? {
+                    old.functions = new_global_ref(env, runtime, functions)?;
+                    old.capacity = env.get_array_length(functions.into_inner())? as usize;
+                }
+                old.kinds_address = kinds_address;
+            }
+            _ => {
+                self.frames = Some(Frames {
+                    frames: new_global_ref(env, runtime, frames)?,
+                    functions: new_global_ref(env, runtime, functions)?,
+                    kinds_address,
+                    capacity: env.get_array_length(functions.into_inner())? as usize,
+                })
+            }
+        }
+        env.delete_local_ref(functions)?;
+        env.delete_local_ref(kinds)?;
+        match &self.frames {
+            Some(frames) if self.len() > frames.capacity => {
+                Err("the shared frames are deeper than their capacity".into())
+            }
+            _ => Ok(()),
+        }
+    }
+
+    /// Creates the `iors.SharedFrames`, or lets it grow its array and buffer.
+    fn grow(&mut self, env: &JNIEnv, runtime: &Runtime) -> Result<()> {
+        let ids = &runtime.globals.shared_frames;
+        let frames = match &self.frames {
+            Some(frames) => {
+                env.call_method_unchecked(
+                    frames.frames.as_obj(),
+                    JMethodID::from(ids.grow),
+                    JavaType::Primitive(Primitive::Void),
+                    &[],
+                )?;
+                env.new_local_ref::<JObject>(JObject::from(frames.frames.as_obj().into_inner()))?
+            }
+            None => env.new_object_unchecked(
+                JClass::from(runtime.globals.shared_frames_class),
+                JMethodID::from(ids.new),
+                &[(INITIAL_CAPACITY as i32).into()],
+            )?,
+        };
+        self.sync(env, runtime, frames)?;
+        env.delete_local_ref(frames)?;
+        Ok(())
+    }
+
+    /// Copies the function into the array and deletes the local reference to it.
+    fn push_bind(&mut self, env: &JNIEnv, runtime: &Runtime, bind: Bind<JObject>) -> Result<()> {
+        let tag = bind.tag();
+        let f = bind.into_function().unwrap_or_else(JObject::null);
+        self.push(env, runtime, tag, f)?;
+        if !f.is_null() {
+            env.delete_local_ref(f)?;
+        }
+        Ok(())
+    }
+
+    fn push(&mut self, env: &JNIEnv, runtime: &Runtime, tag: Tag, f: JObject) -> Result<()> {
+        let len = self.len();
+        if self
+            .frames
+            .as_ref()
+            .map_or(true, |frames| len == frames.capacity)
+        {
+            self.grow(env, runtime)?;
+        }
+
+        let frames = self.frames()?;
+        if !f.is_null() {
+            let functions = frames.functions.as_obj().into_inner();
+            env.set_object_array_element(functions, len as i32, f)?;
+        }
+        unsafe { *frames.kinds_address.add(HEADER + len) = tag as u8 };
+        self.set_len(len + 1)
+    }
+
+    fn get<'a>(&self, env: &JNIEnv<'a>, i: usize) -> Result<(Tag, JObject<'a>)> {
+        let tag = self.tag_at(i)?;
+        let f = match tag {
+            Tag::Attempt => JObject::null(),
+            _ => {
+                let functions = self.frames()?.functions.as_obj().into_inner();
+                env.get_object_array_element(functions, i as i32)?
+            }
+        };
+        Ok((tag, f))
+    }
+
+    fn pop<'a>(&mut self, env: &JNIEnv<'a>) -> Result<Option<(Tag, JObject<'a>)>> {
+        let len = self.len();
+        if len == 0 {
+            return Ok(None);
+        }
+        let (tag, f) = self.get(env, len - 1)?;
+        self.forget_top(env, tag)?;
+        Ok(Some((tag, f)))
+    }
+
+    fn unwind_to_attempt(&mut self, env: &JNIEnv) -> Result<bool> {
+        while self.len() > 0 {
+            let tag = self.tag_at(self.len() - 1)?;
+            self.forget_top(env, tag)?;
+            if tag == Tag::Attempt {
+                return Ok(true);
+            }
+        }
+        Ok(false)
+    }
+
+    /// Takes the top frame off and clears its function, so that the array doesn't keep it from
+    /// being collected.
+    fn forget_top(&mut self, env: &JNIEnv, tag: Tag) -> Result<()> {
+        let len = self.len() - 1;
+        self.set_len(len)?;
+        if tag != Tag::Attempt {
+            let functions = self.frames()?.functions.as_obj().into_inner();
+            env.set_object_array_element(functions, len as i32, JObject::null())?;
+        }
+        Ok(())
+    }
+}
@@ -0,0 +1,23 @@
+// The natives of `iors.NativeTestHooks`, which is in the test jar only. They aren't registered in
+// `natives::register` with the rest, the JVM finds them by their symbols. Built with the
+// `test-hooks` feature only, they take the global runtime away from the programs using it.
+
+use crate::{runtime, throw_error, Globals};
+use jni::{objects::JObject, JNIEnv};
+
+/// Does to the globals what `JNI_OnLoad` does, resolving everything again and replacing the global
+/// runtime, while the library is in use. Runtimes that are still running keep the globals they
+/// were created with.
+#[no_mangle]
+extern "system" fn Java_iors_NativeTestHooks_00024_reloadGlobals0(env: JNIEnv, _this: JObject) {
+    let res = Globals::new(env.clone()).and_then(|globals| runtime::init_global(&env, globals));
+    if let Err(e) = res {
+        throw_error(&env, &*e);
+    }
+}
+
+/// What `JNI_OnUnload` does, without unloading anything.
+#[no_mangle]
+extern "system" fn Java_iors_NativeTestHooks_00024_unload0(env: JNIEnv, _this: JObject) {
+    runtime::shutdown_all(&env);
+}
@@ -0,0 +1,300 @@
+use crate::{config::Config, throw_error, warnings::warn, Globals, Result, Tag};
+use jni::{
+    objects::{JClass, JObject, JStaticMethodID, JString, JThrowable},
+    signature::{JavaType, Primitive},
+    sys::jobjectArray,
+    JNIEnv,
+};
+use std::{cell::RefCell, collections::VecDeque};
+
+pub(crate) fn class_name(env: &JNIEnv, obj: JObject) -> Result<String> {
+    let class = env.get_object_class(obj)?;
+    let jname = env
+        .call_method(class, "getName", "()Ljava/lang/String;", &[])?
+        .l()?;
+    let name = env.get_string(JString::from(jname))?.into();
+    env.delete_local_ref(jname)?;
+    env.delete_local_ref(class.into())?;
+    Ok(name)
+}
+
+pub(crate) fn string_array(env: &JNIEnv, strings: &[String]) -> Result<jobjectArray> {
+    let array = env.new_object_array(strings.len() as i32, "java/lang/String", JObject::null())?;
+    for (i, string) in strings.iter().enumerate() {
+        let string = env.new_string(string)?;
+        env.set_object_array_element(array, i as i32, string)?;
+        // the arrays can be longer than the local frame of the run loop
+        env.delete_local_ref(string.into())?;
+    }
+    Ok(array)
+}
+
+/// The `map`/`flatMap` frames a fiber applied most recently, the one that threw included, attached
+/// to errors raised by user code so that they point back at the combinator chain they came from,
+/// not only at the JNI call.
+pub(crate) struct BindTrace {
+    // (combinator, closure class), newest at the back
+    frames: VecDeque<(&'static str, String)>,
+    depth: usize,
+}
+
+impl BindTrace {
+    /// Remembers the last `depth` frames, `None` when async stack traces are disabled (`depth` = 0)
+    /// so that a fiber doesn't pay for them.
+    pub(crate) fn new(depth: usize) -> Option<BindTrace> {
+        if depth == 0 {
+            return None;
+        }
+
+        Some(BindTrace {
+            frames: VecDeque::with_capacity(depth),
+            depth,
+        })
+    }
+
+    /// Records the frame that is about to be applied. A fused chain of `map`s is a frame per
+    /// function, named after the functions the user passed in.
+    pub(crate) fn record(
+        &mut self,
+        env: &JNIEnv,
+        globals: &Globals,
+        op: &'static str,
+        f: JObject,
+    ) -> Result<()> {
+        let functions = env
+            .call_static_method_unchecked(
+                JClass::from(globals.iors_class),
+                JStaticMethodID::from(globals.iors_unfused),
+                JavaType::Object(String::new()),
+                &[f.into()],
+            )?
+            .l()?;
+        for i in 0..env.get_array_length(functions.into_inner())? {
+            let function = env.get_object_array_element(functions.into_inner(), i)?;
+            let name = class_name(env, function)?;
+            env.delete_local_ref(function)?;
+            if self.frames.len() == self.depth {
+                self.frames.pop_front();
+            }
+            self.frames.push_back((op, name));
+        }
+        env.delete_local_ref(functions)?;
+        Ok(())
+    }
+
+    /// Adds the recorded frames to `throwable` as a suppressed `IoRsTrace`, newest frame first.
+    pub(crate) fn attach(
+        &self,
+        env: &JNIEnv,
+        globals: &Globals,
+        throwable: JThrowable,
+    ) -> Result<()> {
+        let (ops, classes): (Vec<_>, Vec<_>) = self
+            .frames
+            .iter()
+            .rev()
+            .map(|(op, class)| (op.to_string(), class.clone()))
+            .unzip();
+        let ops = string_array(env, &ops)?;
+        let classes = string_array(env, &classes)?;
+
+        env.call_static_method_unchecked(
+            JClass::from(globals.iors_class),
+            JStaticMethodID::from(globals.iors_add_trace),
+            JavaType::Primitive(Primitive::Void),
+            &[
+                JObject::from(throwable).into(),
+                JObject::from(ops).into(),
+                JObject::from(classes).into(),
+            ],
+        )?;
+        Ok(())
+    }
+}
+
+thread_local! {
+    /// The trace of the fiber that is currently being evaluated on this thread, if it's traced.
+    static CURRENT_TRACE: RefCell<Option<ExecutionTrace>> = const { RefCell::new(None) };
+    /// The trace of the last traced fiber that finished on this thread.
+    static LAST_TRACE: RefCell<Option<ExecutionTrace>> = const { RefCell::new(None) };
+}
+
+/// A ring buffer of the nodes a fiber evaluated most recently, with the classes of their closures.
+pub(crate) struct ExecutionTrace {
+    // (node, closure class), newest at the back
+    steps: VecDeque<(Tag, Option<String>)>,
+    capacity: usize,
+    dump_on_failure: bool,
+}
+
+impl ExecutionTrace {
+    /// `None` when the fiber about to start is not sampled.
+    pub(crate) fn new(config: &Config, fiber_id: u64) -> Option<ExecutionTrace> {
+        let every = config.execution_tracing as u64;
+        if every == 0 || (fiber_id - 1) % every != 0 {
+            return None;
+        }
+
+        let capacity = config.execution_trace_buffer_size;
+        Some(ExecutionTrace {
+            steps: VecDeque::with_capacity(capacity),
+            capacity,
+            dump_on_failure: config.execution_trace_dump_on_failure,
+        })
+    }
+
+    fn push(&mut self, step: (Tag, Option<String>)) {
+        if self.steps.len() == self.capacity {
+            self.steps.pop_front();
+        }
+        self.steps.push_back(step);
+    }
+
+    fn lines(&self) -> Vec<String> {
+        self.steps
+            .iter()
+            .map(|step| match step {
+                (tag, Some(class)) => format!("{:?} {}", tag, class),
+                (tag, None) => format!("{:?}", tag),
+            })
+            .collect()
+    }
+}
+
+/// Installs a fiber's trace as the current one on this thread for as long as the run loop
+/// evaluates it here. The trace of the run loop that was active before (if this one was started
+/// from inside a thunk) is restored on drop.
+pub(crate) struct ActiveTrace {
+    outer: Option<ExecutionTrace>,
+    tracing: bool,
+    suspended: bool,
+}
+
+impl ActiveTrace {
+    pub(crate) fn activate(trace: Option<ExecutionTrace>) -> ActiveTrace {
+        let tracing = trace.is_some();
+        let outer = CURRENT_TRACE.with(|current| current.replace(trace));
+        ActiveTrace {
+            outer,
+            tracing,
+            suspended: false,
+        }
+    }
+
+    pub(crate) fn is_tracing(&self) -> bool {
+        self.tracing
+    }
+
+    /// Takes the trace back from the thread, for the fiber to carry it across an async boundary.
+    pub(crate) fn suspend(&mut self) -> Option<ExecutionTrace> {
+        self.tracing = false;
+        self.suspended = true;
+        CURRENT_TRACE.with(|current| current.replace(self.outer.take()))
+    }
+
+    fn with(&self, f: impl FnOnce(&mut ExecutionTrace)) {
+        if self.tracing {
+            CURRENT_TRACE.with(|current| current.borrow_mut().as_mut().map(f));
+        }
+    }
+
+    pub(crate) fn record(
+        &self,
+        env: &JNIEnv,
+        globals: &Globals,
+        tag: Tag,
+        node: JObject,
+    ) -> Result<()> {
+        if !self.tracing {
+            return Ok(());
+        }
+
+        let closure = match tag {
+            Tag::Delay => Some(globals.nodes.delay.thunk(env, node)?),
+            Tag::Async => Some(globals.nodes.r#async.f(env, node)?),
+            Tag::Map => Some(globals.nodes.map.f(env, node)?),
+            Tag::FlatMap => Some(globals.nodes.flat_map.f(env, node)?),
+            Tag::MapIntInt => Some(globals.nodes.map_int_int.f(env, node)?),
+            Tag::MapLongLong => Some(globals.nodes.map_long_long.f(env, node)?),
+            Tag::MapDoubleDouble => Some(globals.nodes.map_double_double.f(env, node)?),
+            Tag::Pure
+            | Tag::PureInt
+            | Tag::PureLong
+            | Tag::PureDouble
+            | Tag::RaiseError
+            | Tag::Attempt
+            | Tag::Extension
+            | Tag::Compiled => None,
+        };
+        let class = match closure {
+            Some(closure) => {
+                let class = class_name(env, closure)?;
+                // recorded outside of the local frame of the step for the nodes that push frames
+                env.delete_local_ref(closure)?;
+                Some(class)
+            }
+            None => None,
+        };
+        self.with(|trace| trace.push((tag, class)));
+        Ok(())
+    }
+
+    /// Reports the trace as a warning if the fiber is failing and it was asked to do so.
+    pub(crate) fn dump_failure(
+        &self,
+        env: &JNIEnv,
+        globals: &Globals,
+        throwable: JThrowable,
+    ) -> Result<()> {
+        let mut lines = None;
+        self.with(|trace| {
+            if trace.dump_on_failure {
+                lines = Some(trace.lines())
+            }
+        });
+
+        if let Some(lines) = lines {
+            let description = env
+                .call_method(throwable, "toString", "()Ljava/lang/String;", &[])?
+                .l()?;
+            let description: String = env.get_string(JString::from(description))?.into();
+            let mut message = format!(
+                "iors: fiber failed with {}, last {} steps (oldest first):",
+                description,
+                lines.len()
+            );
+            for line in lines {
+                message.push_str("\n    ");
+                message.push_str(&line);
+            }
+            warn(env, globals, &message);
+        }
+        Ok(())
+    }
+}
+
+impl Drop for ActiveTrace {
+    fn drop(&mut self) {
+        if self.suspended {
+            return;
+        }
+
+        let finished = CURRENT_TRACE.with(|current| current.replace(self.outer.take()));
+        if finished.is_some() {
+            LAST_TRACE.with(|last| last.replace(finished));
+        }
+    }
+}
+
+/// The trace of the fiber running on the calling thread, or of the last one that finished here.
+pub(crate) extern "system" fn current_trace(env: JNIEnv, _this: JObject) -> jobjectArray {
+    let lines = CURRENT_TRACE
+        .with(|current| current.borrow().as_ref().map(ExecutionTrace::lines))
+        .or_else(|| LAST_TRACE.with(|last| last.borrow().as_ref().map(ExecutionTrace::lines)))
+        .unwrap_or_default();
+
+    string_array(&env, &lines).unwrap_or_else(|e| {
+        throw_error(&env, &*e);
+        std::ptr::null_mut()
+    })
+}
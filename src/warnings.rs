@@ -0,0 +1,30 @@
+use crate::Globals;
+use jni::{
+    objects::{JClass, JObject, JStaticMethodID},
+    signature::{JavaType, Primitive},
+    JNIEnv,
+};
+
+/// Hands `message` to the handler set with `IoRs.setWarningHandler`, which prints it to stderr
+/// unless told otherwise. Goes to stderr directly if the handler throws, the warnings come from
+/// threads with no caller to throw to.
+pub(crate) fn warn(env: &JNIEnv, globals: &Globals, message: &str) {
+    let res = env.with_local_frame(4, || {
+        let jmessage = env.new_string(message)?;
+        env.call_static_method_unchecked(
+            JClass::from(globals.iors_class),
+            JStaticMethodID::from(globals.iors_warn),
+            JavaType::Primitive(Primitive::Void),
+            &[JObject::from(jmessage).into()],
+        )?;
+        Ok(JObject::null())
+    });
+
+    if let Err(e) = res {
+        if env.exception_check().unwrap_or(false) {
+            let _ = env.exception_clear();
+        }
+        eprintln!("{}", message);
+        eprintln!("iors: the warning handler failed: {}", e);
+    }
+}
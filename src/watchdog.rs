@@ -0,0 +1,80 @@
+use crate::{runtime::Runtime, warnings::warn, Result};
+use jni::{objects::JObject, JNIEnv};
+use std::{
+    sync::{Arc, Condvar, Mutex, Weak},
+    thread::{self, JoinHandle},
+    time::Duration,
+};
+
+/// A native thread that periodically warns about fibers of a runtime that have been stuck in a
+/// single `Delay` thunk or suspended on an `Async` node for too long.
+pub(crate) struct Watchdog {
+    stop: Arc<(Mutex<bool>, Condvar)>,
+    thread: JoinHandle<()>,
+}
+
+impl Watchdog {
+    pub(crate) fn start(
+        env: &JNIEnv,
+        name: &str,
+        runtime: Weak<Runtime>,
+        threshold: Duration,
+        interval: Duration,
+    ) -> Result<Watchdog> {
+        let jvm = env.get_java_vm()?;
+        let stop = Arc::new((Mutex::new(false), Condvar::new()));
+        let thread_stop = stop.clone();
+        let thread = thread::Builder::new()
+            .name(name.to_string())
+            .spawn(move || {
+                let env = jvm
+                    .attach_current_thread_as_daemon()
+                    .expect("Could not attach the watchdog to the JVM");
+                let (stopped, wakeup) = &*thread_stop;
+                loop {
+                    // not holding the lock while scanning, dropping the runtime at the end can stop
+                    // this very watchdog
+                    let guard = stopped.lock().unwrap();
+                    if *guard || *wakeup.wait_timeout(guard, interval).unwrap().0 {
+                        return;
+                    }
+                    let runtime = match runtime.upgrade() {
+                        Some(runtime) => runtime,
+                        None => return,
+                    };
+                    if let Err(e) = scan(&env, &runtime, threshold) {
+                        let warning = format!("iors watchdog: scan failed: {}", e);
+                        warn(&env, &runtime.globals, &warning);
+                    }
+                }
+            })?;
+
+        Ok(Watchdog { stop, thread })
+    }
+
+    pub(crate) fn stop(self) {
+        let (stopped, wakeup) = &*self.stop;
+        *stopped.lock().unwrap() = true;
+        wakeup.notify_one();
+        // the watchdog itself can be what drops the last reference to its runtime
+        if self.thread.thread().id() != thread::current().id() {
+            let _ = self.thread.join();
+        }
+    }
+}
+
+fn scan(env: &JNIEnv, runtime: &Runtime, threshold: Duration) -> Result<()> {
+    for fiber in runtime.fibers.live() {
+        env.push_local_frame(8)?;
+        let stuck = fiber.check_stuck(env, threshold);
+        env.pop_local_frame(JObject::null())?;
+        if let Some(warning) = stuck? {
+            warn(
+                env,
+                &runtime.globals,
+                &format!("iors watchdog: {}", warning),
+            );
+        }
+    }
+    Ok(())
+}
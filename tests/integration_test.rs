@@ -1,8 +1,22 @@
-#![feature(bool_to_option)]
-
-use jni::{Executor, InitArgsBuilder, JavaVM};
+use iors::{
+    IorsStep, IorsStepFn, IORS_STEP_BIND, IORS_STEP_PROGRAM, IORS_STEP_SUSPEND, IORS_STEP_VALUE,
+};
+use jni::{
+    objects::JString,
+    sys::{self, jint, jobject},
+    Executor, InitArgsBuilder, JNIEnv, JavaVM,
+};
 use once_cell::sync::Lazy;
-use std::{env, ops::Deref, path::PathBuf, process::Command, sync::Arc};
+use std::{
+    env,
+    ffi::{c_void, CString},
+    fs,
+    ops::Deref,
+    os::raw::c_char,
+    path::PathBuf,
+    process::Command,
+    sync::{Arc, Mutex},
+};
 
 static IORS_PATH: Lazy<PathBuf> = Lazy::new(test_cdylib::build_current_project);
 static JAR_PATH: Lazy<PathBuf> = Lazy::new(|| {
@@ -23,23 +37,22 @@ static JAR_PATH: Lazy<PathBuf> = Lazy::new(|| {
     });
 
     Command::new(java)
-        .args(&["-jar", &sbt_path, "test:assembly"])
+        .args(["-jar", &sbt_path, "test:assembly"])
         .current_dir("./iors-jvm")
         .status()
         .unwrap();
 
     PathBuf::from("./iors-jvm/target/scala-2.13/iors-jvm-test-0.1.jar")
 });
-
-#[test]
-fn jni_works() {
+// there can only be one JVM per process, so the tests share it
+static JVM: Lazy<Arc<JavaVM>> = Lazy::new(|| {
     let lib_path = format!(
         "-Djava.library.path={}",
         IORS_PATH.parent().unwrap().display(),
     );
     let jar_path = format!("-Djava.class.path={}", JAR_PATH.deref().display());
 
-    let jvm = Arc::new(
+    Arc::new(
         JavaVM::new(
             InitArgsBuilder::new()
                 .option(&lib_path)
@@ -49,8 +62,61 @@ fn jni_works() {
                 .unwrap(),
         )
         .unwrap(),
+    )
+});
+
+// `scala_tests` unloads the library, which forgets the node kinds `native_node_kinds_step` registers
+static UNLOADING: Mutex<()> = Mutex::new(());
+
+/// The IoRs.scala node definitions that build.rs generates from src/nodes.rs.
+const GENERATED_NODES: &str = include_str!(concat!(env!("OUT_DIR"), "/nodes.scala"));
+
+/// IoRs.scala is compiled by sbt, without build.rs running, so this checks that it's up to date.
+/// `IORS_UPDATE_SCALA=1 cargo test scala_nodes_are_generated` updates it.
+#[test]
+fn scala_nodes_are_generated() {
+    const BEGIN: &str = "  // generated by build.rs from src/nodes.rs, edit that instead\n";
+    const END: &str = "  // end of generated code\n";
+    let path = concat!(
+        env!("CARGO_MANIFEST_DIR"),
+        "/iors-jvm/src/main/scala/iors/IoRs.scala"
     );
-    let executor = Executor::new(jvm);
+
+    let source = fs::read_to_string(path).unwrap();
+    // the file has windows line endings
+    let crlf = source.contains("\r\n");
+    let source = source.replace("\r\n", "\n");
+    let begin = source
+        .find(BEGIN)
+        .expect("IoRs.scala lost the beginning marker of the generated code")
+        + BEGIN.len();
+    let end = source[begin..]
+        .find(END)
+        .expect("IoRs.scala lost the end marker of the generated code")
+        + begin;
+
+    if env::var_os("IORS_UPDATE_SCALA").is_some() {
+        let updated = format!("{}{}{}", &source[..begin], GENERATED_NODES, &source[end..]);
+        let updated = if crlf {
+            updated.replace('\n', "\r\n")
+        } else {
+            updated
+        };
+        fs::write(path, updated).unwrap();
+        return;
+    }
+
+    assert_eq!(
+        &source[begin..end],
+        GENERATED_NODES,
+        "the node definitions in IoRs.scala don't match src/nodes.rs, run the test with \
+         IORS_UPDATE_SCALA=1 to update them"
+    );
+}
+
+#[test]
+fn jni_works() {
+    let executor = Executor::new(JVM.clone());
 
     let res = executor
         .with_attached(|env| {
@@ -63,25 +129,38 @@ fn jni_works() {
     assert_eq!(res, 111);
 }
 
+/// Runs the whole `IoRsTests` suite, which reports every failed assertion with what it expected.
 #[test]
-fn expensive_stuff_for_profiling() {
-    let lib_path = format!(
-        "-Djava.library.path={}",
-        IORS_PATH.parent().unwrap().display(),
-    );
-    let jar_path = format!("-Djava.class.path={}", JAR_PATH.deref().display());
+fn scala_tests() {
+    let _unloading = UNLOADING.lock().unwrap_or_else(|e| e.into_inner());
+    let executor = Executor::new(JVM.clone());
 
-    let jvm = Arc::new(
-        JavaVM::new(
-            InitArgsBuilder::new()
-                .option(&lib_path)
-                .option(&jar_path)
-                .build()
-                .unwrap(),
-        )
-        .unwrap(),
+    let failures = executor
+        .with_attached(|env| {
+            let failures = env
+                .call_static_method("iors/IoRsTests", "runAll", "()[Ljava/lang/String;", &[])?
+                .l()?
+                .into_inner();
+            (0..env.get_array_length(failures)?)
+                .map(|i| {
+                    let failure = env.get_object_array_element(failures, i)?;
+                    Ok(env.get_string(JString::from(failure))?.into())
+                })
+                .collect::<jni::errors::Result<Vec<String>>>()
+        })
+        .unwrap();
+
+    assert!(
+        failures.is_empty(),
+        "{} scala tests failed:\n\n{}",
+        failures.len(),
+        failures.join("\n")
     );
-    let executor = Executor::new(jvm);
+}
+
+#[test]
+fn expensive_stuff_for_profiling() {
+    let executor = Executor::new(JVM.clone());
 
     executor
         .with_attached(|env| {
@@ -91,3 +170,108 @@ fn expensive_stuff_for_profiling() {
         })
         .unwrap();
 }
+
+type Registrar = unsafe extern "C" fn(
+    jint,
+    *const c_char,
+    *const *const c_char,
+    usize,
+    IorsStepFn,
+    *mut c_void,
+) -> jint;
+
+/// Steps to the first field of the node, as the kind of step `data` points to.
+unsafe extern "C" fn step_to_field(
+    env: *mut sys::JNIEnv,
+    fields: jobject,
+    data: *mut c_void,
+    step: *mut IorsStep,
+) -> jint {
+    let env = JNIEnv::from_raw(env).unwrap();
+    let field = |i| {
+        env.get_object_array_element(fields, i)
+            .unwrap()
+            .into_inner()
+    };
+    let kind = *(data as *const jint);
+    *step = IorsStep {
+        kind,
+        first: field(0),
+        second: if kind == IORS_STEP_BIND {
+            field(1)
+        } else {
+            std::ptr::null_mut()
+        },
+    };
+    0
+}
+
+/// Fails, with the message in its field thrown if there is one.
+unsafe extern "C" fn fail(
+    env: *mut sys::JNIEnv,
+    fields: jobject,
+    _data: *mut c_void,
+    _step: *mut IorsStep,
+) -> jint {
+    let env = JNIEnv::from_raw(env).unwrap();
+    let message = env.get_object_array_element(fields, 0).unwrap();
+    if !message.is_null() {
+        let message: String = env.get_string(JString::from(message)).unwrap().into();
+        env.throw_new("java/lang/IllegalArgumentException", message)
+            .unwrap();
+    }
+    1
+}
+
+/// Registers a kind of node for every [`iors::Step`] through the registrar of the loaded library,
+/// like a native library that isn't linked into it would, and runs them.
+#[test]
+fn native_node_kinds_step() {
+    static STEPS: [jint; 4] = [
+        IORS_STEP_VALUE,
+        IORS_STEP_PROGRAM,
+        IORS_STEP_BIND,
+        IORS_STEP_SUSPEND,
+    ];
+    let _unloading = UNLOADING.lock().unwrap_or_else(|e| e.into_inner());
+    let executor = Executor::new(JVM.clone());
+
+    executor
+        .with_attached(|env| {
+            let registrar = env
+                .call_static_method("iors/IoRs", "nodeKindRegistrar", "()J", &[])?
+                .j()?;
+            let register: Registrar = unsafe { std::mem::transmute(registrar as usize) };
+            let kinds: [(&str, &[&str], IorsStepFn, *const jint); 5] = [
+                ("value", &["value"], step_to_field, &STEPS[0]),
+                ("program", &["program"], step_to_field, &STEPS[1]),
+                ("bind", &["source", "f"], step_to_field, &STEPS[2]),
+                ("suspend", &["register"], step_to_field, &STEPS[3]),
+                ("fail", &["message"], fail, std::ptr::null()),
+            ];
+            for (tag, (name, fields, step, data)) in (200..).zip(kinds) {
+                let name = CString::new(name).unwrap();
+                let fields: Vec<CString> =
+                    fields.iter().map(|f| CString::new(*f).unwrap()).collect();
+                let fields: Vec<*const c_char> = fields.iter().map(|f| f.as_ptr()).collect();
+                let registered = unsafe {
+                    register(
+                        tag,
+                        name.as_ptr(),
+                        fields.as_ptr(),
+                        fields.len(),
+                        step,
+                        data as *mut c_void,
+                    )
+                };
+                assert_eq!(registered, 0, "registering {:?}", name);
+            }
+
+            let res = env.call_static_method("iors/IoRsTests", "nativeNodeKindsStep", "()V", &[]);
+            if res.is_err() && env.exception_check()? {
+                env.exception_describe()?;
+            }
+            res?.v()
+        })
+        .unwrap();
+}